@@ -0,0 +1,412 @@
+// Generates typed bindings for the OrderBook contract from the ABI of
+// contracts/OrderBook.sol, so Rust code that no longer matches the ABI fails to compile
+// instead of at call time. The output is included by src/bindings.rs.
+//
+// The deployable artifact may be built from an older contract, so it only has to be a
+// subset: a function or event it declares differently from the source fails the build.
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const ABI: &str = "contracts/OrderBook.abi.json";
+const ARTIFACT: &str = "build/contracts/OrderBook.json";
+
+const KEYWORDS: &[&str] = &[
+    "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn", "for", "if", "impl", "in",
+    "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static", "struct", "super", "trait",
+    "true", "type", "unsafe", "use", "where", "while", "async", "await", "dyn",
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", ABI);
+    println!("cargo:rerun-if-changed={}", ARTIFACT);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let abi = read_json(ABI);
+    let abi = abi.as_array().unwrap_or_else(|| panic!("{} is not an ABI array", ABI));
+    let artifact = read_json(ARTIFACT);
+    let artifact_abi = artifact["abi"].as_array().unwrap_or_else(|| panic!("{} has no abi", ARTIFACT));
+    let declared: BTreeSet<String> = abi.iter().filter_map(signature).collect();
+    for item in artifact_abi {
+        if let Some(signature) = signature(item) {
+            if !declared.contains(&signature) {
+                panic!("{} has {}, which {} does not declare; update the ABI or rebuild the artifact", ARTIFACT, signature, ABI);
+            }
+        }
+    }
+
+    let mut bindings = Bindings::default();
+    let mut functions = String::new();
+    let mut events = String::new();
+    let mut seen = BTreeSet::new();
+    for item in abi {
+        let name = item["name"].as_str().unwrap_or_default();
+        let kind = item["type"].as_str().unwrap_or_default();
+        if !matches!(kind, "function" | "event") {
+            continue;
+        }
+        if !seen.insert((kind, name)) {
+            panic!("{} {} is overloaded, which the bindings do not support", kind, name);
+        }
+        match kind {
+            "function" => bindings.function(&mut functions, item),
+            _ => bindings.event(&mut events, item),
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by build.rs from {}. Do not edit.", ABI).unwrap();
+    writeln!(out, "pub const ABI_JSON: &str = {:?};", abi_json(abi)).unwrap();
+    for tuple in bindings.tuples.values() {
+        out.push_str(tuple);
+    }
+    writeln!(out, "pub mod functions {{\n{}}}", functions).unwrap();
+    writeln!(out, "pub mod events {{\n{}}}", events).unwrap();
+
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("order_book.rs");
+    fs::write(path, out).unwrap();
+}
+
+fn read_json(path: &str) -> Value {
+    serde_json::from_str(&fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path, e)))
+        .unwrap_or_else(|e| panic!("{} is not valid JSON: {}", path, e))
+}
+
+// "function name(types)->(types)" or "event name(types)", with tuples spelled out
+fn signature(item: &Value) -> Option<String> {
+    let kind = item["type"].as_str()?;
+    if !matches!(kind, "function" | "event") {
+        return None;
+    }
+    let types = |key: &str| params(item, key).iter().map(canonical_type).collect::<Vec<_>>().join(",");
+    Some(match kind {
+        "function" => format!("function {}({})->({})", item["name"].as_str()?, types("inputs"), types("outputs")),
+        _ => format!("event {}({})", item["name"].as_str()?, types("inputs")),
+    })
+}
+
+fn canonical_type(param: &Value) -> String {
+    let abi_type = param["type"].as_str().unwrap_or_default();
+    match abi_type.strip_prefix("tuple") {
+        Some(suffix) => {
+            let components: Vec<String> = params(param, "components").iter().map(canonical_type).collect();
+            format!("({}){}", components.join(","), suffix)
+        },
+        None => abi_type.to_string(),
+    }
+}
+
+fn abi_json(abi: &[Value]) -> String {
+    serde_json::to_string(abi).unwrap()
+}
+
+// A parameter's Rust type and how it converts to and from an ethabi Token
+struct Param {
+    field: String,
+    rust_type: String,
+    param_type: String,
+    indexed: bool,
+    kind: Kind,
+}
+
+enum Kind {
+    Uint,
+    Int,
+    Address,
+    Bool,
+    String,
+    Bytes,
+    Tuple(String),
+    Array(Box<Kind>),
+}
+
+impl Kind {
+    // An expression of the Token for `value`
+    fn encode(&self, value: &str) -> String {
+        match self {
+            Kind::Uint => format!("::ethabi::Token::Uint({})", value),
+            Kind::Int => format!("::ethabi::Token::Int({})", value),
+            Kind::Address => format!("::ethabi::Token::Address({})", value),
+            Kind::Bool => format!("::ethabi::Token::Bool({})", value),
+            Kind::String => format!("::ethabi::Token::String({})", value),
+            Kind::Bytes => format!("::ethabi::Token::Bytes({})", value),
+            Kind::Tuple(_) => format!("::web3::contract::tokens::Tokenizable::into_token({})", value),
+            Kind::Array(item) => format!(
+                "::ethabi::Token::Array({}.into_iter().map(|item| {}).collect())",
+                value,
+                item.encode("item")
+            ),
+        }
+    }
+
+    // An expression of Option<T> from the Token `token`
+    fn decode(&self, token: &str) -> String {
+        match self {
+            Kind::Uint => format!("{}.into_uint()", token),
+            Kind::Int => format!("{}.into_int()", token),
+            Kind::Address => format!("{}.into_address()", token),
+            Kind::Bool => format!("{}.into_bool()", token),
+            Kind::String => format!("{}.into_string()", token),
+            Kind::Bytes => format!("{}.into_bytes()", token),
+            Kind::Tuple(name) => format!("super::{}::from_tuple({})", name, token),
+            Kind::Array(item) => format!(
+                "{}.into_array().and_then(|items| items.into_iter().map(|item| {}).collect::<Option<Vec<_>>>())",
+                token,
+                item.decode("item")
+            ),
+        }
+    }
+}
+
+fn field_name(name: &str, index: usize, prefix: &str) -> String {
+    if name.is_empty() {
+        return format!("{}{}", prefix, index);
+    }
+    let mut field = String::new();
+    let mut previous_lower = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && previous_lower {
+            field.push('_');
+        }
+        previous_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        field.push(c.to_ascii_lowercase());
+    }
+    if KEYWORDS.contains(&field.as_str()) {
+        format!("r#{}", field)
+    } else {
+        field
+    }
+}
+
+fn type_name(name: &str) -> String {
+    let mut type_name = String::new();
+    let mut upper = true;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            type_name.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            type_name.push(c);
+        }
+    }
+    type_name
+}
+
+#[derive(Default)]
+struct Bindings {
+    // Structs for tuple parameters, keyed by name so each is generated once
+    tuples: BTreeMap<String, String>,
+}
+
+impl Bindings {
+    fn param(&mut self, param: &Value, index: usize, prefix: &str) -> Param {
+        let name = param["name"].as_str().unwrap_or_default();
+        let abi_type = param["type"].as_str().unwrap_or_default();
+        let (rust_type, param_type, kind) = self.abi_type(param, abi_type);
+        Param {
+            field: field_name(name, index, prefix),
+            rust_type,
+            param_type,
+            indexed: param["indexed"].as_bool().unwrap_or(false),
+            kind,
+        }
+    }
+
+    fn abi_type(&mut self, param: &Value, abi_type: &str) -> (String, String, Kind) {
+        if let Some(item_type) = abi_type.strip_suffix("[]") {
+            let (rust_type, param_type, kind) = self.abi_type(param, item_type);
+            return (
+                format!("Vec<{}>", rust_type),
+                format!("::ethabi::ParamType::Array(Box::new({}))", param_type),
+                Kind::Array(Box::new(kind)),
+            );
+        }
+        if let Some(bits) = abi_type.strip_prefix("uint") {
+            return ("::web3::types::U256".to_string(), format!("::ethabi::ParamType::Uint({})", bits_or_256(bits)), Kind::Uint);
+        }
+        if let Some(bits) = abi_type.strip_prefix("int") {
+            return ("::web3::types::U256".to_string(), format!("::ethabi::ParamType::Int({})", bits_or_256(bits)), Kind::Int);
+        }
+        match abi_type {
+            "address" => ("::web3::types::Address".to_string(), "::ethabi::ParamType::Address".to_string(), Kind::Address),
+            "bool" => ("bool".to_string(), "::ethabi::ParamType::Bool".to_string(), Kind::Bool),
+            "string" => ("String".to_string(), "::ethabi::ParamType::String".to_string(), Kind::String),
+            "bytes" => ("Vec<u8>".to_string(), "::ethabi::ParamType::Bytes".to_string(), Kind::Bytes),
+            "tuple" => {
+                let name = self.tuple(param);
+                let components: Vec<String> = param["components"].as_array().unwrap().iter()
+                    .enumerate()
+                    .map(|(index, component)| self.param(component, index, "field").param_type)
+                    .collect();
+                (
+                    format!("super::{}", name),
+                    format!("::ethabi::ParamType::Tuple(vec![{}])", components.join(", ")),
+                    Kind::Tuple(name),
+                )
+            },
+            _ => panic!("{} uses ABI type {}, which the bindings do not support", ABI, abi_type),
+        }
+    }
+
+    // Generates the struct for a tuple parameter, named after its Solidity struct
+    fn tuple(&mut self, param: &Value) -> String {
+        let internal_type = param["internalType"].as_str().unwrap_or_default();
+        let name = internal_type
+            .trim_start_matches("struct ")
+            .trim_end_matches("[]")
+            .rsplit('.')
+            .next()
+            .filter(|name| !name.is_empty())
+            .map(type_name)
+            .unwrap_or_else(|| panic!("tuple {} has no struct name", param["name"]));
+        if self.tuples.contains_key(&name) {
+            return name;
+        }
+        // Claimed first, in case a struct refers to itself
+        self.tuples.insert(name.clone(), String::new());
+
+        let fields: Vec<Param> = param["components"].as_array().unwrap().iter()
+            .enumerate()
+            .map(|(index, component)| self.param(component, index, "field"))
+            .collect();
+        let mut out = String::new();
+        writeln!(out, "// The {} struct", internal_type.trim_start_matches("struct ")).unwrap();
+        writeln!(out, "#[derive(Debug, Clone, PartialEq)]\npub struct {} {{", name).unwrap();
+        for field in &fields {
+            writeln!(out, "    pub {}: {},", field.field, field.rust_type).unwrap();
+        }
+        writeln!(out, "}}\n\nimpl {} {{", name).unwrap();
+        writeln!(out, "    pub fn from_tuple(token: ::ethabi::Token) -> Option<Self> {{").unwrap();
+        writeln!(out, "        let mut fields = token.into_tuple()?.into_iter();").unwrap();
+        writeln!(out, "        Some({} {{", name).unwrap();
+        for field in &fields {
+            writeln!(out, "            {}: {}?,", field.field, field.kind.decode("fields.next()?").replace("super::", "")).unwrap();
+        }
+        writeln!(out, "        }})\n    }}\n}}\n").unwrap();
+        writeln!(out, "impl ::web3::contract::tokens::Tokenizable for {} {{", name).unwrap();
+        writeln!(out, "    fn from_token(token: ::ethabi::Token) -> Result<Self, ::web3::contract::Error> {{").unwrap();
+        writeln!(out, "        Self::from_tuple(token).ok_or_else(|| ::web3::contract::Error::InvalidOutputType(\"expected {}\".to_string()))", name).unwrap();
+        writeln!(out, "    }}\n").unwrap();
+        writeln!(out, "    fn into_token(self) -> ::ethabi::Token {{").unwrap();
+        writeln!(out, "        ::ethabi::Token::Tuple(vec![").unwrap();
+        for field in &fields {
+            writeln!(out, "            {},", field.kind.encode(&format!("self.{}", field.field))).unwrap();
+        }
+        writeln!(out, "        ])\n    }}\n}}\n").unwrap();
+        self.tuples.insert(name.clone(), out.replace("super::", ""));
+        name
+    }
+
+    fn function(&mut self, out: &mut String, item: &Value) {
+        let name = item["name"].as_str().unwrap();
+        let struct_name = type_name(name);
+        let inputs: Vec<Param> = params(item, "inputs").iter().enumerate().map(|(index, input)| self.param(input, index, "arg")).collect();
+        let outputs: Vec<Param> = params(item, "outputs").iter().enumerate().map(|(index, output)| self.param(output, index, "output")).collect();
+        let signature: Vec<&str> = params(item, "inputs").iter().map(|input| input["type"].as_str().unwrap()).collect();
+
+        writeln!(out, "// {}({}), {}", name, signature.join(","), item["stateMutability"].as_str().unwrap_or("nonpayable")).unwrap();
+        if inputs.is_empty() {
+            writeln!(out, "#[derive(Debug, Clone)]\npub struct {};\n", struct_name).unwrap();
+        } else {
+            writeln!(out, "#[derive(Debug, Clone)]\npub struct {} {{", struct_name).unwrap();
+            for input in &inputs {
+                writeln!(out, "    pub {}: {},", input.field, input.rust_type).unwrap();
+            }
+            writeln!(out, "}}\n").unwrap();
+        }
+
+        writeln!(out, "impl ::web3::contract::tokens::Tokenize for {} {{", struct_name).unwrap();
+        writeln!(out, "    fn into_tokens(self) -> Vec<::ethabi::Token> {{").unwrap();
+        writeln!(out, "        vec![").unwrap();
+        for input in &inputs {
+            writeln!(out, "            {},", input.kind.encode(&format!("self.{}", input.field))).unwrap();
+        }
+        writeln!(out, "        ]\n    }}\n}}\n").unwrap();
+        writeln!(out, "impl super::Function for {} {{\n    const NAME: &'static str = {:?};\n}}\n", struct_name, name).unwrap();
+
+        let output_type = match outputs.len() {
+            0 => return,
+            1 => outputs[0].rust_type.clone(),
+            _ => {
+                let output_name = format!("{}Output", struct_name);
+                writeln!(out, "#[derive(Debug, Clone)]\npub struct {} {{", output_name).unwrap();
+                for output in &outputs {
+                    writeln!(out, "    pub {}: {},", output.field, output.rust_type).unwrap();
+                }
+                writeln!(out, "}}\n").unwrap();
+                writeln!(out, "impl {} {{", output_name).unwrap();
+                writeln!(out, "    fn from_outputs(tokens: Vec<::ethabi::Token>) -> Option<Self> {{").unwrap();
+                writeln!(out, "        let mut tokens = tokens.into_iter();").unwrap();
+                writeln!(out, "        Some({} {{", output_name).unwrap();
+                for output in &outputs {
+                    writeln!(out, "            {}: {}?,", output.field, output.kind.decode("tokens.next()?")).unwrap();
+                }
+                writeln!(out, "        }})\n    }}\n}}\n").unwrap();
+                writeln!(out, "impl ::web3::contract::tokens::Detokenize for {} {{", output_name).unwrap();
+                writeln!(out, "    fn from_tokens(tokens: Vec<::ethabi::Token>) -> Result<Self, ::web3::contract::Error> {{").unwrap();
+                writeln!(out, "        Self::from_outputs(tokens).ok_or_else(|| ::web3::contract::Error::InvalidOutputType(\"expected {}\".to_string()))", output_name).unwrap();
+                writeln!(out, "    }}\n}}\n").unwrap();
+                output_name
+            },
+        };
+        writeln!(out, "impl super::View for {} {{\n    type Output = {};\n}}\n", struct_name, output_type).unwrap();
+    }
+
+    fn event(&mut self, out: &mut String, item: &Value) {
+        let name = item["name"].as_str().unwrap();
+        let fields: Vec<Param> = params(item, "inputs").iter().enumerate().map(|(index, input)| self.param(input, index, "field")).collect();
+        let abi_types: Vec<&str> = params(item, "inputs").iter().map(|input| input["type"].as_str().unwrap()).collect();
+        let signature = format!("{}({})", name, abi_types.join(","));
+
+        writeln!(out, "// The {} event", name).unwrap();
+        writeln!(out, "#[derive(Debug, Clone)]\npub struct {} {{", name).unwrap();
+        for field in &fields {
+            writeln!(out, "    pub {}: {},", field.field, field.rust_type).unwrap();
+        }
+        writeln!(out, "}}\n").unwrap();
+
+        let data: Vec<&Param> = fields.iter().filter(|field| !field.indexed).collect();
+        let data_types: Vec<&str> = data.iter().map(|field| field.param_type.as_str()).collect();
+        writeln!(out, "impl super::Event for {} {{", name).unwrap();
+        writeln!(out, "    const SIGNATURE: &'static str = {:?};\n", signature).unwrap();
+        writeln!(out, "    fn from_log(log: &::web3::types::Log) -> Option<Self> {{").unwrap();
+        if !data.is_empty() {
+            writeln!(out, "        let mut data = ::ethabi::decode(&[{}], &log.data.0).ok()?.into_iter();", data_types.join(", ")).unwrap();
+        }
+        let mut topic = 1;
+        writeln!(out, "        Some({} {{", name).unwrap();
+        for field in &fields {
+            if field.indexed {
+                if matches!(field.kind, Kind::String | Kind::Bytes | Kind::Tuple(_) | Kind::Array(_)) {
+                    panic!("event {} indexes {}, which the bindings do not support", name, field.field);
+                }
+                let token = format!(
+                    "::ethabi::decode(&[{}], log.topics.get({})?.as_bytes()).ok()?.into_iter().next()?",
+                    field.param_type, topic
+                );
+                writeln!(out, "            {}: {}?,", field.field, field.kind.decode(&token)).unwrap();
+                topic += 1;
+            } else {
+                writeln!(out, "            {}: {}?,", field.field, field.kind.decode("data.next()?")).unwrap();
+            }
+        }
+        writeln!(out, "        }})\n    }}\n}}\n").unwrap();
+    }
+}
+
+fn params<'a>(item: &'a Value, key: &str) -> &'a [Value] {
+    item[key].as_array().map_or(&[], |params| params.as_slice())
+}
+
+fn bits_or_256(bits: &str) -> &str {
+    if bits.is_empty() {
+        "256"
+    } else {
+        bits
+    }
+}
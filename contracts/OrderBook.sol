@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.0;
+
+contract OrderBook {
+    enum OrderType { LIMIT, MARKET, STOP }
+
+    struct Order {
+        uint id;
+        string symbol;
+        uint quantity;
+        uint price;
+        address trader;
+        string user_id;
+        string order_id;
+        OrderType orderType;
+    }
+
+    // One entry of a placeOrders batch
+    struct OrderInput {
+        bool isBuy;
+        string symbol;
+        uint quantity;
+        uint price;
+        string user_id;
+        string order_id;
+        OrderType orderType;
+    }
+
+    uint public orderCount = 0;
+    mapping(uint => Order) public buyOrders;
+    mapping(uint => Order) public sellOrders;
+
+    event BuyOrderPlaced(uint id, string symbol, uint quantity, uint price, address trader, string user_id, string order_id, OrderType orderType);
+    event SellOrderPlaced(uint id, string symbol, uint quantity, uint price, address trader, string user_id, string order_id, OrderType orderType);
+    event OrderMatched(uint buyOrderId, uint sellOrderId, string symbol, uint quantity, uint price, address buyer, string buyer_user_id, string buyer_order_id, address seller, string seller_user_id, string seller_order_id);
+    event OrderCancelled(uint id, string symbol, uint quantity, address trader, string user_id, string order_id);
+    event Log(string message);
+
+    function placeBuyOrder(string memory symbol, uint quantity, uint price, string memory user_id, string memory order_id, OrderType orderType) public {
+        emit Log("Placing Buy Order");
+
+        addBuyOrder(symbol, quantity, price, user_id, order_id, orderType);
+        matchOrders();
+    }
+
+    function placeSellOrder(string memory symbol, uint quantity, uint price, string memory user_id, string memory order_id, OrderType orderType) public {
+        emit Log("Placing Sell Order");
+
+        addSellOrder(symbol, quantity, price, user_id, order_id, orderType);
+        matchOrders();
+    }
+
+    // Places several orders in one transaction and matches once at the end. Empty
+    // orders are skipped instead of reverting the batch, so each order's outcome is
+    // whether its BuyOrderPlaced or SellOrderPlaced event was emitted.
+    function placeOrders(OrderInput[] memory orders) public {
+        emit Log("Placing Order Batch");
+
+        for (uint i = 0; i < orders.length; i++) {
+            OrderInput memory order = orders[i];
+            if (order.quantity == 0) {
+                emit Log("Skipping Empty Order");
+                continue;
+            }
+            if (order.isBuy) {
+                addBuyOrder(order.symbol, order.quantity, order.price, order.user_id, order.order_id, order.orderType);
+            } else {
+                addSellOrder(order.symbol, order.quantity, order.price, order.user_id, order.order_id, order.orderType);
+            }
+        }
+        matchOrders();
+    }
+
+    function addBuyOrder(string memory symbol, uint quantity, uint price, string memory user_id, string memory order_id, OrderType orderType) internal {
+        orderCount++;
+        buyOrders[orderCount] = Order(orderCount, symbol, quantity, price, msg.sender, user_id, order_id, orderType);
+        emit BuyOrderPlaced(orderCount, symbol, quantity, price, msg.sender, user_id, order_id, orderType);
+    }
+
+    function addSellOrder(string memory symbol, uint quantity, uint price, string memory user_id, string memory order_id, OrderType orderType) internal {
+        orderCount++;
+        sellOrders[orderCount] = Order(orderCount, symbol, quantity, price, msg.sender, user_id, order_id, orderType);
+        emit SellOrderPlaced(orderCount, symbol, quantity, price, msg.sender, user_id, order_id, orderType);
+    }
+
+    // Pulls the caller's resting order with this order_id off the book. Unknown and
+    // already filled orders are skipped rather than reverting, as in placeOrders.
+    function cancelOrder(string memory order_id) public {
+        bytes32 target = keccak256(bytes(order_id));
+        for (uint i = 1; i <= orderCount; i++) {
+            if (keccak256(bytes(buyOrders[i].order_id)) == target) {
+                cancelBuyOrder(i);
+                return;
+            }
+            if (keccak256(bytes(sellOrders[i].order_id)) == target) {
+                cancelSellOrder(i);
+                return;
+            }
+        }
+        emit Log("Order Not Found");
+    }
+
+    // Pulls every resting order the caller placed for user_id off the book, in one
+    // symbol or, when symbol is empty, in all of them
+    function cancelOrders(string memory user_id, string memory symbol) public {
+        bytes32 user = keccak256(bytes(user_id));
+        bytes32 target = keccak256(bytes(symbol));
+        bool allSymbols = bytes(symbol).length == 0;
+        for (uint i = 1; i <= orderCount; i++) {
+            if (buyOrders[i].quantity > 0 && keccak256(bytes(buyOrders[i].user_id)) == user &&
+                (allSymbols || keccak256(bytes(buyOrders[i].symbol)) == target)) {
+                cancelBuyOrder(i);
+            }
+            if (sellOrders[i].quantity > 0 && keccak256(bytes(sellOrders[i].user_id)) == user &&
+                (allSymbols || keccak256(bytes(sellOrders[i].symbol)) == target)) {
+                cancelSellOrder(i);
+            }
+        }
+    }
+
+    // Only the account that placed an order may cancel it
+    function cancelBuyOrder(uint id) internal {
+        Order storage order = buyOrders[id];
+        if (order.trader != msg.sender || order.quantity == 0) {
+            emit Log("Order Not Cancellable");
+            return;
+        }
+        emit OrderCancelled(id, order.symbol, order.quantity, order.trader, order.user_id, order.order_id);
+        order.quantity = 0;
+    }
+
+    function cancelSellOrder(uint id) internal {
+        Order storage order = sellOrders[id];
+        if (order.trader != msg.sender || order.quantity == 0) {
+            emit Log("Order Not Cancellable");
+            return;
+        }
+        emit OrderCancelled(id, order.symbol, order.quantity, order.trader, order.user_id, order.order_id);
+        order.quantity = 0;
+    }
+
+    function matchOrders() internal {
+        emit Log("Matching Orders");
+
+        for (uint i = 1; i <= orderCount; i++) {
+            if (buyOrders[i].quantity > 0) {
+                for (uint j = 1; j <= orderCount; j++) {
+                    if (sellOrders[j].quantity > 0) {
+                        if (keccak256(bytes(buyOrders[i].symbol)) == keccak256(bytes(sellOrders[j].symbol))) {
+                            if (buyOrders[i].orderType == OrderType.MARKET || sellOrders[j].orderType == OrderType.MARKET ||
+                                (buyOrders[i].orderType == OrderType.LIMIT && sellOrders[j].orderType == OrderType.LIMIT && buyOrders[i].price >= sellOrders[j].price) ||
+                                (buyOrders[i].orderType == OrderType.STOP && sellOrders[j].orderType == OrderType.STOP && buyOrders[i].price <= sellOrders[j].price)) {
+                                
+                                uint matchQuantity = buyOrders[i].quantity < sellOrders[j].quantity ? buyOrders[i].quantity : sellOrders[j].quantity;
+
+                                buyOrders[i].quantity -= matchQuantity;
+                                sellOrders[j].quantity -= matchQuantity;
+
+                                emit OrderMatched(
+                                    buyOrders[i].id, sellOrders[j].id, buyOrders[i].symbol, matchQuantity, buyOrders[i].price,
+                                    buyOrders[i].trader, buyOrders[i].user_id, buyOrders[i].order_id,
+                                    sellOrders[j].trader, sellOrders[j].user_id, sellOrders[j].order_id
+                                );
+
+                                emit Log("Order Matched");
+
+                                if (buyOrders[i].quantity == 0) {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    function getBuyOrder(uint id) public view returns (Order memory) {
+        return buyOrders[id];
+    }
+
+    function getSellOrder(uint id) public view returns (Order memory) {
+        return sellOrders[id];
+    }
+}
\ No newline at end of file
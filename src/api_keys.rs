@@ -0,0 +1,243 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use sha2::Sha256;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+use crate::errors::ApiError;
+use crate::models::{ApiKey, ApiScope};
+
+// Headers a signed request carries instead of a Bearer token
+pub const API_KEY_HEADER: &str = "X-API-KEY";
+pub const API_TIMESTAMP_HEADER: &str = "X-API-TIMESTAMP";
+pub const API_NONCE_HEADER: &str = "X-API-NONCE";
+pub const API_SIGNATURE_HEADER: &str = "X-API-SIGNATURE";
+
+fn api_key_key(key_id: &str) -> String {
+    format!("api_key:{}", key_id)
+}
+
+fn user_api_keys_key(username: &str) -> String {
+    format!("api_keys:{}", username)
+}
+
+fn nonce_key(key_id: &str, nonce: &str) -> String {
+    format!("api_nonce:{}:{}", key_id, nonce)
+}
+
+pub async fn create_api_key(
+    con: &mut MultiplexedConnection,
+    username: &str,
+    label: &str,
+    scopes: Vec<ApiScope>,
+    ip_allowlist: Vec<String>,
+) -> Result<ApiKey, ApiError> {
+    let api_key = ApiKey {
+        key_id: Uuid::new_v4().simple().to_string(),
+        secret: format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+        username: username.to_string(),
+        label: label.to_string(),
+        scopes,
+        ip_allowlist,
+        created_at: Utc::now(),
+    };
+
+    let _: () = con.set(api_key_key(&api_key.key_id), serde_json::to_string(&api_key)?).await?;
+    let _: () = con.sadd(user_api_keys_key(username), &api_key.key_id).await?;
+    Ok(api_key)
+}
+
+pub async fn get_api_key(con: &mut MultiplexedConnection, key_id: &str) -> Result<Option<ApiKey>, ApiError> {
+    let api_key_json: Option<String> = con.get(api_key_key(key_id)).await?;
+    Ok(api_key_json.map(|api_key_json| serde_json::from_str(&api_key_json)).transpose()?)
+}
+
+pub async fn user_api_keys(con: &mut MultiplexedConnection, username: &str) -> Result<Vec<ApiKey>, ApiError> {
+    let key_ids: Vec<String> = con.smembers(user_api_keys_key(username)).await?;
+    let mut api_keys = vec![];
+    for key_id in key_ids {
+        if let Some(api_key) = get_api_key(con, &key_id).await? {
+            api_keys.push(api_key);
+        }
+    }
+    Ok(api_keys)
+}
+
+// Returns false if the key does not exist or belongs to someone else
+pub async fn delete_api_key(con: &mut MultiplexedConnection, username: &str, key_id: &str) -> Result<bool, ApiError> {
+    match get_api_key(con, key_id).await? {
+        Some(api_key) if api_key.username == username => {
+            let _: () = con.del(api_key_key(key_id)).await?;
+            let _: () = con.srem(user_api_keys_key(username), key_id).await?;
+            Ok(true)
+        },
+        _ => Ok(false),
+    }
+}
+
+// Entries are single addresses or CIDR ranges; an empty list allows any address
+pub fn validate_allowlist(ip_allowlist: &[String]) -> Result<(), String> {
+    for entry in ip_allowlist {
+        if entry.parse::<IpAddr>().is_err() && entry.parse::<IpNet>().is_err() {
+            return Err(format!("Invalid IP allowlist entry {}", entry));
+        }
+    }
+    Ok(())
+}
+
+pub fn ip_allowed(api_key: &ApiKey, ip: Option<IpAddr>) -> bool {
+    if api_key.ip_allowlist.is_empty() {
+        return true;
+    }
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return false,
+    };
+    api_key.ip_allowlist.iter().any(|entry| match entry.parse::<IpNet>() {
+        Ok(net) => net.contains(&ip),
+        Err(_) => entry.parse::<IpAddr>().is_ok_and(|allowed| allowed == ip),
+    })
+}
+
+// The signature is hex(HMAC-SHA256(secret, timestamp + nonce + METHOD + path_and_query + body))
+pub fn verify_signature(
+    api_key: &ApiKey,
+    timestamp: &str,
+    nonce: &str,
+    method: &str,
+    path_and_query: &str,
+    body: &[u8],
+    signature: &str,
+) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(api_key.secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(nonce.as_bytes());
+    mac.update(method.as_bytes());
+    mac.update(path_and_query.as_bytes());
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+// Timestamps are unix seconds and may drift at most `window_seconds` from server time
+pub fn timestamp_fresh(timestamp: &str, window_seconds: i64) -> bool {
+    match timestamp.parse::<i64>() {
+        Ok(timestamp) => (Utc::now().timestamp() - timestamp).abs() <= window_seconds,
+        Err(_) => false,
+    }
+}
+
+// Records the nonce for this key, returning false if it was already used. Nonces only
+// need remembering for as long as their timestamp could still pass timestamp_fresh.
+pub async fn claim_nonce(con: &mut MultiplexedConnection, key_id: &str, nonce: &str, window_seconds: i64) -> Result<bool, ApiError> {
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(nonce_key(key_id, nonce))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(window_seconds * 2)
+        .query_async(con)
+        .await?;
+    Ok(claimed.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_key(ip_allowlist: &[&str]) -> ApiKey {
+        ApiKey {
+            key_id: "k1".to_string(),
+            secret: "s3cret".to_string(),
+            username: "alice".to_string(),
+            label: "bot".to_string(),
+            scopes: vec![ApiScope::Trade],
+            ip_allowlist: ip_allowlist.iter().map(|entry| entry.to_string()).collect(),
+            created_at: Utc::now(),
+        }
+    }
+
+    // Signs the request the way a client would
+    fn sign(secret: &str, timestamp: &str, nonce: &str, method: &str, path: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}{}{}{}", timestamp, nonce, method, path).as_bytes());
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn accepts_a_correctly_signed_request() {
+        let key = api_key(&[]);
+        let body = br#"{"symbol":"ETH"}"#;
+        let signature = sign("s3cret", "1700000000", "n1", "POST", "/buy", body);
+        assert!(verify_signature(&key, "1700000000", "n1", "POST", "/buy", body, &signature));
+    }
+
+    #[test]
+    fn rejects_a_signature_over_different_content() {
+        let key = api_key(&[]);
+        let body = br#"{"symbol":"ETH"}"#;
+        let signature = sign("s3cret", "1700000000", "n1", "POST", "/buy", body);
+
+        assert!(!verify_signature(&key, "1700000001", "n1", "POST", "/buy", body, &signature));
+        assert!(!verify_signature(&key, "1700000000", "n2", "POST", "/buy", body, &signature));
+        assert!(!verify_signature(&key, "1700000000", "n1", "POST", "/sell", body, &signature));
+        assert!(!verify_signature(&key, "1700000000", "n1", "POST", "/buy", br#"{"symbol":"BTC"}"#, &signature));
+    }
+
+    #[test]
+    fn rejects_a_signature_from_another_secret() {
+        let signature = sign("guessed", "1700000000", "n1", "GET", "/orders", b"");
+        assert!(!verify_signature(&api_key(&[]), "1700000000", "n1", "GET", "/orders", b"", &signature));
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let key = api_key(&[]);
+        assert!(!verify_signature(&key, "1700000000", "n1", "GET", "/orders", b"", "not hex"));
+        assert!(!verify_signature(&key, "1700000000", "n1", "GET", "/orders", b"", ""));
+    }
+
+    #[test]
+    fn rejects_stale_and_future_timestamps() {
+        let now = Utc::now().timestamp();
+        assert!(timestamp_fresh(&now.to_string(), 30));
+        assert!(!timestamp_fresh(&(now - 3600).to_string(), 30));
+        assert!(!timestamp_fresh(&(now + 3600).to_string(), 30));
+        assert!(!timestamp_fresh("yesterday", 30));
+    }
+
+    #[test]
+    fn an_empty_allowlist_allows_any_address() {
+        let key = api_key(&[]);
+        assert!(ip_allowed(&key, Some("203.0.113.7".parse().unwrap())));
+        assert!(ip_allowed(&key, None));
+    }
+
+    #[test]
+    fn matches_single_addresses_and_ranges() {
+        let key = api_key(&["203.0.113.7", "10.0.0.0/8", "2001:db8::/32"]);
+        assert!(ip_allowed(&key, Some("203.0.113.7".parse().unwrap())));
+        assert!(ip_allowed(&key, Some("10.20.30.40".parse().unwrap())));
+        assert!(ip_allowed(&key, Some("2001:db8::1".parse().unwrap())));
+
+        assert!(!ip_allowed(&key, Some("203.0.113.8".parse().unwrap())));
+        assert!(!ip_allowed(&key, Some("11.0.0.1".parse().unwrap())));
+        // Without a peer address there is nothing to match against
+        assert!(!ip_allowed(&key, None));
+    }
+
+    #[test]
+    fn validates_allowlist_entries() {
+        assert!(validate_allowlist(&["10.0.0.0/8".to_string(), "::1".to_string()]).is_ok());
+        assert!(validate_allowlist(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(validate_allowlist(&["localhost".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{HttpServiceFactory, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Logger;
+use actix_web::{web, App, Route};
+use log::info;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::auth::{bootstrap_admin, RequireRole, ADMIN_ROLES, READ_ROLES, RISK_ROLES, TRADING_ROLES};
+use crate::batch::run_order_batcher;
+use crate::config::Config;
+use crate::errors;
+use crate::handlers::*;
+use crate::models::{ApiScope, Role};
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::readiness::Dependencies;
+use crate::receipts::run_receipt_tracker;
+use crate::reconcile::run_reconciler;
+use crate::sessions::run_session_scheduler;
+use crate::signer::Signer;
+use crate::state::AppState;
+use crate::supervisor::supervise_listener;
+use crate::venues::Venues;
+use crate::users::migrate_user_keys;
+
+// A single-route resource that only the given roles may call, rate limited per caller
+fn guarded(path: &'static str, roles: &'static [Role], route: Route) -> impl HttpServiceFactory {
+    web::resource(path).wrap(RateLimit::per_caller(path)).wrap(RequireRole::new(roles)).route(route)
+}
+
+// Like guarded, but also callable with a signed request from an API key with the scope
+fn guarded_api(path: &'static str, roles: &'static [Role], scope: ApiScope, route: Route) -> impl HttpServiceFactory {
+    web::resource(path).wrap(RateLimit::per_caller(path)).wrap(RequireRole::with_api_key(roles, scope)).route(route)
+}
+
+// A running server's shared state, with its listeners and background tasks started.
+// Shared by main and the integration tests, which serve the same app in-process.
+#[derive(Clone)]
+pub struct Services {
+    state: web::Data<AsyncMutex<AppState>>,
+    venues: Arc<Venues>,
+    dependencies: web::Data<Dependencies>,
+    rate_limiter: web::Data<RateLimiter>,
+    production: bool,
+}
+
+impl Services {
+    // Connects to Redis and every venue, then starts each venue's listener and the
+    // session scheduler, receipt tracker, order batcher and reconciler
+    pub async fn start(config: &Config) -> Result<Services, String> {
+        let venues = Arc::new(Venues::connect(&config.venues).await?);
+        let redis_client = redis::Client::open(config.redis_url.as_str()).map_err(|e| format!("Invalid Redis URL: {}", e))?;
+        migrate_user_keys(&redis_client).await.map_err(|e| e.to_string())?;
+        bootstrap_admin(&redis_client, config.bcrypt_cost).await.map_err(|e| e.to_string())?;
+
+        let rate_limiter = web::Data::new(RateLimiter { redis_client: redis_client.clone(), config: config.clone() });
+        let state = web::Data::new(AsyncMutex::new(AppState { 
+            venues: venues.clone(),
+            config: config.clone(),
+            redis_client,
+            signer: config.signer_key.map(|key| Arc::new(Signer::new(key, config.derive_user_accounts))),
+        }));
+
+        for venue in venues.all() {
+            let listen_data = state.clone();
+            let listen_venue = venue.clone();
+            tokio::spawn(async move {
+                supervise_listener(listen_data, listen_venue).await;
+            });
+        }
+
+        let scheduler_data = state.clone();
+        tokio::spawn(async move {
+            run_session_scheduler(scheduler_data).await;
+        });
+
+        let tracker_data = state.clone();
+        tokio::spawn(async move {
+            run_receipt_tracker(tracker_data).await;
+        });
+
+        if config.batch_window_ms > 0 {
+            info!("Batching orders every {} ms, up to {} per transaction", config.batch_window_ms, config.batch_max_orders);
+            let batcher_data = state.clone();
+            tokio::spawn(async move {
+                run_order_batcher(batcher_data).await;
+            });
+        }
+
+        if config.reconcile_interval_seconds > 0 {
+            let reconciler_data = state.clone();
+            tokio::spawn(async move {
+                run_reconciler(reconciler_data).await;
+            });
+        }
+
+        let dependencies = web::Data::new(Dependencies {
+            redis_client: redis::Client::open(config.redis_url.as_str()).map_err(|e| format!("Invalid Redis URL: {}", e))?,
+            venues: venues.clone(),
+            max_listener_lag_blocks: config.ready_max_listener_lag_blocks,
+            max_block_age_seconds: config.ready_max_block_age_seconds,
+        });
+
+        Ok(Services { state, venues, dependencies, rate_limiter, production: config.is_production() })
+    }
+
+    pub fn app(&self) -> App<impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >> {
+        let production = self.production;
+        App::new()
+            .app_data(self.state.clone())
+            .app_data(web::Data::from(self.venues.clone()))
+            .app_data(self.dependencies.clone())
+            .app_data(self.rate_limiter.clone())
+            .app_data(web::JsonConfig::default().error_handler(errors::json_error))
+            .app_data(web::PathConfig::default().error_handler(errors::path_error))
+            .app_data(web::QueryConfig::default().error_handler(errors::query_error))
+            .wrap(RateLimit::per_ip())
+            .wrap(Logger::default())
+
+            // Probes
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
+
+            // Public routes
+            .route("/register", web::post().to(register_user))
+            .route("/login", web::post().to(login))
+            .route("/refresh", web::post().to(refresh))
+            .route("/signout", web::post().to(signout))
+            .route("/instruments", web::get().to(get_instruments))
+            .route("/instruments/{symbol}", web::get().to(get_instrument_by_symbol))
+            .route("/sessions/{symbol}", web::get().to(get_session))
+            .route("/auctions/{symbol}", web::get().to(get_auction))
+
+            // Application routes
+            .service(guarded_api("/buy", TRADING_ROLES, ApiScope::Trade, web::post().to(place_buy_order)))
+            .service(guarded_api("/sell", TRADING_ROLES, ApiScope::Trade, web::post().to(place_sell_order)))
+            .service(guarded_api("/order/user/{user_id}", READ_ROLES, ApiScope::Read, web::get().to(get_user_orders)))
+            .service(guarded_api("/order/id/{order_id}", READ_ROLES, ApiScope::Read, web::get().to(get_order_by_id)))
+            .service(guarded_api("/portfolio/user/{user_id}", READ_ROLES, ApiScope::Read, web::get().to(get_user_portfolio)))
+            .service(guarded_api("/portfolio/id/{portfolio_id}", READ_ROLES, ApiScope::Read, web::get().to(get_portfolio_by_id)))
+            .service(guarded_api("/transactions", READ_ROLES, ApiScope::Read, web::get().to(get_user_transactions)))
+            .service(guarded_api("/transactions/{tx_hash}", READ_ROLES, ApiScope::Read, web::get().to(get_transaction)))
+            .service(guarded_api("/account/withdraw", TRADING_ROLES, ApiScope::Withdraw, web::post().to(withdraw)))
+            .service(guarded_api("/account/ledger", READ_ROLES, ApiScope::Read, web::get().to(get_ledger)))
+            .service(guarded_api("/me/portfolio", READ_ROLES, ApiScope::Read, web::get().to(get_my_portfolio)))
+            .service(guarded_api("/me/orders", READ_ROLES, ApiScope::Read, web::get().to(get_my_orders)))
+            .service(guarded_api("/me/transactions", READ_ROLES, ApiScope::Read, web::get().to(get_user_transactions)))
+            .service(guarded_api("/me/ledger", READ_ROLES, ApiScope::Read, web::get().to(get_ledger)))
+
+            // API key management, token only so a leaked key cannot mint more keys
+            .service(
+                web::resource("/account/api_keys")
+                    .wrap(RateLimit::per_caller("/account/api_keys"))
+                    .wrap(RequireRole::new(READ_ROLES))
+                    .route(web::get().to(get_user_api_keys))
+                    .route(web::post().to(create_user_api_key))
+            )
+            .service(guarded_api("/account/kill_switch", TRADING_ROLES, ApiScope::Trade, web::post().to(engage_my_kill_switch)))
+            .service(guarded_api("/account/kill_switch/release", TRADING_ROLES, ApiScope::Trade, web::post().to(release_my_kill_switch)))
+            .service(guarded_api("/account/cancel_on_disconnect", TRADING_ROLES, ApiScope::Trade, web::post().to(set_cancel_on_disconnect)))
+            .service(guarded("/account/password", READ_ROLES, web::post().to(change_password)))
+            .service(guarded("/account/api_keys/{key_id}", READ_ROLES, web::delete().to(revoke_user_api_key)))
+
+            // Risk routes
+            .service(guarded("utils/post/circuit_breakers", RISK_ROLES, web::post().to(upsert_circuit_breaker)))
+            .service(guarded("utils/post/halt", RISK_ROLES, web::post().to(halt_trading)))
+            .service(guarded("utils/post/resume/{symbol}", RISK_ROLES, web::post().to(resume_trading)))
+            .service(guarded("utils/get/kill_switches", RISK_ROLES, web::get().to(get_kill_switches)))
+            .service(guarded("utils/post/kill_switch", RISK_ROLES, web::post().to(engage_kill_switch)))
+            .service(guarded("utils/post/kill_switch/release", RISK_ROLES, web::post().to(release_kill_switch)))
+
+            // Utility routes
+            .service(guarded("utils/get/users", ADMIN_ROLES, web::get().to(get_all_users)))
+            .service(guarded("utils/get/orders", ADMIN_ROLES, web::get().to(get_order_book)))
+            .service(guarded("utils/get/portfolios", ADMIN_ROLES, web::get().to(get_all_portfolios)))
+            .service(guarded("utils/post/deposit", ADMIN_ROLES, web::post().to(deposit)))
+            .service(guarded("utils/get/fees", ADMIN_ROLES, web::get().to(get_fee_schedule)))
+            .service(guarded("utils/post/fees", ADMIN_ROLES, web::post().to(set_fee_schedule)))
+            .service(guarded("utils/get/fee_revenue", ADMIN_ROLES, web::get().to(get_fee_revenue)))
+            .service(guarded("utils/post/instruments", ADMIN_ROLES, web::post().to(upsert_instrument)))
+            .service(guarded("utils/delete/instruments/{symbol}", ADMIN_ROLES, web::delete().to(remove_instrument)))
+            .service(guarded("utils/post/sessions", ADMIN_ROLES, web::post().to(upsert_session_calendar)))
+            .service(guarded("utils/post/role", ADMIN_ROLES, web::post().to(set_user_role)))
+            .service(guarded("utils/get/health", ADMIN_ROLES, web::get().to(get_health)))
+            .service(guarded("utils/get/signing_account/{username}", ADMIN_ROLES, web::get().to(get_signing_account)))
+            .service(guarded("utils/get/gas", ADMIN_ROLES, web::get().to(get_gas_metrics)))
+            .service(guarded("utils/get/reconciliation", ADMIN_ROLES, web::get().to(get_reconciliation)))
+            .service(guarded("utils/post/reconcile", ADMIN_ROLES, web::post().to(run_reconciliation)))
+
+            // Destructive utility routes, not served in production
+            .configure(move |cfg| {
+                if production {
+                    return;
+                }
+                cfg.service(guarded("utils/post/initialize_user", ADMIN_ROLES, web::post().to(initialize_user)))
+                    .service(guarded("utils/delete/all_data", ADMIN_ROLES, web::delete().to(delete_all_data)))
+                    .service(guarded("utils/delete/users", ADMIN_ROLES, web::delete().to(delete_all_users)))
+                    .service(guarded("utils/delete/orders", ADMIN_ROLES, web::delete().to(delete_all_orders)))
+                    .service(guarded("utils/delete/portfolios", ADMIN_ROLES, web::delete().to(delete_all_portfolios)));
+            })
+    }
+
+    // Stops order entry on every venue
+    pub fn begin_shutdown(&self) {
+        self.venues.begin_shutdown();
+    }
+
+    // Waits for in-flight contract calls, returning false on timeout
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.venues.drain(timeout).await
+    }
+}
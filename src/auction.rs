@@ -0,0 +1,322 @@
+use log::{error, info};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use web3::types::U256;
+
+use crate::errors::ApiError;
+use crate::events::settle_fill;
+use crate::models::{AuctionIndicative, FillSource, OrderMatchedEvent, OrderRequest, OrderSide, OrderStatus, OrderType, QueuedOrder, SessionPhase};
+use crate::orders::record_order;
+use crate::sessions::{queue_order, queued_orders, queued_orders_key};
+use crate::state::AppState;
+use crate::valuation::LAST_PRICE_KEY;
+
+// Volumes are summed in u64 so a book of large orders cannot overflow
+struct Candidate {
+    price: u32,
+    buy_volume: u64,
+    sell_volume: u64,
+}
+
+impl Candidate {
+    fn executable(&self) -> u64 {
+        self.buy_volume.min(self.sell_volume)
+    }
+
+    fn surplus(&self) -> i64 {
+        self.buy_volume as i64 - self.sell_volume as i64
+    }
+}
+
+fn is_market(queued: &QueuedOrder) -> bool {
+    matches!(queued.order.order_type, OrderType::Market)
+}
+
+// Whether the order would trade if the auction uncrossed at `price`
+fn crosses(queued: &QueuedOrder, price: u32) -> bool {
+    is_market(queued) || match queued.side {
+        OrderSide::Buy => queued.order.price >= price,
+        OrderSide::Sell => queued.order.price <= price,
+    }
+}
+
+fn volume_at(orders: &[QueuedOrder], side: OrderSide, price: u32) -> u64 {
+    orders.iter()
+        .filter(|queued| queued.side == side && crosses(queued, price))
+        .map(|queued| queued.order.quantity as u64)
+        .sum()
+}
+
+// Picks the uncrossing price among the limit prices in the book:
+//   1. maximise executable volume
+//   2. minimise the surplus left on either side
+//   3. if every remaining price leaves a buy surplus take the highest, a sell surplus the lowest
+//   4. otherwise take the price closest to the reference (last traded) price
+pub fn equilibrium(symbol: &str, orders: &[QueuedOrder], reference: Option<f64>) -> AuctionIndicative {
+    let mut prices: Vec<u32> = orders.iter()
+        .filter(|queued| !is_market(queued))
+        .map(|queued| queued.order.price)
+        .collect();
+    if prices.is_empty() {
+        // Only market orders: they can still uncross at the reference price
+        prices.extend(reference.map(|reference| reference.round() as u32));
+    }
+    prices.sort();
+    prices.dedup();
+
+    let candidates: Vec<Candidate> = prices.iter()
+        .map(|price| Candidate {
+            price: *price,
+            buy_volume: volume_at(orders, OrderSide::Buy, *price),
+            sell_volume: volume_at(orders, OrderSide::Sell, *price),
+        })
+        .collect();
+
+    let max_volume = candidates.iter().map(Candidate::executable).max().unwrap_or(0);
+    if max_volume == 0 {
+        return AuctionIndicative {
+            symbol: symbol.to_string(),
+            price: None,
+            executable_volume: 0,
+            imbalance: 0,
+            imbalance_side: None,
+        };
+    }
+
+    let mut best: Vec<&Candidate> = candidates.iter().filter(|candidate| candidate.executable() == max_volume).collect();
+    let min_surplus = best.iter().map(|candidate| candidate.surplus().abs()).min().unwrap();
+    best.retain(|candidate| candidate.surplus().abs() == min_surplus);
+
+    let chosen = if best.iter().all(|candidate| candidate.surplus() > 0) {
+        best.iter().max_by_key(|candidate| candidate.price).unwrap()
+    } else if best.iter().all(|candidate| candidate.surplus() < 0) {
+        best.iter().min_by_key(|candidate| candidate.price).unwrap()
+    } else {
+        match reference {
+            Some(reference) => best.iter()
+                .min_by(|a, b| {
+                    let distance_a = (a.price as f64 - reference).abs();
+                    let distance_b = (b.price as f64 - reference).abs();
+                    distance_a.partial_cmp(&distance_b).unwrap()
+                })
+                .unwrap(),
+            None => best.first().unwrap(),
+        }
+    };
+
+    let surplus = chosen.surplus();
+    AuctionIndicative {
+        symbol: symbol.to_string(),
+        price: Some(chosen.price),
+        executable_volume: max_volume,
+        imbalance: surplus.unsigned_abs(),
+        imbalance_side: if surplus > 0 {
+            Some(OrderSide::Buy)
+        } else if surplus < 0 {
+            Some(OrderSide::Sell)
+        } else {
+            None
+        },
+    }
+}
+
+// Indices of the orders on one side that trade at `price`, in priority order:
+// market orders first, then best price, then earliest arrival.
+fn priority(orders: &[QueuedOrder], side: OrderSide, price: u32) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..orders.len())
+        .filter(|i| orders[*i].side == side && crosses(&orders[*i], price))
+        .collect();
+    indices.sort_by_key(|i| {
+        let queued = &orders[*i];
+        let limit = match side {
+            OrderSide::Buy => -(queued.order.price as i64),
+            OrderSide::Sell => queued.order.price as i64,
+        };
+        (!is_market(queued), limit, queued.queued_at)
+    });
+    indices
+}
+
+pub async fn indicative(con: &mut MultiplexedConnection, symbol: &str) -> Result<AuctionIndicative, ApiError> {
+    let orders = queued_orders(con, symbol).await?;
+    let reference: Option<f64> = con.hget(LAST_PRICE_KEY, symbol).await?;
+    Ok(equilibrium(symbol, &orders, reference))
+}
+
+// Matches the call book at `price`, settling each fill as it goes. Quantities are
+// reduced and `filled` marked as fills settle, so on error they show what is left.
+async fn uncross(
+    state: &AppState,
+    con: &mut MultiplexedConnection,
+    symbol: &str,
+    orders: &mut [QueuedOrder],
+    filled: &mut [bool],
+    price: u32,
+) -> Result<(), ApiError> {
+    let buys = priority(orders, OrderSide::Buy, price);
+    let sells = priority(orders, OrderSide::Sell, price);
+    let (mut b, mut s) = (0, 0);
+
+    while b < buys.len() && s < sells.len() {
+        let (buy, sell) = (buys[b], sells[s]);
+        let quantity = orders[buy].order.quantity.min(orders[sell].order.quantity);
+
+        // Neither order reached the contract, so the fill has no contract ids and
+        // the earlier-queued order is treated as the resting side for fees
+        let maker = if orders[buy].queued_at <= orders[sell].queued_at { OrderSide::Buy } else { OrderSide::Sell };
+        let event = OrderMatchedEvent {
+            position: None,
+            source: FillSource::Auction { maker },
+            venue: state.venues.for_symbol(symbol).name.clone(),
+            buy_order_id: U256::zero(),
+            sell_order_id: U256::zero(),
+            symbol: symbol.to_string(),
+            quantity: U256::from(quantity),
+            price: U256::from(price),
+            buyer: state.config.account,
+            buyer_user_id: orders[buy].username.clone(),
+            buyer_order_id: orders[buy].order_id.clone(),
+            seller: state.config.account,
+            seller_user_id: orders[sell].username.clone(),
+            seller_order_id: orders[sell].order_id.clone(),
+        };
+        if !settle_fill(state, con, &event).await? {
+            // The sell order can't be delivered; it stays unfilled and the next one is tried
+            s += 1;
+            continue;
+        }
+
+        filled[buy] = true;
+        filled[sell] = true;
+        orders[buy].order.quantity -= quantity;
+        orders[sell].order.quantity -= quantity;
+        if orders[buy].order.quantity == 0 {
+            b += 1;
+        }
+        if orders[sell].order.quantity == 0 {
+            s += 1;
+        }
+    }
+    Ok(())
+}
+
+// Uncrosses the call book when an auction phase ends. Every fill settles at the single
+// equilibrium price exactly as a contract OrderMatched event would. After the opening
+// auction unfilled limit quantity is requeued for continuous trading; everything else
+// left in the book (market remainders, anything after the close) expires.
+pub async fn run_auction(state: &AppState, con: &mut MultiplexedConnection, symbol: &str, phase: SessionPhase) -> Result<(), ApiError> {
+    let mut orders = queued_orders(con, symbol).await?;
+    let reference: Option<f64> = con.hget(LAST_PRICE_KEY, symbol).await?;
+    let result = equilibrium(symbol, &orders, reference);
+    let _: () = con.del(queued_orders_key(symbol)).await?;
+
+    info!("Uncrossing {:?} for {}: {:?}", phase, symbol, result);
+
+    let original: Vec<u32> = orders.iter().map(|queued| queued.order.quantity).collect();
+    let mut filled = vec![false; orders.len()];
+
+    if let Some(price) = result.price {
+        if let Err(e) = uncross(state, con, symbol, &mut orders, &mut filled, price).await {
+            // The book was taken off the queue above, so put back whatever has not
+            // traded; fills settled before the failure keep their reduced quantity
+            error!("Auction for {} failed part-way, requeueing its unfilled orders: {}", symbol, e);
+            for queued in orders.iter().filter(|queued| queued.order.quantity > 0) {
+                if let Err(requeue_error) = queue_order(con, queued).await {
+                    error!("Could not requeue order {} after the failed auction: {}", queued.order_id, requeue_error);
+                }
+            }
+            return Err(e);
+        }
+    }
+
+    for (index, queued) in orders.iter().enumerate() {
+        let requeue = phase == SessionPhase::OpeningAuction && !is_market(queued);
+        if queued.order.quantity > 0 && requeue {
+            // Recorded as accepted at its full quantity if it traded; the requeued
+            // remainder replaces the record once it is released
+            if filled[index] {
+                let request = OrderRequest { quantity: original[index], ..queued.order.clone() };
+                record_order(con, &queued.username, &queued.order_id, &request, OrderStatus::Accepted).await?;
+            }
+            queue_order(con, queued).await?;
+        } else if filled[index] {
+            // Accepted for the quantity that traded; any remainder expired with the call
+            let request = OrderRequest { quantity: original[index] - queued.order.quantity, ..queued.order.clone() };
+            record_order(con, &queued.username, &queued.order_id, &request, OrderStatus::Accepted).await?;
+        } else {
+            record_order(con, &queued.username, &queued.order_id, &queued.order, OrderStatus::Expired).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn order(side: OrderSide, quantity: u32, price: Option<u32>) -> QueuedOrder {
+        QueuedOrder {
+            order_id: "order".to_string(),
+            username: "alice".to_string(),
+            side,
+            order: OrderRequest {
+                symbol: "AAPL".to_string(),
+                quantity,
+                price: price.unwrap_or(0),
+                order_type: if price.is_some() { OrderType::Limit } else { OrderType::Market },
+            },
+            queued_at: Utc::now(),
+        }
+    }
+
+    fn buy(quantity: u32, price: u32) -> QueuedOrder {
+        order(OrderSide::Buy, quantity, Some(price))
+    }
+
+    fn sell(quantity: u32, price: u32) -> QueuedOrder {
+        order(OrderSide::Sell, quantity, Some(price))
+    }
+
+    #[test]
+    fn picks_equilibrium_price() {
+        // (case, orders, reference, price, executable volume, imbalance, imbalance side)
+        let cases = [
+            ("max volume", vec![buy(10, 101), buy(5, 100), sell(12, 100), sell(3, 101)], None, Some(100), 12, 3, Some(OrderSide::Buy)),
+            ("min surplus", vec![buy(10, 102), sell(10, 100), sell(5, 101)], None, Some(100), 10, 0, None),
+            ("buy pressure", vec![buy(15, 101), sell(10, 99)], None, Some(101), 10, 5, Some(OrderSide::Buy)),
+            ("sell pressure", vec![buy(10, 101), sell(15, 99)], None, Some(99), 10, 5, Some(OrderSide::Sell)),
+            ("reference above", vec![buy(10, 102), sell(10, 98)], Some(101.0), Some(102), 10, 0, None),
+            ("reference below", vec![buy(10, 102), sell(10, 98)], Some(99.0), Some(98), 10, 0, None),
+            ("no reference", vec![buy(10, 102), sell(10, 98)], None, Some(98), 10, 0, None),
+            ("market only", vec![order(OrderSide::Buy, 5, None), order(OrderSide::Sell, 5, None)], Some(100.4), Some(100), 5, 0, None),
+            ("market only without reference", vec![order(OrderSide::Buy, 5, None), order(OrderSide::Sell, 5, None)], None, None, 0, 0, None),
+            ("no cross", vec![buy(5, 98), sell(5, 100)], Some(99.0), None, 0, 0, None),
+        ];
+
+        for (case, orders, reference, price, volume, imbalance, side) in cases {
+            let result = equilibrium("AAPL", &orders, reference);
+            assert_eq!(result.price, price, "{}", case);
+            assert_eq!(result.executable_volume, volume, "{}", case);
+            assert_eq!(result.imbalance, imbalance, "{}", case);
+            assert_eq!(result.imbalance_side, side, "{}", case);
+        }
+    }
+
+    #[test]
+    fn sums_volume_beyond_u32() {
+        let orders = vec![buy(u32::MAX, 100), buy(u32::MAX, 101), sell(u32::MAX, 99), sell(u32::MAX, 100)];
+        assert_eq!(volume_at(&orders, OrderSide::Buy, 100), 2 * u32::MAX as u64);
+
+        let result = equilibrium("AAPL", &orders, None);
+        assert_eq!(result.executable_volume, 2 * u32::MAX as u64);
+        assert_eq!(result.imbalance, 0);
+    }
+
+    #[test]
+    fn prioritises_market_then_price_then_time() {
+        let mut orders = vec![buy(5, 100), buy(5, 102), order(OrderSide::Buy, 5, None), buy(5, 102), buy(5, 99)];
+        orders[3].queued_at = orders[1].queued_at + chrono::Duration::seconds(1);
+        assert_eq!(priority(&orders, OrderSide::Buy, 100), vec![2, 1, 3, 0]);
+    }
+}
@@ -0,0 +1,291 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::dev::Payload;
+use actix_web::error::PayloadError;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, ResponseError};
+use bcrypt::hash;
+use futures::future::LocalBoxFuture;
+use futures::Stream;
+use log::{info, warn};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use tokio::sync::Mutex as AsyncMutex;
+use uuid::Uuid;
+
+use crate::api_keys::{
+    claim_nonce, get_api_key, ip_allowed, timestamp_fresh, verify_signature, API_KEY_HEADER, API_NONCE_HEADER,
+    API_SIGNATURE_HEADER, API_TIMESTAMP_HEADER,
+};
+use crate::errors::ApiError;
+use crate::handlers::validate_token;
+use crate::models::{ApiScope, Claims, Order, Portfolio, Role, UserState};
+use crate::users::{find_by_portfolio_id, find_by_user_id, index_user, load_user_state, user_key};
+use crate::state::AppState;
+
+// Role sets used when wiring routes in main.rs
+pub const READ_ROLES: &[Role] = &[Role::Trader, Role::ReadOnly, Role::Admin, Role::RiskOfficer];
+pub const TRADING_ROLES: &[Role] = &[Role::Trader, Role::Admin];
+pub const RISK_ROLES: &[Role] = &[Role::RiskOfficer, Role::Admin];
+pub const ADMIN_ROLES: &[Role] = &[Role::Admin];
+
+// Middleware that rejects requests whose Bearer token is missing, invalid, or carries
+// a role outside the allowed set. Routes built with with_api_key also accept requests
+// signed with an API key holding the given scope. The validated Claims are left in the
+// request extensions for handlers that need them.
+pub struct RequireRole {
+    roles: &'static [Role],
+    api_scope: Option<ApiScope>,
+}
+
+impl RequireRole {
+    pub fn new(roles: &'static [Role]) -> Self {
+        RequireRole { roles, api_scope: None }
+    }
+
+    pub fn with_api_key(roles: &'static [Role], api_scope: ApiScope) -> Self {
+        RequireRole { roles, api_scope: Some(api_scope) }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireRoleMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireRoleMiddleware {
+            service: Rc::new(service),
+            roles: self.roles,
+            api_scope: self.api_scope,
+        }))
+    }
+}
+
+pub struct RequireRoleMiddleware<S> {
+    service: Rc<S>,
+    roles: &'static [Role],
+    api_scope: Option<ApiScope>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let roles = self.roles;
+        let api_scope = self.api_scope;
+
+        Box::pin(async move {
+            let data = req.app_data::<web::Data<AsyncMutex<AppState>>>().unwrap().clone();
+
+            let claims = match api_scope {
+                Some(api_scope) if req.headers().contains_key(API_KEY_HEADER) => {
+                    match authenticate_api_key(&mut req, &data, api_scope).await {
+                        Ok(claims) => claims,
+                        Err(e) => return Ok(req.into_response(e.error_response()).map_into_right_body()),
+                    }
+                },
+                _ => {
+                    let validated = validate_token(req.request(), &*data.lock().await).await;
+                    match validated {
+                        Ok(token_data) => token_data.claims,
+                        Err(e) => {
+                            return Ok(req.into_response(e.error_response()).map_into_right_body());
+                        }
+                    }
+                },
+            };
+
+            if !roles.contains(&claims.role) {
+                warn!("{} ({:?}) denied access to {}", claims.sub, claims.role, req.path());
+                let response = ApiError::Forbidden("Insufficient role for this route".to_string()).error_response();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            req.extensions_mut().insert(claims);
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+// Authenticates a request signed with an API key and returns claims standing in for
+// the owner's token. The body is read to check the signature and then put back for
+// the handler's extractors.
+async fn authenticate_api_key(
+    req: &mut ServiceRequest,
+    data: &web::Data<AsyncMutex<AppState>>,
+    api_scope: ApiScope,
+) -> Result<Claims, ApiError> {
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok()).unwrap_or("").to_string();
+    let key_id = header(API_KEY_HEADER);
+    let timestamp = header(API_TIMESTAMP_HEADER);
+    let nonce = header(API_NONCE_HEADER);
+    let signature = header(API_SIGNATURE_HEADER);
+    if timestamp.is_empty() || nonce.is_empty() || signature.is_empty() {
+        return Err(ApiError::Unauthorized("Missing API signature headers".to_string()));
+    }
+
+    let body = req.extract::<web::Bytes>().await.map_err(|_| ApiError::InvalidRequest("Unreadable request body".to_string()))?;
+    let replay = body.clone();
+    let stream: Pin<Box<dyn Stream<Item = Result<web::Bytes, PayloadError>>>> =
+        Box::pin(futures::stream::once(async move { Ok(replay) }));
+    req.set_payload(Payload::from(stream));
+
+    let (mut con, window_seconds) = {
+        let state = data.lock().await;
+        (state.redis_client.get_multiplexed_async_connection().await?, state.config.api_signature_window_seconds)
+    };
+    let api_key = match get_api_key(&mut con, &key_id).await? {
+        Some(api_key) => api_key,
+        None => return Err(ApiError::Unauthorized("Unknown API key".to_string())),
+    };
+
+    if !ip_allowed(&api_key, req.peer_addr().map(|addr| addr.ip())) {
+        warn!("API key {} used from disallowed address {:?}", api_key.key_id, req.peer_addr());
+        return Err(ApiError::Forbidden("Address not allowed for this API key".to_string()));
+    }
+    if !api_key.scopes.contains(&api_scope) {
+        return Err(ApiError::Forbidden(format!("API key lacks the {:?} scope", api_scope)));
+    }
+    if !timestamp_fresh(&timestamp, window_seconds) {
+        return Err(ApiError::Unauthorized("Request timestamp outside the allowed window".to_string()));
+    }
+
+    let path_and_query = req.uri().path_and_query().map(|path| path.as_str()).unwrap_or(req.path()).to_string();
+    if !verify_signature(&api_key, &timestamp, &nonce, req.method().as_str(), &path_and_query, &body, &signature) {
+        return Err(ApiError::Unauthorized("Invalid request signature".to_string()));
+    }
+    // Claimed only once the signature checks out, so nobody can burn another key's nonces
+    if !claim_nonce(&mut con, &api_key.key_id, &nonce, window_seconds).await? {
+        return Err(ApiError::Unauthorized("Nonce already used".to_string()));
+    }
+
+    // Lets rate limiting bucket signed requests per key rather than per user
+    req.extensions_mut().insert(api_key.clone());
+    match load_user_state(&mut con, &api_key.username).await? {
+        Some(user_state) => Ok(Claims { sub: user_state.username, exp: 0, role: user_state.role, jti: String::new(), iat: 0 }),
+        None => Err(ApiError::Unauthorized("API key owner no longer exists".to_string())),
+    }
+}
+
+// The authenticated caller, resolved from Claims.sub. Behind RequireRole the claims
+// are taken from the request extensions, otherwise the Bearer token is validated here.
+pub struct Principal {
+    pub username: String,
+    pub role: Role,
+}
+
+impl FromRequest for Principal {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(claims) = req.extensions().get::<Claims>() {
+            let principal = Principal { username: claims.sub.clone(), role: claims.role };
+            return Box::pin(async move { Ok(principal) });
+        }
+
+        let req = req.clone();
+        Box::pin(async move {
+            let data = req.app_data::<web::Data<AsyncMutex<AppState>>>().unwrap().clone();
+            let claims = validate_token(&req, &*data.lock().await).await?.claims;
+            Ok(Principal { username: claims.sub, role: claims.role })
+        })
+    }
+}
+
+impl Principal {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    // Loads the user a user-scoped route names. Anyone may name themselves, by username
+    // or user_id; only admins may name someone else. Non-admins get 403 whether or not
+    // the other user exists.
+    pub async fn target_user(&self, con: &mut MultiplexedConnection, user_id: &str) -> Result<UserState, ApiError> {
+        if let Some(own) = load_user_state(con, &self.username).await? {
+            if own.username == user_id || own.user_id == user_id {
+                return Ok(own);
+            }
+        }
+        if !self.is_admin() {
+            return Err(ApiError::Forbidden("Not allowed to access another user's data".to_string()));
+        }
+        find_by_user_id(con, user_id).await?.ok_or_else(|| ApiError::NotFound("User not found".to_string()))
+    }
+
+    pub async fn target_portfolio(&self, con: &mut MultiplexedConnection, portfolio_id: &str) -> Result<UserState, ApiError> {
+        if let Some(own) = load_user_state(con, &self.username).await? {
+            if own.portfolio.portfolio_id == portfolio_id {
+                return Ok(own);
+            }
+        }
+        if !self.is_admin() {
+            return Err(ApiError::Forbidden("Not allowed to access another user's data".to_string()));
+        }
+        find_by_portfolio_id(con, portfolio_id).await?.ok_or_else(|| ApiError::NotFound("Portfolio not found".to_string()))
+    }
+
+    pub async fn authorize_order(&self, con: &mut MultiplexedConnection, order: &Order) -> Result<(), ApiError> {
+        if self.is_admin() {
+            return Ok(());
+        }
+        match load_user_state(con, &self.username).await? {
+            Some(own) if own.user_id == order.user_id => Ok(()),
+            _ => Err(ApiError::Forbidden("Not allowed to access another user's data".to_string())),
+        }
+    }
+}
+
+// Creates the admin account named by ADMIN_USERNAME / ADMIN_PASSWORD on startup if it
+// does not exist yet. Further admins are promoted through utils/post/role.
+pub async fn bootstrap_admin(redis_client: &redis::Client, bcrypt_cost: u32) -> Result<(), ApiError> {
+    let (username, password) = match (env::var("ADMIN_USERNAME"), env::var("ADMIN_PASSWORD")) {
+        (Ok(username), Ok(password)) => (username, password),
+        _ => return Ok(()),
+    };
+
+    let mut con = redis_client.get_multiplexed_async_connection().await?;
+    let user_state = UserState {
+        user_id: Uuid::new_v4().to_string(),
+        username: username.clone(),
+        password: hash(&password, bcrypt_cost)?,
+        orders: vec![],
+        transactions: vec![],
+        portfolio: Portfolio {
+            portfolio_id: Uuid::new_v4().to_string(),
+            total_money: 0.0,
+            assets: HashMap::new(),
+            realized_pnl: 0.0,
+        },
+        role: Role::Admin,
+    };
+
+    let created: bool = con.set_nx(user_key(&username), serde_json::to_string(&user_state)?).await?;
+    if created {
+        index_user(&mut con, &user_state).await?;
+        info!("Bootstrapped admin user {}", username);
+    } else {
+        info!("Admin bootstrap skipped: user {} already exists", username);
+    }
+    Ok(())
+}
@@ -0,0 +1,356 @@
+use actix_web::web;
+use log::{error, info, warn};
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Direction};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::Mutex as AsyncMutex;
+use uuid::Uuid;
+use web3::contract::Options;
+use web3::transports::WebSocket;
+use web3::types::{Address, Log, U256};
+use web3::Web3;
+
+use crate::bindings::events::{BuyOrderPlaced, SellOrderPlaced};
+use crate::bindings::functions::PlaceOrders;
+use crate::bindings::{Event, Function, OrderInput};
+use crate::models::{Order, OrderSide, OrderStatus, QueuedOrder};
+use crate::orders::{attach_transaction, contract_order_type, order_book, send_call, send_order};
+use crate::receipts::track_transaction;
+use crate::config::AbiVersion;
+use crate::errors::ApiError;
+use crate::kill_switch::blocking_kill_switch;
+use crate::state::AppState;
+use crate::users::{load_user_state, save_user_state};
+use crate::venues::{Venue, Venues};
+
+// Orders waiting for the next batch, oldest first
+pub const ORDER_BATCH_KEY: &str = "order_batch";
+// Orders of failed batches, each sent in a transaction of its own
+pub const SINGLE_ORDERS_KEY: &str = "order_batch_singles";
+
+// placeOrders only exists in an OrderBook deployed from the current contracts/OrderBook.sol.
+// An empty batch is estimated against the deployed contract, which reverts without it.
+pub async fn contract_supports_batching(web3: &Web3<WebSocket>, contract_address: Address) -> bool {
+    let contract = order_book(web3, contract_address);
+    contract.estimate_gas(PlaceOrders::NAME, PlaceOrders { orders: vec![] }, Address::zero(), Options::default()).await.is_ok()
+}
+
+pub async fn enqueue_order(con: &mut MultiplexedConnection, queued: &QueuedOrder) -> Result<(), ApiError> {
+    let _: () = con.rpush(ORDER_BATCH_KEY, serde_json::to_string(queued)?).await?;
+    Ok(())
+}
+
+pub async fn resubmit_singly(con: &mut MultiplexedConnection, orders: &[QueuedOrder]) -> Result<(), ApiError> {
+    for queued in orders {
+        let _: () = con.rpush(SINGLE_ORDERS_KEY, serde_json::to_string(queued)?).await?;
+    }
+    Ok(())
+}
+
+// Orders the batcher has taken off a queue and not yet sent, rejected or put back
+fn in_flight_key(key: &str) -> String {
+    format!("{}:in_flight", key)
+}
+
+// Moves up to `count` orders onto the queue's in-flight list, where they stay until
+// acked, so the orders of a batcher that stops before sending them are not lost
+async fn take(con: &mut MultiplexedConnection, key: &str, count: usize) -> Result<Vec<QueuedOrder>, ApiError> {
+    let mut taken = vec![];
+    while taken.len() < count {
+        let queued_json: Option<String> = con.lmove(key, in_flight_key(key), Direction::Left, Direction::Right).await?;
+        match queued_json {
+            Some(queued_json) => taken.push(serde_json::from_str(&queued_json)?),
+            None => break,
+        }
+    }
+    Ok(taken)
+}
+
+// Drops an order the batcher is done with from its in-flight list. Left there if this
+// fails, it is recovered on the next start once its recorded status shows it was handled.
+async fn ack(con: &mut MultiplexedConnection, key: &str, queued: &QueuedOrder) {
+    let acked = match serde_json::to_string(queued) {
+        Ok(queued_json) => con.lrem::<_, _, ()>(in_flight_key(key), 1, queued_json).await.map_err(ApiError::from),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = acked {
+        error!("Could not ack order {} on {}: {}", queued.order_id, key, e);
+    }
+}
+
+// Whether the order is still submitted with no transaction, so it never reached the contract
+async fn awaiting_send(con: &mut MultiplexedConnection, queued: &QueuedOrder) -> Result<bool, ApiError> {
+    Ok(load_user_state(con, &queued.username).await?.is_some_and(|user_state| {
+        user_state.orders.iter().any(|order| order.order_id == queued.order_id && order.status == OrderStatus::Submitted)
+            && !user_state.transactions.iter().any(|transaction| transaction.order_id == queued.order_id)
+    }))
+}
+
+// Puts the orders a previous batcher took but never sent back at the front of their
+// queue, in order. Orders it sent or rejected before stopping are only dropped.
+async fn recover_in_flight(con: &mut MultiplexedConnection) -> Result<(), ApiError> {
+    for key in [SINGLE_ORDERS_KEY, ORDER_BATCH_KEY] {
+        let in_flight: Vec<String> = con.lrange(in_flight_key(key), 0, -1).await?;
+        for queued_json in in_flight.iter().rev() {
+            let queued: QueuedOrder = serde_json::from_str(queued_json)?;
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            if awaiting_send(con, &queued).await? {
+                info!("Recovered order {} taken from {} but never sent", queued.order_id, key);
+                pipe.lpush(key, queued_json).ignore();
+            }
+            let _: () = pipe.lrem(in_flight_key(key), 1, queued_json).ignore().query_async(con).await?;
+        }
+    }
+    Ok(())
+}
+
+// Returns a taken order to the front of its queue
+async fn put_back(con: &mut MultiplexedConnection, key: &str, queued: &QueuedOrder) -> Result<(), ApiError> {
+    let queued_json = serde_json::to_string(queued)?;
+    let _: () = redis::pipe()
+        .atomic()
+        .lpush(key, &queued_json).ignore()
+        .lrem(in_flight_key(key), 1, &queued_json).ignore()
+        .query_async(con)
+        .await?;
+    Ok(())
+}
+
+// order_ids of the BuyOrderPlaced and SellOrderPlaced events the contract emitted
+pub fn placed_order_ids(logs: &[Log], contract_address: Address) -> HashSet<String> {
+    logs.iter()
+        .filter(|log| log.address == contract_address)
+        .filter_map(|log| {
+            BuyOrderPlaced::decode(log)
+                .map(|placed| placed.order_id)
+                .or_else(|| SellOrderPlaced::decode(log).map(|placed| placed.order_id))
+        })
+        .collect()
+}
+
+// The queued order as one entry of a placeOrders batch
+fn batch_entry(queued: &QueuedOrder) -> OrderInput {
+    OrderInput {
+        is_buy: queued.side == OrderSide::Buy,
+        symbol: queued.order.symbol.clone(),
+        quantity: U256::from(queued.order.quantity),
+        price: U256::from(queued.order.price),
+        user_id: queued.username.clone(),
+        order_id: queued.order_id.clone(),
+        order_type: contract_order_type(&queued.order.order_type),
+    }
+}
+
+// Marks an order that could not be sent at all. Call with the AppState lock held, as
+// fills and new orders also rewrite the user state.
+async fn reject_order(con: &mut MultiplexedConnection, queued: &QueuedOrder) -> Result<(), ApiError> {
+    let order_json: Option<String> = con.get(&queued.order_id).await?;
+    if let Some(order_json) = order_json {
+        let mut order: Order = serde_json::from_str(&order_json)?;
+        order.status = OrderStatus::Rejected;
+        let _: () = con.set(&queued.order_id, serde_json::to_string(&order)?).await?;
+    }
+
+    if let Some(mut user_state) = load_user_state(con, &queued.username).await? {
+        for order in user_state.orders.iter_mut().filter(|order| order.order_id == queued.order_id) {
+            order.status = OrderStatus::Rejected;
+        }
+        save_user_state(con, &user_state).await?;
+    }
+    Ok(())
+}
+
+// Drops the orders waiting for the order batcher that match a kill switch's scope and
+// marks them rejected. Call with the AppState lock held.
+pub async fn cancel_batched_orders(con: &mut MultiplexedConnection, username: Option<&str>, symbol: Option<&str>) -> Result<Vec<QueuedOrder>, ApiError> {
+    let mut cancelled = vec![];
+    for key in [ORDER_BATCH_KEY, SINGLE_ORDERS_KEY] {
+        let waiting: Vec<String> = con.lrange(key, 0, -1).await?;
+        for queued_json in waiting {
+            let queued: QueuedOrder = serde_json::from_str(&queued_json)?;
+            let in_scope = username.is_none_or(|username| queued.username == username)
+                && symbol.is_none_or(|symbol| queued.order.symbol == symbol);
+            if !in_scope {
+                continue;
+            }
+            // The batcher may have taken it already; it re-checks kill switches before sending
+            let removed: u32 = con.lrem(key, 1, &queued_json).await?;
+            if removed > 0 {
+                reject_order(con, &queued).await?;
+                cancelled.push(queued);
+            }
+        }
+    }
+    Ok(cancelled)
+}
+
+// The RPCs run on a copy of the state; the lock is only taken for the user state writes
+async fn send_single(
+    data: &web::Data<AsyncMutex<AppState>>,
+    state: &AppState,
+    venue: &Venue,
+    con: &mut MultiplexedConnection,
+    key: &str,
+    queued: &QueuedOrder,
+) {
+    let sent = send_order(state, venue, &queued.username, &queued.order_id, &queued.order, queued.side).await;
+    let _state = data.lock().await;
+    let recorded = match sent {
+        Ok((tx_hash, from)) => match track_transaction(con, &tx_hash, &queued.order_id, &queued.username, &venue.name, from, vec![]).await {
+            Ok(()) => attach_transaction(con, &queued.username, &queued.order_id, &tx_hash).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => {
+            error!("Could not submit order {}: {:?}", queued.order_id, e);
+            reject_order(con, queued).await
+        },
+    };
+    if let Err(e) = recorded {
+        error!("Could not record the outcome of order {}: {}", queued.order_id, e);
+    }
+    ack(con, key, queued).await;
+}
+
+// Sends the orders in one placeOrders transaction, or one transaction each if the
+// batch cannot be sent. The tracker resubmits them singly if the batch later reverts.
+async fn send_batch(
+    data: &web::Data<AsyncMutex<AppState>>,
+    state: &AppState,
+    venue: &Venue,
+    con: &mut MultiplexedConnection,
+    key: &str,
+    batch: Vec<QueuedOrder>,
+) {
+    let orders = PlaceOrders { orders: batch.iter().map(batch_entry).collect() };
+
+    // Batching is refused with per-user accounts, so every batch is sent from the shared account
+    let sent = match venue.web3() {
+        Ok(web3) => send_call(state, venue, &order_book(&web3, venue.contract_address), orders, "").await,
+        Err(e) => Err(e.into()),
+    };
+    match sent {
+        Ok((tx_hash, from)) => {
+            let batch_id = format!("batch-{}", Uuid::new_v4());
+            info!("Sent {} with {} orders to {}: tx_id = {}", batch_id, batch.len(), venue.name, tx_hash);
+            let _state = data.lock().await;
+            for queued in &batch {
+                if let Err(e) = attach_transaction(con, &queued.username, &queued.order_id, &tx_hash).await {
+                    error!("Could not record the transaction of order {}: {}", queued.order_id, e);
+                }
+            }
+            if let Err(e) = track_transaction(con, &tx_hash, &batch_id, "", &venue.name, from, batch.clone()).await {
+                error!("Could not track {} in transaction {}: {}", batch_id, tx_hash, e);
+            }
+            for queued in &batch {
+                ack(con, key, queued).await;
+            }
+        },
+        Err(e) => {
+            warn!("Could not send a batch of {} orders, submitting them singly: {:?}", batch.len(), e);
+            for queued in &batch {
+                send_single(data, state, venue, con, key, queued).await;
+            }
+        },
+    }
+}
+
+// Splits orders by the venue their symbol is routed to, keeping arrival order
+fn by_venue(venues: &Venues, orders: Vec<QueuedOrder>) -> Vec<(Arc<Venue>, Vec<QueuedOrder>)> {
+    let mut groups: Vec<(Arc<Venue>, Vec<QueuedOrder>)> = vec![];
+    for queued in orders {
+        let venue = venues.for_symbol(&queued.order.symbol);
+        match groups.iter_mut().find(|(grouped, _)| grouped.name == venue.name) {
+            Some((_, group)) => group.push(queued),
+            None => groups.push((venue.clone(), vec![queued])),
+        }
+    }
+    groups
+}
+
+// Every batch_window_ms, sends the orders that arrived since in batches of up to
+// batch_max_orders per venue, after any orders of failed batches. Venues without
+// placeOrders get each order in a transaction of its own.
+pub async fn run_order_batcher(data: web::Data<AsyncMutex<AppState>>) {
+    let (window, redis_client) = {
+        let state = data.lock().await;
+        (state.config.batch_window_ms, state.redis_client.clone())
+    };
+    let recovered = match redis_client.get_multiplexed_async_connection().await {
+        Ok(mut con) => recover_in_flight(&mut con).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = recovered {
+        error!("Order batcher could not recover the orders it had in flight: {}", e);
+    }
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_millis(window));
+    loop {
+        interval.tick().await;
+
+        // A copy of the handles, so the AppState lock is not held across RPCs
+        let state = data.lock().await.clone();
+        let mut con = match state.redis_client.get_multiplexed_async_connection().await {
+            Ok(con) => con,
+            Err(e) => {
+                error!("Order batcher could not connect to Redis: {:?}", e);
+                continue;
+            }
+        };
+
+        // Orders for a venue whose fills cannot be settled wait for a later window
+        let max_orders = state.config.batch_max_orders;
+        for (key, batching) in [(SINGLE_ORDERS_KEY, false), (ORDER_BATCH_KEY, true)] {
+            let mut waiting = vec![];
+            loop {
+                let taken = match take(&mut con, key, max_orders).await {
+                    Ok(taken) => taken,
+                    Err(e) => {
+                        error!("Order batcher could not take orders from {}: {}", key, e);
+                        break;
+                    }
+                };
+                if taken.is_empty() {
+                    break;
+                }
+                // A kill switch engaged since the order was accepted still stops it
+                let mut allowed = vec![];
+                for queued in taken {
+                    match blocking_kill_switch(&mut con, &queued.username, &queued.order.symbol).await {
+                        Ok(Some(kill_switch)) => {
+                            warn!("Dropped batched order {}: kill switch engaged: {}", queued.order_id, kill_switch.reason);
+                            let _state = data.lock().await;
+                            if let Err(e) = reject_order(&mut con, &queued).await {
+                                error!("Could not reject batched order {}: {}", queued.order_id, e);
+                            }
+                            ack(&mut con, key, &queued).await;
+                        },
+                        Ok(None) => allowed.push(queued),
+                        Err(e) => {
+                            error!("Could not check kill switches for order {}: {}", queued.order_id, e);
+                            waiting.push(queued);
+                        },
+                    }
+                }
+                let taken = allowed;
+                for (venue, orders) in by_venue(&state.venues, taken) {
+                    if !venue.health.accepting_orders() {
+                        waiting.extend(orders);
+                    } else if batching && venue.abi_version == AbiVersion::V2 && orders.len() > 1 {
+                        send_batch(&data, &state, &venue, &mut con, key, orders).await;
+                    } else {
+                        for queued in &orders {
+                            send_single(&data, &state, &venue, &mut con, key, queued).await;
+                        }
+                    }
+                }
+            }
+            for queued in waiting.iter().rev() {
+                if let Err(e) = put_back(&mut con, key, queued).await {
+                    error!("Could not put order {} back on {}: {}", queued.order_id, key, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+// The ABI has calls and events the server does not use, and the generated conversions
+// are written per item rather than per type
+#![allow(dead_code, clippy::redundant_closure)]
+
+use std::sync::OnceLock;
+use web3::contract::tokens::{Detokenize, Tokenize};
+use web3::contract::{Contract, Options};
+use web3::signing::keccak256;
+use web3::transports::WebSocket;
+use web3::types::{Log, H256};
+
+// Typed calls, events and structs of the OrderBook contract, generated by build.rs from
+// contracts/OrderBook.abi.json. A call, argument or event field the ABI no longer has
+// stops the server compiling.
+include!(concat!(env!("OUT_DIR"), "/order_book.rs"));
+
+// A contract function and its arguments
+pub trait Function: Tokenize + Clone {
+    const NAME: &'static str;
+}
+
+// A function that returns values, read with `query`
+pub trait View: Function {
+    type Output: Detokenize;
+}
+
+pub trait Event: Sized {
+    const SIGNATURE: &'static str;
+
+    // Decodes the log's fields without checking which event it is
+    fn from_log(log: &Log) -> Option<Self>;
+
+    fn topic() -> H256 {
+        H256::from(keccak256(Self::SIGNATURE.as_bytes()))
+    }
+
+    // The event, if `log` is one
+    fn decode(log: &Log) -> Option<Self> {
+        if log.topics.first() != Some(&Self::topic()) {
+            return None;
+        }
+        Self::from_log(log)
+    }
+}
+
+// The ABI, parsed once
+pub fn abi() -> &'static ethabi::Contract {
+    static ABI: OnceLock<ethabi::Contract> = OnceLock::new();
+    ABI.get_or_init(|| ethabi::Contract::load(ABI_JSON.as_bytes()).unwrap())
+}
+
+pub async fn query<V: View>(contract: &Contract<WebSocket>, call: V) -> Result<V::Output, web3::contract::Error> {
+    contract.query(V::NAME, call, None, Options::default(), None).await
+}
@@ -0,0 +1,902 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use web3::signing::{Key, SecretKey, SecretKeyRef};
+use web3::types::H160;
+
+use crate::rate_limit::Limit;
+use crate::tokens::JwtKeys;
+use crate::valuation::CostBasisMethod;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    Dev,
+    Test,
+    Prod,
+}
+
+impl Profile {
+    // APP_ENV picks the profile; unset means dev
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("APP_ENV").unwrap_or_default().as_str() {
+            "" | "dev" | "development" => Ok(Profile::Dev),
+            "test" => Ok(Profile::Test),
+            "prod" | "production" => Ok(Profile::Prod),
+            other => Err(format!("APP_ENV must be dev, test or prod, got {:?}", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Test => "test",
+            Profile::Prod => "prod",
+        }
+    }
+}
+
+// How order transactions are priced
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeeMode {
+    // A single gas price, for chains and dev nodes without EIP-1559
+    Legacy,
+    // Type 2 transactions with a max fee and a priority fee
+    Eip1559,
+}
+
+// Which functions a deployed OrderBook contract has
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbiVersion {
+    // placeBuyOrder and placeSellOrder
+    V1,
+    // Adds placeOrders, from contracts/OrderBook.sol
+    V2,
+}
+
+// The venue configured by the top-level ws_url and contract_address. It takes every
+// symbol no other venue lists.
+pub const DEFAULT_VENUE: &str = "default";
+
+// A chain and OrderBook contract that orders are routed to
+#[derive(Debug, Clone)]
+pub struct VenueConfig {
+    pub name: String,
+    // Checked against the node's eth_chainId on startup when set
+    pub chain_id: Option<u64>,
+    pub ws_url: String,
+    pub contract_address: H160,
+    pub abi_version: AbiVersion,
+    pub symbols: Vec<String>,
+}
+
+// A [[venues]] table in config/<profile>.toml; venues are only read from the file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileVenue {
+    name: String,
+    chain_id: Option<u64>,
+    ws_url: String,
+    contract_address: String,
+    abi_version: Option<String>,
+    #[serde(default)]
+    symbols: Vec<String>,
+}
+
+// Settings as read from config/<profile>.toml; every field may also come from the env
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    ws_url: Option<String>,
+    contract_address: Option<String>,
+    chain_id: Option<u64>,
+    abi_version: Option<String>,
+    account_address: Option<String>,
+    secret_key: Option<String>,
+    redis_url: Option<String>,
+    bind_addr: Option<String>,
+    gas_limit: Option<u64>,
+    shutdown_timeout_seconds: Option<u64>,
+    ready_max_listener_lag_blocks: Option<u64>,
+    ready_max_block_age_seconds: Option<u64>,
+    tx_confirmations: Option<u64>,
+    tx_drop_seconds: Option<u64>,
+    signer_keystore: Option<String>,
+    derive_user_accounts: Option<bool>,
+    gas_margin_percent: Option<u64>,
+    fee_mode: Option<String>,
+    gas_price_gwei: Option<u64>,
+    max_fee_gwei: Option<u64>,
+    priority_fee_gwei: Option<u64>,
+    fee_bump_after_seconds: Option<u64>,
+    fee_bump_percent: Option<u64>,
+    max_fee_bumps: Option<u64>,
+    batch_window_ms: Option<u64>,
+    batch_max_orders: Option<u64>,
+    reconcile_interval_seconds: Option<u64>,
+    reconcile_repair: Option<bool>,
+    cost_basis_method: Option<String>,
+    jwt_active_kid: Option<String>,
+    access_token_ttl_seconds: Option<u64>,
+    refresh_token_ttl_seconds: Option<u64>,
+    bcrypt_cost: Option<u64>,
+    login_max_attempts: Option<u64>,
+    login_lockout_seconds: Option<u64>,
+    api_signature_window_seconds: Option<u64>,
+    rate_limits: Option<String>,
+    rate_limit_default: Option<String>,
+    rate_limit_ip: Option<String>,
+    otr_max_ratio: Option<f64>,
+    otr_min_orders: Option<u64>,
+    otr_window_seconds: Option<u64>,
+    otr_throttle_seconds: Option<u64>,
+    #[serde(default)]
+    venues: Vec<FileVenue>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub profile: Profile,
+    // The default venue first, then the [[venues]] in file order
+    pub venues: Vec<VenueConfig>,
+    pub account: H160,
+    pub secret_key: String,
+    pub redis_url: String,
+    pub bind_addr: SocketAddr,
+    // Upper bound on the gas of an order transaction
+    pub gas_limit: u64,
+    // Added on top of each order's gas estimate
+    pub gas_margin_percent: u64,
+    pub fee_mode: FeeMode,
+    // Legacy gas price; None uses the node's eth_gasPrice
+    pub gas_price_gwei: Option<u64>,
+    // Ceiling on the per-gas fee in either mode, including after bumps
+    pub max_fee_gwei: Option<u64>,
+    pub priority_fee_gwei: u64,
+    // A transaction still pending this long is resent with higher fees; 0 turns bumping off
+    pub fee_bump_after_seconds: u64,
+    // Nodes refuse replacements that raise fees by less than 10%
+    pub fee_bump_percent: u64,
+    pub max_fee_bumps: u64,
+    // Orders are collected this long and sent together in one placeOrders call; 0 sends each order at once
+    pub batch_window_ms: u64,
+    pub batch_max_orders: usize,
+    // How often the contract's book is checked against Redis; 0 leaves it to the admin endpoint
+    pub reconcile_interval_seconds: u64,
+    // Whether the periodic check also repairs what it finds
+    pub reconcile_repair: bool,
+    // How long shutdown waits for in-flight requests and contract calls
+    pub shutdown_timeout_seconds: u64,
+    // /readyz fails once the listener is this many blocks behind the chain head
+    pub ready_max_listener_lag_blocks: u64,
+    // /readyz fails if the latest block is older than this; 0 turns the check off
+    pub ready_max_block_age_seconds: u64,
+    // Blocks on top of an order's transaction before the receipt tracker treats it as final
+    pub tx_confirmations: u64,
+    // A transaction the node no longer knows of after this long is marked dropped
+    pub tx_drop_seconds: u64,
+    // Key orders are signed with locally; None leaves signing to the node's unlocked account
+    pub signer_key: Option<SecretKey>,
+    // Sign each user's orders with a key derived from signer_key instead of signer_key itself
+    pub derive_user_accounts: bool,
+    pub cost_basis_method: CostBasisMethod,
+    // secret_key under the kid "default", plus any JWT_KEYS, with the access token lifetime
+    pub jwt_keys: JwtKeys,
+    pub refresh_token_ttl_seconds: i64,
+    // Trades login latency for resistance to offline cracking
+    pub bcrypt_cost: u32,
+    // Failed logins before a username is locked out, and for how long
+    pub login_max_attempts: i64,
+    pub login_lockout_seconds: i64,
+    // How far a signed request's timestamp may drift from server time
+    pub api_signature_window_seconds: i64,
+    // Token buckets per user or API key on the routes RATE_LIMITS names
+    pub rate_limits: HashMap<String, Limit>,
+    // For authenticated routes not in rate_limits, other than /buy and /sell
+    pub rate_limit_default: Limit,
+    // Per client address across every route
+    pub rate_limit_ip: Limit,
+    // A user entering at least otr_min_orders orders in otr_window_seconds at more than
+    // otr_max_ratio orders per fill is refused orders for otr_throttle_seconds
+    pub otr_max_ratio: f64,
+    pub otr_min_orders: i64,
+    pub otr_window_seconds: i64,
+    pub otr_throttle_seconds: i64,
+}
+
+// Every problem found while loading, so a bad deployment is fixed in one pass
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+// Env vars win over the file; ACCOUNT is accepted for ACCOUNT_ADDRESS as older setups used it
+fn setting(file_value: Option<String>, env_names: &[&str]) -> Option<String> {
+    env_names
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|value| !value.is_empty()))
+        .or(file_value)
+}
+
+fn check_ws_url(url: &str, key: &str, problems: &mut Vec<String>) {
+    if !url.starts_with("ws://") && !url.starts_with("wss://") {
+        problems.push(format!("{} must start with ws:// or wss://, got {:?}", key, url));
+    }
+}
+
+fn abi_version(value: Option<&str>, key: &str, problems: &mut Vec<String>) -> AbiVersion {
+    match value {
+        None | Some("v1") => AbiVersion::V1,
+        Some("v2") => AbiVersion::V2,
+        Some(other) => {
+            problems.push(format!("{} must be v1 or v2, got {:?}", key, other));
+            AbiVersion::V1
+        },
+    }
+}
+
+// Reads a whole-number setting that must be at least `min`
+fn number(file_value: Option<u64>, env_name: &str, key: &str, min: u64, problems: &mut Vec<String>) -> Option<u64> {
+    let value = setting(file_value.map(|value| value.to_string()), &[env_name])?;
+    match value.parse::<u64>() {
+        Ok(number) if number >= min => Some(number),
+        _ => {
+            problems.push(format!("{} must be an integer of at least {}, got {:?}", key, min, value));
+            None
+        },
+    }
+}
+
+// Reads a "rate:burst" token bucket setting
+fn limit(file_value: Option<String>, env_name: &str, key: &str, default: Limit, problems: &mut Vec<String>) -> Limit {
+    match setting(file_value, &[env_name]) {
+        Some(value) => Limit::parse(&value).unwrap_or_else(|| {
+            problems.push(format!("{} must be rate:burst with a positive rate and a burst of at least 1, got {:?}", key, value));
+            default
+        }),
+        None => default,
+    }
+}
+
+fn missing(key: &str, env_name: &str, source: &str) -> String {
+    format!("{} is not set (set {} or {} in {})", key, env_name, key, source)
+}
+
+// Reads a true/false setting
+fn flag(file_value: Option<bool>, env_name: &str, key: &str, problems: &mut Vec<String>) -> bool {
+    let value = setting(file_value.map(|value| value.to_string()), &[env_name]).unwrap_or_else(|| "false".to_string());
+    value.parse::<bool>().unwrap_or_else(|_| {
+        problems.push(format!("{} must be true or false, got {:?}", key, value));
+        false
+    })
+}
+
+struct SignerSettings {
+    key: Option<SecretKey>,
+    derive_user_accounts: bool,
+}
+
+fn signer_settings(file: &FileConfig, problems: &mut Vec<String>) -> SignerSettings {
+    // The raw key and keystore password are secrets, so they are only read from the env
+    let raw_signer_key = setting(None, &["SIGNER_PRIVATE_KEY"]);
+    let signer_keystore = setting(file.signer_keystore.clone(), &["SIGNER_KEYSTORE"]);
+    let key = match (raw_signer_key, signer_keystore) {
+        (Some(_), Some(_)) => {
+            problems.push("set only one of SIGNER_PRIVATE_KEY and signer_keystore".to_string());
+            None
+        },
+        (Some(raw), None) => hex::decode(raw.trim_start_matches("0x")).ok()
+            .and_then(|bytes| SecretKey::from_slice(&bytes).ok())
+            .or_else(|| {
+                problems.push("SIGNER_PRIVATE_KEY is not a valid hex secp256k1 key".to_string());
+                None
+            }),
+        (None, Some(path)) => {
+            let password = env::var("SIGNER_KEYSTORE_PASSWORD").unwrap_or_default();
+            match eth_keystore::decrypt_key(&path, password) {
+                Ok(bytes) => SecretKey::from_slice(&bytes).ok().or_else(|| {
+                    problems.push(format!("signer_keystore {} does not hold a secp256k1 key", path));
+                    None
+                }),
+                Err(e) => {
+                    problems.push(format!("signer_keystore {} could not be decrypted: {}", path, e));
+                    None
+                },
+            }
+        },
+        (None, None) => None,
+    };
+
+    let derive_user_accounts = flag(file.derive_user_accounts, "DERIVE_USER_ACCOUNTS", "derive_user_accounts", problems);
+    if derive_user_accounts && key.is_none() {
+        problems.push("derive_user_accounts needs SIGNER_PRIVATE_KEY or signer_keystore".to_string());
+    }
+    SignerSettings { key, derive_user_accounts }
+}
+
+struct ChainSettings {
+    venues: Vec<VenueConfig>,
+    account: H160,
+}
+
+fn address(value: Option<String>, key: &str, env_name: &str, source: &str, problems: &mut Vec<String>) -> H160 {
+    match value {
+        Some(value) => value.parse::<H160>().unwrap_or_else(|_| {
+            problems.push(format!("{} is not a valid address: {:?}", key, value));
+            H160::zero()
+        }),
+        None => {
+            problems.push(missing(key, env_name, source));
+            H160::zero()
+        },
+    }
+}
+
+// The default venue from the top-level settings, the [[venues]] tables and the sending account
+fn chain_settings(
+    profile: Profile,
+    file: &FileConfig,
+    source: &str,
+    signer_key: Option<&SecretKey>,
+    problems: &mut Vec<String>,
+) -> ChainSettings {
+    let ws_url = setting(file.ws_url.clone(), &["WS_URL"])
+        .or_else(|| (profile != Profile::Prod).then(|| "ws://localhost:8545".to_string()));
+    let ws_url = match ws_url {
+        Some(url) => {
+            check_ws_url(&url, "ws_url", problems);
+            url
+        },
+        None => {
+            problems.push(missing("ws_url", "WS_URL", source));
+            String::new()
+        },
+    };
+    let contract_address = setting(file.contract_address.clone(), &["CONTRACT_ADDRESS"]);
+    let contract_address = address(contract_address, "contract_address", "CONTRACT_ADDRESS", source, problems);
+
+    // With a local signer the account is the key's address, so it need not be set
+    let account = setting(file.account_address.clone(), &["ACCOUNT_ADDRESS", "ACCOUNT"]);
+    let signer_address = signer_key.map(|key| SecretKeyRef::new(key).address());
+    let account = match (account, signer_address) {
+        (None, Some(signer_address)) => signer_address,
+        (account, signer_address) => {
+            let account = address(account, "account_address", "ACCOUNT_ADDRESS", source, problems);
+            if signer_address.is_some_and(|signer_address| signer_address != account) {
+                problems.push(format!("account_address {:?} is not the signer key's address", account));
+            }
+            account
+        },
+    };
+
+    let chain_id = number(file.chain_id, "CHAIN_ID", "chain_id", 1, problems);
+    let default_abi = setting(file.abi_version.clone(), &["ABI_VERSION"]);
+    let mut venues = vec![VenueConfig {
+        name: DEFAULT_VENUE.to_string(),
+        chain_id,
+        ws_url,
+        contract_address,
+        abi_version: abi_version(default_abi.as_deref(), "abi_version", problems),
+        symbols: vec![],
+    }];
+    let mut routed = HashSet::new();
+    for venue in &file.venues {
+        let key = format!("venues.{}", venue.name);
+        if venue.name.is_empty() || venues.iter().any(|other| other.name == venue.name) {
+            problems.push(format!("venue names must be unique and not {:?}, got {:?}", DEFAULT_VENUE, venue.name));
+        }
+        check_ws_url(&venue.ws_url, &format!("{}.ws_url", key), problems);
+        let contract_address = venue.contract_address.parse::<H160>().unwrap_or_else(|_| {
+            problems.push(format!("{}.contract_address is not a valid address: {:?}", key, venue.contract_address));
+            H160::zero()
+        });
+        if venue.symbols.is_empty() {
+            problems.push(format!("{} routes no symbols", key));
+        }
+        for symbol in &venue.symbols {
+            if !routed.insert(symbol.clone()) {
+                problems.push(format!("{} is routed to more than one venue", symbol));
+            }
+        }
+        venues.push(VenueConfig {
+            abi_version: abi_version(venue.abi_version.as_deref(), &format!("{}.abi_version", key), problems),
+            name: venue.name.clone(),
+            chain_id: venue.chain_id,
+            ws_url: venue.ws_url.clone(),
+            contract_address,
+            symbols: venue.symbols.clone(),
+        });
+    }
+    ChainSettings { venues, account }
+}
+
+struct ServerSettings {
+    secret_key: String,
+    redis_url: String,
+    bind_addr: SocketAddr,
+    shutdown_timeout_seconds: u64,
+    ready_max_listener_lag_blocks: u64,
+    ready_max_block_age_seconds: u64,
+}
+
+fn server_settings(profile: Profile, file: &FileConfig, source: &str, problems: &mut Vec<String>) -> ServerSettings {
+    let secret_key = setting(file.secret_key.clone(), &["SECRET_KEY"]).unwrap_or_else(|| {
+        problems.push(missing("secret_key", "SECRET_KEY", source));
+        String::new()
+    });
+    if profile == Profile::Prod && !secret_key.is_empty() && secret_key.len() < 32 {
+        problems.push("secret_key must be at least 32 characters in prod".to_string());
+    }
+
+    let redis_url = setting(file.redis_url.clone(), &["REDIS_CLIENT_URL"])
+        .or_else(|| (profile != Profile::Prod).then(|| "redis://127.0.0.1/".to_string()))
+        .unwrap_or_else(|| {
+            problems.push(missing("redis_url", "REDIS_CLIENT_URL", source));
+            String::new()
+        });
+    if !redis_url.is_empty() && redis::Client::open(redis_url.as_str()).is_err() {
+        problems.push(format!("redis_url is not a valid Redis URL: {:?}", redis_url));
+    }
+
+    let bind_addr = setting(file.bind_addr.clone(), &["BIND_ADDR"]).unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let bind_addr = bind_addr.parse::<SocketAddr>().unwrap_or_else(|_| {
+        problems.push(format!("bind_addr must be host:port, got {:?}", bind_addr));
+        SocketAddr::from(([127, 0, 0, 1], 8080))
+    });
+
+    ServerSettings {
+        secret_key,
+        redis_url,
+        bind_addr,
+        shutdown_timeout_seconds: number(
+            file.shutdown_timeout_seconds, "SHUTDOWN_TIMEOUT_SECONDS", "shutdown_timeout_seconds", 0, problems,
+        ).unwrap_or(30),
+        ready_max_listener_lag_blocks: number(
+            file.ready_max_listener_lag_blocks, "READY_MAX_LISTENER_LAG_BLOCKS", "ready_max_listener_lag_blocks", 0, problems,
+        ).unwrap_or(12),
+        ready_max_block_age_seconds: number(
+            file.ready_max_block_age_seconds, "READY_MAX_BLOCK_AGE_SECONDS", "ready_max_block_age_seconds", 0, problems,
+        ).unwrap_or(0),
+    }
+}
+
+// Gas, fee pricing and when a sent transaction counts as final or dropped
+struct TransactionSettings {
+    gas_limit: u64,
+    gas_margin_percent: u64,
+    fee_mode: FeeMode,
+    gas_price_gwei: Option<u64>,
+    max_fee_gwei: Option<u64>,
+    priority_fee_gwei: u64,
+    fee_bump_after_seconds: u64,
+    fee_bump_percent: u64,
+    max_fee_bumps: u64,
+    tx_confirmations: u64,
+    tx_drop_seconds: u64,
+}
+
+fn transaction_settings(file: &FileConfig, problems: &mut Vec<String>) -> TransactionSettings {
+    let fee_mode = match setting(file.fee_mode.clone(), &["FEE_MODE"]).as_deref() {
+        None | Some("legacy") => FeeMode::Legacy,
+        Some("eip1559") => FeeMode::Eip1559,
+        Some(other) => {
+            problems.push(format!("fee_mode must be legacy or eip1559, got {:?}", other));
+            FeeMode::Legacy
+        },
+    };
+    let gas_price_gwei = number(file.gas_price_gwei, "GAS_PRICE_GWEI", "gas_price_gwei", 1, problems);
+    let max_fee_gwei = number(file.max_fee_gwei, "MAX_FEE_GWEI", "max_fee_gwei", 1, problems);
+    if let (Some(gas_price), Some(max_fee)) = (gas_price_gwei, max_fee_gwei) {
+        if gas_price > max_fee {
+            problems.push(format!("gas_price_gwei {} is above max_fee_gwei {}", gas_price, max_fee));
+        }
+    }
+
+    TransactionSettings {
+        gas_limit: number(file.gas_limit, "GAS_LIMIT", "gas_limit", 1, problems).unwrap_or(3_000_000),
+        gas_margin_percent: number(file.gas_margin_percent, "GAS_MARGIN_PERCENT", "gas_margin_percent", 0, problems).unwrap_or(20),
+        fee_mode,
+        gas_price_gwei,
+        max_fee_gwei,
+        priority_fee_gwei: number(file.priority_fee_gwei, "PRIORITY_FEE_GWEI", "priority_fee_gwei", 0, problems).unwrap_or(2),
+        fee_bump_after_seconds: number(
+            file.fee_bump_after_seconds, "FEE_BUMP_AFTER_SECONDS", "fee_bump_after_seconds", 0, problems,
+        ).unwrap_or(60),
+        fee_bump_percent: number(file.fee_bump_percent, "FEE_BUMP_PERCENT", "fee_bump_percent", 10, problems).unwrap_or(15),
+        max_fee_bumps: number(file.max_fee_bumps, "MAX_FEE_BUMPS", "max_fee_bumps", 0, problems).unwrap_or(3),
+        tx_confirmations: number(file.tx_confirmations, "TX_CONFIRMATIONS", "tx_confirmations", 1, problems).unwrap_or(1),
+        tx_drop_seconds: number(file.tx_drop_seconds, "TX_DROP_SECONDS", "tx_drop_seconds", 1, problems).unwrap_or(300),
+    }
+}
+
+// The order batcher and the reconciler
+struct BackgroundSettings {
+    batch_window_ms: u64,
+    batch_max_orders: usize,
+    reconcile_interval_seconds: u64,
+    reconcile_repair: bool,
+}
+
+fn background_settings(
+    file: &FileConfig,
+    derive_user_accounts: bool,
+    venues: &[VenueConfig],
+    problems: &mut Vec<String>,
+) -> BackgroundSettings {
+    let batch_window_ms = number(file.batch_window_ms, "BATCH_WINDOW_MS", "batch_window_ms", 0, problems).unwrap_or(0);
+    let batch_max_orders = number(file.batch_max_orders, "BATCH_MAX_ORDERS", "batch_max_orders", 2, problems).unwrap_or(20);
+    if batch_window_ms > 0 && derive_user_accounts {
+        problems.push("batch_window_ms cannot be combined with derive_user_accounts; a batch is sent from one account".to_string());
+    }
+    if batch_window_ms > 0 && !venues.iter().any(|venue| venue.abi_version == AbiVersion::V2) {
+        problems.push("batch_window_ms needs a venue with abi_version = \"v2\"".to_string());
+    }
+
+    BackgroundSettings {
+        batch_window_ms,
+        batch_max_orders: batch_max_orders as usize,
+        reconcile_interval_seconds: number(
+            file.reconcile_interval_seconds, "RECONCILE_INTERVAL_SECONDS", "reconcile_interval_seconds", 0, problems,
+        ).unwrap_or(0),
+        reconcile_repair: flag(file.reconcile_repair, "RECONCILE_REPAIR", "reconcile_repair", problems),
+    }
+}
+
+// Tokens, password hashing, login lockout and API key signatures
+struct AuthSettings {
+    jwt_keys: JwtKeys,
+    refresh_token_ttl_seconds: i64,
+    bcrypt_cost: u32,
+    login_max_attempts: i64,
+    login_lockout_seconds: i64,
+    api_signature_window_seconds: i64,
+}
+
+fn auth_settings(file: &FileConfig, secret_key: &str, problems: &mut Vec<String>) -> AuthSettings {
+    // JWT_KEYS holds "kid:secret" pairs separated by commas; as secrets they are only read from the env
+    let mut jwt_secrets = HashMap::new();
+    jwt_secrets.insert("default".to_string(), secret_key.to_string());
+    for pair in setting(None, &["JWT_KEYS"]).unwrap_or_default().split(',').filter(|pair| !pair.trim().is_empty()) {
+        match pair.trim().split_once(':') {
+            Some((kid, secret)) if !kid.is_empty() && !secret.is_empty() => {
+                jwt_secrets.insert(kid.to_string(), secret.to_string());
+            },
+            _ => problems.push(format!("JWT_KEYS entries must be kid:secret, got {:?}", pair.trim())),
+        }
+    }
+    let jwt_active_kid = setting(file.jwt_active_kid.clone(), &["JWT_ACTIVE_KID"]).unwrap_or_else(|| "default".to_string());
+    if !jwt_secrets.contains_key(&jwt_active_kid) {
+        problems.push(format!("jwt_active_kid {:?} is not in JWT_KEYS", jwt_active_kid));
+    }
+    let access_token_ttl_seconds = number(
+        file.access_token_ttl_seconds, "ACCESS_TOKEN_TTL_SECONDS", "access_token_ttl_seconds", 1, problems,
+    ).unwrap_or(15 * 60);
+
+    let bcrypt_cost = number(file.bcrypt_cost, "BCRYPT_COST", "bcrypt_cost", 10, problems).unwrap_or(bcrypt::DEFAULT_COST as u64);
+    if bcrypt_cost > 31 {
+        problems.push(format!("bcrypt_cost must be at most 31, got {}", bcrypt_cost));
+    }
+
+    AuthSettings {
+        jwt_keys: JwtKeys {
+            active_kid: jwt_active_kid,
+            secrets: jwt_secrets,
+            access_token_ttl: access_token_ttl_seconds as i64,
+        },
+        refresh_token_ttl_seconds: number(
+            file.refresh_token_ttl_seconds, "REFRESH_TOKEN_TTL_SECONDS", "refresh_token_ttl_seconds", 1, problems,
+        ).unwrap_or(7 * 24 * 60 * 60) as i64,
+        bcrypt_cost: bcrypt_cost as u32,
+        login_max_attempts: number(file.login_max_attempts, "LOGIN_MAX_ATTEMPTS", "login_max_attempts", 1, problems).unwrap_or(5) as i64,
+        login_lockout_seconds: number(
+            file.login_lockout_seconds, "LOGIN_LOCKOUT_SECONDS", "login_lockout_seconds", 1, problems,
+        ).unwrap_or(15 * 60) as i64,
+        api_signature_window_seconds: number(
+            file.api_signature_window_seconds, "API_SIGNATURE_WINDOW_SECONDS", "api_signature_window_seconds", 1, problems,
+        ).unwrap_or(30) as i64,
+    }
+}
+
+// Request rate limits and order-to-trade monitoring
+struct LimitSettings {
+    rate_limits: HashMap<String, Limit>,
+    rate_limit_default: Limit,
+    rate_limit_ip: Limit,
+    otr_max_ratio: f64,
+    otr_min_orders: i64,
+    otr_window_seconds: i64,
+    otr_throttle_seconds: i64,
+}
+
+fn limit_settings(file: &FileConfig, problems: &mut Vec<String>) -> LimitSettings {
+    // RATE_LIMITS holds "path=rate:burst" pairs separated by commas, e.g. "/buy=1:3"
+    let mut rate_limits = HashMap::new();
+    for pair in setting(file.rate_limits.clone(), &["RATE_LIMITS"]).unwrap_or_default().split(',').filter(|pair| !pair.trim().is_empty()) {
+        match pair.trim().split_once('=').and_then(|(route, limit)| Some((route, Limit::parse(limit)?))) {
+            Some((route, limit)) if route.starts_with('/') => {
+                rate_limits.insert(route.to_string(), limit);
+            },
+            _ => problems.push(format!("rate_limits entries must be /path=rate:burst, got {:?}", pair.trim())),
+        }
+    }
+
+    let otr_max_ratio = setting(file.otr_max_ratio.map(|ratio| ratio.to_string()), &["OTR_MAX_RATIO"])
+        .unwrap_or_else(|| "20".to_string());
+    let otr_max_ratio = match otr_max_ratio.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 => ratio,
+        _ => {
+            problems.push(format!("otr_max_ratio must be a positive number, got {:?}", otr_max_ratio));
+            0.0
+        },
+    };
+
+    LimitSettings {
+        rate_limits,
+        rate_limit_default: limit(
+            file.rate_limit_default.clone(), "RATE_LIMIT_DEFAULT", "rate_limit_default", Limit { rate: 20.0, burst: 40.0 }, problems,
+        ),
+        rate_limit_ip: limit(file.rate_limit_ip.clone(), "RATE_LIMIT_IP", "rate_limit_ip", Limit { rate: 50.0, burst: 100.0 }, problems),
+        otr_max_ratio,
+        otr_min_orders: number(file.otr_min_orders, "OTR_MIN_ORDERS", "otr_min_orders", 1, problems).unwrap_or(50) as i64,
+        otr_window_seconds: number(file.otr_window_seconds, "OTR_WINDOW_SECONDS", "otr_window_seconds", 1, problems).unwrap_or(60 * 60) as i64,
+        otr_throttle_seconds: number(
+            file.otr_throttle_seconds, "OTR_THROTTLE_SECONDS", "otr_throttle_seconds", 1, problems,
+        ).unwrap_or(5 * 60) as i64,
+    }
+}
+
+impl Config {
+    // Reads config/<profile>.toml (CONFIG_DIR overrides the directory) if present, then
+    // the environment, then falls back to the profile's defaults
+    pub fn load() -> Result<Self, ConfigError> {
+        let profile = Profile::from_env().map_err(|e| ConfigError(vec![e]))?;
+        let config_dir = env::var("CONFIG_DIR").unwrap_or_else(|_| "config".to_string());
+        let path = Path::new(&config_dir).join(format!("{}.toml", profile.name()));
+
+        let file: FileConfig = if path.exists() {
+            let contents = fs::read_to_string(&path).map_err(|e| ConfigError(vec![format!("{}: {}", path.display(), e)]))?;
+            toml::from_str(&contents).map_err(|e| ConfigError(vec![format!("{}: {}", path.display(), e)]))?
+        } else {
+            FileConfig::default()
+        };
+
+        Config::resolve(profile, file, &path.display().to_string())
+    }
+
+    // Each section is parsed on its own, collecting every problem before any is reported
+    fn resolve(profile: Profile, file: FileConfig, source: &str) -> Result<Self, ConfigError> {
+        let mut problems = vec![];
+        let signer = signer_settings(&file, &mut problems);
+        let chain = chain_settings(profile, &file, source, signer.key.as_ref(), &mut problems);
+        let server = server_settings(profile, &file, source, &mut problems);
+        let transactions = transaction_settings(&file, &mut problems);
+        let background = background_settings(&file, signer.derive_user_accounts, &chain.venues, &mut problems);
+        let auth = auth_settings(&file, &server.secret_key, &mut problems);
+        let limits = limit_settings(&file, &mut problems);
+        let cost_basis_method = match setting(file.cost_basis_method, &["COST_BASIS_METHOD"]) {
+            Some(method) => CostBasisMethod::from_str(&method).unwrap_or_else(|| {
+                problems.push(format!("cost_basis_method must be fifo or average, got {:?}", method));
+                CostBasisMethod::AverageCost
+            }),
+            None => CostBasisMethod::AverageCost,
+        };
+
+        if !problems.is_empty() {
+            return Err(ConfigError(problems));
+        }
+
+        Ok(Config {
+            profile,
+            venues: chain.venues,
+            account: chain.account,
+            secret_key: server.secret_key,
+            redis_url: server.redis_url,
+            bind_addr: server.bind_addr,
+            gas_limit: transactions.gas_limit,
+            gas_margin_percent: transactions.gas_margin_percent,
+            fee_mode: transactions.fee_mode,
+            gas_price_gwei: transactions.gas_price_gwei,
+            max_fee_gwei: transactions.max_fee_gwei,
+            priority_fee_gwei: transactions.priority_fee_gwei,
+            fee_bump_after_seconds: transactions.fee_bump_after_seconds,
+            fee_bump_percent: transactions.fee_bump_percent,
+            max_fee_bumps: transactions.max_fee_bumps,
+            batch_window_ms: background.batch_window_ms,
+            batch_max_orders: background.batch_max_orders,
+            reconcile_interval_seconds: background.reconcile_interval_seconds,
+            reconcile_repair: background.reconcile_repair,
+            shutdown_timeout_seconds: server.shutdown_timeout_seconds,
+            ready_max_listener_lag_blocks: server.ready_max_listener_lag_blocks,
+            ready_max_block_age_seconds: server.ready_max_block_age_seconds,
+            tx_confirmations: transactions.tx_confirmations,
+            tx_drop_seconds: transactions.tx_drop_seconds,
+            signer_key: signer.key,
+            derive_user_accounts: signer.derive_user_accounts,
+            cost_basis_method,
+            jwt_keys: auth.jwt_keys,
+            refresh_token_ttl_seconds: auth.refresh_token_ttl_seconds,
+            bcrypt_cost: auth.bcrypt_cost,
+            login_max_attempts: auth.login_max_attempts,
+            login_lockout_seconds: auth.login_lockout_seconds,
+            api_signature_window_seconds: auth.api_signature_window_seconds,
+            rate_limits: limits.rate_limits,
+            rate_limit_default: limits.rate_limit_default,
+            rate_limit_ip: limits.rate_limit_ip,
+            otr_max_ratio: limits.otr_max_ratio,
+            otr_min_orders: limits.otr_min_orders,
+            otr_window_seconds: limits.otr_window_seconds,
+            otr_throttle_seconds: limits.otr_throttle_seconds,
+        })
+    }
+
+    pub fn is_production(&self) -> bool {
+        self.profile == Profile::Prod
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Settings are read from the process environment, which the tests share
+    static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+    // Every variable resolve reads that a test might otherwise pick up from the host
+    const ENV_NAMES: &[&str] = &[
+        "WS_URL", "CONTRACT_ADDRESS", "CHAIN_ID", "ABI_VERSION", "ACCOUNT_ADDRESS", "ACCOUNT", "SIGNER_PRIVATE_KEY",
+        "SIGNER_KEYSTORE", "DERIVE_USER_ACCOUNTS", "SECRET_KEY", "REDIS_CLIENT_URL", "BIND_ADDR", "GAS_LIMIT",
+        "SHUTDOWN_TIMEOUT_SECONDS", "READY_MAX_LISTENER_LAG_BLOCKS", "READY_MAX_BLOCK_AGE_SECONDS", "TX_CONFIRMATIONS",
+        "TX_DROP_SECONDS", "GAS_MARGIN_PERCENT", "FEE_MODE", "GAS_PRICE_GWEI", "MAX_FEE_GWEI", "PRIORITY_FEE_GWEI",
+        "FEE_BUMP_AFTER_SECONDS", "FEE_BUMP_PERCENT", "MAX_FEE_BUMPS", "BATCH_WINDOW_MS", "BATCH_MAX_ORDERS",
+        "RECONCILE_INTERVAL_SECONDS", "RECONCILE_REPAIR", "COST_BASIS_METHOD", "JWT_KEYS", "JWT_ACTIVE_KID",
+        "ACCESS_TOKEN_TTL_SECONDS", "REFRESH_TOKEN_TTL_SECONDS", "BCRYPT_COST", "LOGIN_MAX_ATTEMPTS",
+        "LOGIN_LOCKOUT_SECONDS", "API_SIGNATURE_WINDOW_SECONDS", "RATE_LIMITS", "RATE_LIMIT_DEFAULT", "RATE_LIMIT_IP",
+        "OTR_MAX_RATIO", "OTR_MIN_ORDERS", "OTR_WINDOW_SECONDS", "OTR_THROTTLE_SECONDS",
+    ];
+
+    const CONTRACT: &str = "0x5fbdb2315678afecb367f032d93f642f64180aa3";
+    const ACCOUNT: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+
+    // Resolves `file` with only `vars` set in the environment
+    fn resolve(profile: Profile, file: &str, vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let _environment = ENVIRONMENT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for name in ENV_NAMES {
+            env::remove_var(name);
+        }
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        let file: FileConfig = toml::from_str(file).unwrap();
+        let config = Config::resolve(profile, file, "config/test.toml");
+        for (name, _) in vars {
+            env::remove_var(name);
+        }
+        config
+    }
+
+    fn minimal_file() -> String {
+        format!("contract_address = {:?}\naccount_address = {:?}\nsecret_key = \"file-secret\"\n", CONTRACT, ACCOUNT)
+    }
+
+    fn problems(result: Result<Config, ConfigError>) -> Vec<String> {
+        match result {
+            Ok(_) => panic!("configuration was accepted"),
+            Err(ConfigError(problems)) => problems,
+        }
+    }
+
+    #[test]
+    fn dev_fills_in_local_defaults() {
+        let config = resolve(Profile::Dev, &minimal_file(), &[]).unwrap();
+        assert_eq!(config.venues[0].ws_url, "ws://localhost:8545");
+        assert_eq!(config.redis_url, "redis://127.0.0.1/");
+        assert_eq!(config.gas_limit, 3_000_000);
+        assert_eq!(config.jwt_keys.access_token_ttl, 15 * 60);
+        assert_eq!(config.fee_mode, FeeMode::Legacy);
+    }
+
+    #[test]
+    fn prod_requires_what_dev_defaults() {
+        let found = problems(resolve(Profile::Prod, &minimal_file(), &[]));
+        assert!(found.iter().any(|problem| problem.starts_with("ws_url is not set")), "{:?}", found);
+        assert!(found.iter().any(|problem| problem.starts_with("redis_url is not set")), "{:?}", found);
+        assert!(found.iter().any(|problem| problem.starts_with("secret_key must be at least 32")), "{:?}", found);
+    }
+
+    #[test]
+    fn file_values_override_defaults() {
+        let file = format!("{}ws_url = \"ws://file:8545\"\ngas_limit = 500000\nfee_mode = \"eip1559\"\n", minimal_file());
+        let config = resolve(Profile::Dev, &file, &[]).unwrap();
+        assert_eq!(config.venues[0].ws_url, "ws://file:8545");
+        assert_eq!(config.gas_limit, 500_000);
+        assert_eq!(config.fee_mode, FeeMode::Eip1559);
+    }
+
+    #[test]
+    fn env_overrides_the_file() {
+        let file = format!("{}ws_url = \"ws://file:8545\"\ngas_limit = 500000\n", minimal_file());
+        let config = resolve(Profile::Dev, &file, &[
+            ("WS_URL", "ws://env:8545"),
+            ("GAS_LIMIT", "750000"),
+            ("SECRET_KEY", "env-secret"),
+        ]).unwrap();
+        assert_eq!(config.venues[0].ws_url, "ws://env:8545");
+        assert_eq!(config.gas_limit, 750_000);
+        assert_eq!(config.secret_key, "env-secret");
+    }
+
+    #[test]
+    fn empty_env_values_fall_back_to_the_file() {
+        let file = format!("{}ws_url = \"ws://file:8545\"\n", minimal_file());
+        let config = resolve(Profile::Dev, &file, &[("WS_URL", "")]).unwrap();
+        assert_eq!(config.venues[0].ws_url, "ws://file:8545");
+    }
+
+    #[test]
+    fn legacy_account_variable_is_accepted() {
+        let file = format!("contract_address = {:?}\nsecret_key = \"file-secret\"\n", CONTRACT);
+        let config = resolve(Profile::Dev, &file, &[("ACCOUNT", ACCOUNT)]).unwrap();
+        assert_eq!(config.account, ACCOUNT.parse::<H160>().unwrap());
+    }
+
+    #[test]
+    fn reports_every_invalid_value_at_once() {
+        let found = problems(resolve(Profile::Dev, &minimal_file(), &[
+            ("WS_URL", "http://localhost:8545"),
+            ("GAS_LIMIT", "0"),
+            ("FEE_MODE", "turbo"),
+            ("RECONCILE_REPAIR", "yes"),
+            ("RATE_LIMIT_IP", "fast"),
+            ("BCRYPT_COST", "4"),
+        ]));
+        assert_eq!(found.len(), 6, "{:?}", found);
+        assert!(found[0].starts_with("ws_url must start with ws://"), "{:?}", found);
+    }
+
+    #[test]
+    fn rejects_conflicting_fee_ceilings() {
+        let found = problems(resolve(Profile::Dev, &minimal_file(), &[("GAS_PRICE_GWEI", "50"), ("MAX_FEE_GWEI", "40")]));
+        assert_eq!(found, vec!["gas_price_gwei 50 is above max_fee_gwei 40".to_string()]);
+    }
+
+    #[test]
+    fn rejects_an_active_kid_without_a_secret() {
+        let found = problems(resolve(Profile::Dev, &minimal_file(), &[("JWT_ACTIVE_KID", "next")]));
+        assert_eq!(found, vec!["jwt_active_kid \"next\" is not in JWT_KEYS".to_string()]);
+
+        let config = resolve(Profile::Dev, &minimal_file(), &[("JWT_ACTIVE_KID", "next"), ("JWT_KEYS", "next:rotated")]).unwrap();
+        assert_eq!(config.jwt_keys.secrets["next"], "rotated");
+        assert_eq!(config.jwt_keys.secrets["default"], "file-secret");
+    }
+
+    #[test]
+    fn batching_needs_a_v2_venue() {
+        let found = problems(resolve(Profile::Dev, &minimal_file(), &[("BATCH_WINDOW_MS", "50")]));
+        assert_eq!(found, vec!["batch_window_ms needs a venue with abi_version = \"v2\"".to_string()]);
+        assert!(resolve(Profile::Dev, &minimal_file(), &[("BATCH_WINDOW_MS", "50"), ("ABI_VERSION", "v2")]).is_ok());
+    }
+
+    #[test]
+    fn venues_must_route_distinct_symbols() {
+        let file = format!(
+            "{}\n[[venues]]\nname = \"l2\"\nws_url = \"ws://l2:8545\"\ncontract_address = {:?}\nsymbols = [\"AAPL\"]\n\n\
+             [[venues]]\nname = \"l3\"\nws_url = \"ws://l3:8545\"\ncontract_address = {:?}\nsymbols = [\"AAPL\"]\n",
+            minimal_file(), CONTRACT, CONTRACT,
+        );
+        let found = problems(resolve(Profile::Dev, &file, &[]));
+        assert_eq!(found, vec!["AAPL is routed to more than one venue".to_string()]);
+    }
+}
@@ -0,0 +1,80 @@
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::net::IpAddr;
+
+use crate::config::Config;
+use crate::errors::ApiError;
+
+// Counted per username and address, so failures from one address cannot lock the
+// owner out from everywhere else
+fn failed_logins_key(username: &str, ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(ip) => format!("failed_logins:{}:{}", username, ip),
+        None => format!("failed_logins:{}:unknown", username),
+    }
+}
+
+// 3 to 32 letters, digits, '_', '-' or '.'
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if username.len() < 3 || username.len() > 32 {
+        return Err("Username must be between 3 and 32 characters".to_string());
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+        return Err("Username may only contain letters, digits, '_', '-' and '.'".to_string());
+    }
+    Ok(())
+}
+
+// bcrypt ignores everything past 72 bytes, so longer passwords are refused rather
+// than silently truncated
+pub fn validate_password(username: &str, password: &str) -> Result<(), String> {
+    if password.len() < 10 {
+        return Err("Password must be at least 10 characters".to_string());
+    }
+    if password.len() > 72 {
+        return Err("Password must be at most 72 bytes".to_string());
+    }
+    if !password.chars().any(|c| c.is_alphabetic()) || !password.chars().any(|c| c.is_ascii_digit()) {
+        return Err("Password must contain both letters and digits".to_string());
+    }
+    if password.to_lowercase().contains(&username.to_lowercase()) {
+        return Err("Password must not contain the username".to_string());
+    }
+    Ok(())
+}
+
+// Seconds until the username may try again from this address, if it has used up its attempts
+pub async fn lockout_remaining(
+    con: &mut MultiplexedConnection,
+    config: &Config,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> Result<Option<i64>, ApiError> {
+    let failures: Option<i64> = con.get(failed_logins_key(username, ip)).await?;
+    if failures.unwrap_or(0) < config.login_max_attempts {
+        return Ok(None);
+    }
+    let ttl: i64 = con.ttl(failed_logins_key(username, ip)).await?;
+    Ok(Some(ttl.max(1)))
+}
+
+// Failures are counted per username whether or not it exists, and the count expires
+// login_lockout_seconds after the first failure in a run
+pub async fn record_failed_login(
+    con: &mut MultiplexedConnection,
+    config: &Config,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> Result<(), ApiError> {
+    let key = failed_logins_key(username, ip);
+    let failures: i64 = con.incr(&key, 1).await?;
+    if failures == 1 {
+        let _: () = con.expire(&key, config.login_lockout_seconds).await?;
+    }
+    Ok(())
+}
+
+pub async fn clear_failed_logins(con: &mut MultiplexedConnection, username: &str, ip: Option<IpAddr>) -> Result<(), ApiError> {
+    let _: () = con.del(failed_logins_key(username, ip)).await?;
+    Ok(())
+}
@@ -0,0 +1,179 @@
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use log::error;
+use serde_json::{json, Value};
+use std::fmt;
+
+// Every error a route can return. Responses carry a JSON body
+// {"code": ..., "message": ..., "details": ...} where `code` is one of the stable
+// strings from `code()`, listed in the README; clients should branch on it rather than
+// on the message.
+#[derive(Debug)]
+pub enum ApiError {
+    // The request body, path or query could not be parsed
+    InvalidRequest(String),
+    // The request parsed but a value breaks a rule
+    Validation(String),
+    OrderRejected(String),
+    InsufficientFunds,
+    Unauthorized(String),
+    Forbidden(String),
+    KillSwitchEngaged(String),
+    NotFound(String),
+    Conflict(String),
+    RateLimited { message: String, retry_after: i64 },
+    ServiceUnavailable(String),
+    Storage(redis::RedisError),
+    Chain(String),
+    Serialization(serde_json::Error),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::OrderRejected(_) => "order_rejected",
+            ApiError::InsufficientFunds => "insufficient_funds",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::KillSwitchEngaged(_) => "kill_switch_engaged",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::ServiceUnavailable(_) => "service_unavailable",
+            ApiError::Storage(_) => "storage_error",
+            ApiError::Chain(_) => "chain_error",
+            ApiError::Serialization(_) => "serialization_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    // Storage, chain and internal failures are logged in full but only described
+    // generically to the client
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::InvalidRequest(message)
+            | ApiError::Validation(message)
+            | ApiError::OrderRejected(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::ServiceUnavailable(message) => message.clone(),
+            ApiError::InsufficientFunds => "Insufficient funds".to_string(),
+            ApiError::KillSwitchEngaged(reason) => format!("Order entry blocked by kill switch: {}", reason),
+            ApiError::RateLimited { message, .. } => message.clone(),
+            ApiError::Storage(_) => "Storage is unavailable".to_string(),
+            ApiError::Chain(_) => "The blockchain node rejected or failed the request".to_string(),
+            ApiError::Serialization(_) => "Stored data could not be read".to_string(),
+            ApiError::Internal(_) => "Internal error".to_string(),
+        }
+    }
+
+    pub fn details(&self) -> Value {
+        match self {
+            ApiError::RateLimited { retry_after, .. } => json!({ "retry_after": retry_after }),
+            ApiError::KillSwitchEngaged(reason) => json!({ "reason": reason }),
+            _ => Value::Null,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Storage(e) => write!(f, "{}: {}", self.code(), e),
+            ApiError::Chain(e) | ApiError::Internal(e) => write!(f, "{}: {}", self.code(), e),
+            ApiError::Serialization(e) => write!(f, "{}: {}", self.code(), e),
+            _ => write!(f, "{}: {}", self.code(), self.message()),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidRequest(_)
+            | ApiError::Validation(_)
+            | ApiError::OrderRejected(_)
+            | ApiError::InsufficientFunds => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) | ApiError::KillSwitchEngaged(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ServiceUnavailable(_) | ApiError::Storage(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Chain(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Serialization(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if self.status_code().is_server_error() {
+            error!("{}", self);
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let ApiError::RateLimited { retry_after, .. } = self {
+            response.insert_header(("Retry-After", retry_after.to_string()));
+        }
+        response.json(json!({
+            "code": self.code(),
+            "message": self.message(),
+            "details": self.details()
+        }))
+    }
+}
+
+impl From<redis::RedisError> for ApiError {
+    fn from(e: redis::RedisError) -> Self {
+        ApiError::Storage(e)
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::Serialization(e)
+    }
+}
+
+impl From<web3::Error> for ApiError {
+    fn from(e: web3::Error) -> Self {
+        ApiError::Chain(e.to_string())
+    }
+}
+
+impl From<web3::contract::Error> for ApiError {
+    fn from(e: web3::contract::Error) -> Self {
+        ApiError::Chain(e.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for ApiError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        ApiError::Internal(format!("token error: {}", e))
+    }
+}
+
+impl From<bcrypt::BcryptError> for ApiError {
+    fn from(e: bcrypt::BcryptError) -> Self {
+        ApiError::Internal(format!("password hashing failed: {}", e))
+    }
+}
+
+// Extractor error handlers, so malformed bodies, paths and queries get the same JSON
+// shape as every other error
+pub fn json_error(err: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::InvalidRequest(err.to_string()).into()
+}
+
+pub fn path_error(err: PathError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::InvalidRequest(err.to_string()).into()
+}
+
+pub fn query_error(err: QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::InvalidRequest(err.to_string()).into()
+}
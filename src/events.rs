@@ -1,63 +1,134 @@
-use web3::types::{Log, Address, U256};
+use actix_web::web;
+use futures::StreamExt;
+use web3::types::{BlockNumber, FilterBuilder, Log, U256};
 use serde_json::json;
 use redis::AsyncCommands;
-use log::{info, error};
-use ethabi;
-use crate::models::OrderMatchedEvent;
+use redis::aio::MultiplexedConnection;
+use log::{debug, error, info};
+use crate::bindings::Event;
+use crate::bindings::events::OrderMatched;
+use crate::models::{EntryKind, FillSource, Liquidity, OrderMatchedEvent, OrderSide, Posting};
+use crate::ledger::{cash_account, ensure_opening_balance, post_entry, sync_cash, transfer};
+use crate::sessions::check_circuit_breaker;
+use crate::rate_limit::record_trade;
+use crate::fees::{fee_for_fill, liquidity, load_schedule, record_revenue, record_volume, FEE_ACCOUNT};
 use crate::state::AppState;
-use crate::models::{UserState, Order, Transaction, Portfolio, Asset};
-use serde_json::Value;
+use crate::venues::Venue;
+use crate::config::DEFAULT_VENUE;
+use crate::errors::ApiError;
+use std::sync::Arc;
+use crate::users::{load_user_state, user_key};
+use crate::valuation::{apply_buy, apply_sell, last_prices, new_asset, record_last_price, revalue};
 use tokio::sync::Mutex as AsyncMutex;
 
-pub fn parse_log(log: Log) -> Result<OrderMatchedEvent, web3::Error> {
-    let event_data = ethabi::decode(
-        &[
-            ethabi::ParamType::Uint(256),
-            ethabi::ParamType::Uint(256),
-            ethabi::ParamType::String,
-            ethabi::ParamType::Uint(256),
-            ethabi::ParamType::Uint(256),
-            ethabi::ParamType::Address,
-            ethabi::ParamType::String,
-            ethabi::ParamType::String,
-            ethabi::ParamType::Address,
-            ethabi::ParamType::String,
-            ethabi::ParamType::String,
-        ],
-        &log.data.0,
-    ).map_err(|e| web3::Error::from(format!("Failed to decode event data: {}", e)))?;
+pub fn parse_log(venue: &Venue, log: Log) -> Result<OrderMatchedEvent, web3::Error> {
+    let position = log_position(&log);
+    let event = OrderMatched::decode(&log)
+        .ok_or_else(|| web3::Error::from("Failed to decode event data: not an OrderMatched event".to_string()))?;
 
     Ok(OrderMatchedEvent {
-        buy_order_id: event_data[0].clone().into_uint().unwrap(),
-        sell_order_id: event_data[1].clone().into_uint().unwrap(),
-        symbol: event_data[2].clone().to_string(),
-        quantity: event_data[3].clone().into_uint().unwrap(),
-        price: event_data[4].clone().into_uint().unwrap(),
-        buyer: event_data[5].clone().into_address().unwrap(),
-        buyer_user_id: event_data[6].clone().to_string(),
-        buyer_order_id: event_data[7].clone().to_string(),
-        seller: event_data[8].clone().into_address().unwrap(),
-        seller_user_id: event_data[9].clone().to_string(),
-        seller_order_id: event_data[10].clone().to_string(),
+        source: FillSource::Contract,
+        venue: venue.name.clone(),
+        position,
+        buy_order_id: event.buy_order_id,
+        sell_order_id: event.sell_order_id,
+        symbol: event.symbol,
+        quantity: event.quantity,
+        price: event.price,
+        buyer: event.buyer,
+        buyer_user_id: event.buyer_user_id,
+        buyer_order_id: event.buyer_order_id,
+        seller: event.seller,
+        seller_user_id: event.seller_user_id,
+        seller_order_id: event.seller_order_id,
     })
 }
 
-pub async fn handle_event(data: web::Data<AsyncMutex<AppState>>, event: OrderMatchedEvent) {
-    println!("Order matched event received: {:?}", event);
+// Returns whether the fill was settled now
+pub async fn handle_event(data: web::Data<AsyncMutex<AppState>>, event: OrderMatchedEvent) -> Result<bool, ApiError> {
+    info!("Order matched event received: {:?}", event);
 
     let state = data.lock().await;
-    let mut con = state.redis_client.get_multiplexed_async_connection().await.unwrap();
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
 
-    // Update order book
-    let buy_order_key = format!("buy_order:{}", event.buy_order_id);
-    let sell_order_key = format!("sell_order:{}", event.sell_order_id);
+    settle_fill(&state, &mut con, &event).await
+}
+
+// Applies a fill to the order book, ledger and both portfolios. Contract OrderMatched
+// events and call auction uncrosses both settle through here. Returns false, with nothing
+// posted, when either user is unknown or the seller does not hold the shares, or when
+// the fill's log was already settled. A contract fill that cannot be settled has already
+// traded on chain, so it is kept in UNSETTLED_FILLS_KEY rather than dropped.
+pub async fn settle_fill(state: &AppState, con: &mut MultiplexedConnection, event: &OrderMatchedEvent) -> Result<bool, ApiError> {
+    // Remove matched orders from order book; auction fills have no contract orders
+    if event.source == FillSource::Contract {
+        let _: () = con.del(book_key(&event.venue, OrderSide::Buy, event.buy_order_id)).await?;
+        let _: () = con.del(book_key(&event.venue, OrderSide::Sell, event.sell_order_id)).await?;
+    }
+
+    let quantity = event.quantity.as_u64() as u32;
+    let price = event.price.as_u64() as f64;
+    let notional = quantity as f64 * price;
+
+    // Both sides are checked before anything is posted, so a fill settles in full or not at all
+    let buyer_state = load_user_state(con, &event.buyer_user_id).await?;
+    let seller_state = load_user_state(con, &event.seller_user_id).await?;
+    let (buyer_state, seller_state) = match (buyer_state, seller_state) {
+        (Some(buyer_state), Some(seller_state)) => (buyer_state, seller_state),
+        _ => {
+            error!("Not settling fill of {} and {}: unknown user", event.buyer_order_id, event.seller_order_id);
+            record_unsettled(con, event, "unknown user").await?;
+            return Ok(false);
+        },
+    };
+    let held = seller_state.portfolio.assets.get(&event.symbol).map_or(0, |asset| asset.shares);
+    if held < quantity {
+        error!(
+            "Not settling fill of {} and {}: seller {} holds {} {} of {}",
+            event.buyer_order_id, event.seller_order_id, event.seller_user_id, held, event.symbol, quantity
+        );
+        record_unsettled(con, event, &format!("seller holds {} of {} {}", held, quantity, event.symbol)).await?;
+        return Ok(false);
+    }
 
-    // Remove matched orders from order book
-    let _: () = con.del(buy_order_key).await.unwrap();
-    let _: () = con.del(sell_order_key).await.unwrap();
+    // Price both sides against the schedule before this fill counts toward their volume
+    let schedule = load_schedule(con).await?;
+    let (buyer_liquidity, seller_liquidity) = match event.source {
+        FillSource::Contract => (
+            liquidity(event.buy_order_id.as_u64(), event.sell_order_id.as_u64()),
+            liquidity(event.sell_order_id.as_u64(), event.buy_order_id.as_u64()),
+        ),
+        FillSource::Auction { maker: OrderSide::Buy } => (Liquidity::Maker, Liquidity::Taker),
+        FillSource::Auction { maker: OrderSide::Sell } => (Liquidity::Taker, Liquidity::Maker),
+    };
+    let buyer_fee = fee_for_fill(con, &schedule, &event.buyer_user_id, &event.symbol, buyer_liquidity, notional).await?;
+    let seller_fee = fee_for_fill(con, &schedule, &event.seller_user_id, &event.symbol, seller_liquidity, notional).await?;
+
+    // A contract fill's log is claimed before the first posting, so one replayed after a
+    // crash part-way through, or settled by the reconciler, is never posted twice. A
+    // failure from here on leaves the fill claimed and part-settled for reconcile to report.
+    if let Some((block, log_index)) = event.position {
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(settled_log_key(&event.venue, block, log_index))
+            .arg(1)
+            .arg("NX")
+            .query_async(con)
+            .await?;
+        if claimed.is_none() {
+            info!("Fill of {} and {} at block {} log {} is already settled", event.buyer_order_id, event.seller_order_id, block, log_index);
+            return Ok(false);
+        }
+        let _: () = con.hdel(UNSETTLED_FILLS_KEY, unsettled_field(event)).await?;
+    }
+    record_volume(con, &event.buyer_user_id, notional).await?;
+    record_volume(con, &event.seller_user_id, notional).await?;
+    record_trade(con, &state.config, &event.buyer_user_id).await?;
+    record_trade(con, &state.config, &event.seller_user_id).await?;
 
     // Add matched order to order history
     let matched_order = json!({
+        "source": event.source,
+        "venue": event.venue,
         "buy_order_id": event.buy_order_id,
         "sell_order_id": event.sell_order_id,
         "symbol": event.symbol,
@@ -66,108 +137,232 @@ pub async fn handle_event(data: web::Data<AsyncMutex<AppState>>, event: OrderMat
         "buyer": event.buyer,
         "buyer_user_id": event.buyer_user_id,
         "buyer_order_id": event.buyer_order_id,
+        "buyer_liquidity": buyer_liquidity,
+        "buyer_fee": buyer_fee,
         "seller": event.seller,
         "seller_user_id": event.seller_user_id,
-        "seller_order_id": event.seller_order_id
+        "seller_order_id": event.seller_order_id,
+        "seller_liquidity": seller_liquidity,
+        "seller_fee": seller_fee
     });
 
-    let _: () = con.rpush("order_history", serde_json::to_string(&matched_order).unwrap()).await.unwrap();
+    let _: () = con.rpush("order_history", serde_json::to_string(&matched_order)?).await?;
+
+    // Mark the symbol at the fill price before revaluing either side
+    record_last_price(con, &event.symbol, price).await?;
+    let prices = last_prices(con).await?;
+    check_circuit_breaker(con, &event.symbol, price).await?;
+
+    // Settle the cash leg through the ledger; portfolios only mirror the resulting balances
+    for user_state in [&buyer_state, &seller_state] {
+        ensure_opening_balance(con, user_state).await?;
+    }
+    let fill_reference = format!("{}:{}", event.buyer_order_id, event.seller_order_id);
+    transfer(
+        con,
+        EntryKind::Fill,
+        &fill_reference,
+        &cash_account(&event.buyer_user_id),
+        &cash_account(&event.seller_user_id),
+        notional,
+    ).await?;
+
+    if buyer_fee + seller_fee > 0.0 {
+        post_entry(con, EntryKind::Fee, &fill_reference, vec![
+            Posting { account: cash_account(&event.buyer_user_id), amount: -buyer_fee },
+            Posting { account: cash_account(&event.seller_user_id), amount: -seller_fee },
+            Posting { account: FEE_ACCOUNT.to_string(), amount: buyer_fee + seller_fee },
+        ]).await?;
+        record_revenue(con, &event.symbol, buyer_fee + seller_fee).await?;
+    }
 
     // Update buyer's portfolio
-    if let Ok(buyer_state_json) = con.get::<String, String>(event.buyer_user_id.clone()).await {
-        println!("Updating buyer's portfolio for buyer: {:?}", event.buyer);
-
-        let mut buyer_state: UserState = serde_json::from_str(&buyer_state_json).unwrap();
-        let asset = buyer_state.portfolio.assets.entry(event.symbol.clone()).or_insert(Asset {
-            symbol: event.symbol.clone(),
-            shares: 0,
-            market_value: 0.0,
-            average_cost: 0.0,
-            portfolio_diversity: 0.0,
-        });
-
-        let total_cost = asset.shares as f64 * asset.average_cost;
-        let new_total_cost = total_cost + event.quantity.as_u64() as f64 * event.price.as_u64() as f64;
-        asset.shares += event.quantity.as_u64() as u32;
-        asset.average_cost = new_total_cost / asset.shares as f64;
-        asset.market_value = asset.shares as f64 * event.price.as_u64() as f64;
-
-        // Print event quantity and price as u64
-        println!("Event quantity as u64: {:?}", event.quantity.as_u64());
-        println!("Event price as u64: {:?}", event.price);
-        println!("Buyer money {:?}", buyer_state.portfolio.total_money);
-
-        buyer_state.portfolio.total_money -= event.quantity.as_u64() as f64 * event.price.as_u64() as f64;
-
-        for asset in buyer_state.portfolio.assets.values_mut() {
-            asset.portfolio_diversity = asset.market_value / buyer_state.portfolio.total_money;
-        }
+    let mut buyer_state = buyer_state;
+    let asset = buyer_state.portfolio.assets.entry(event.symbol.clone()).or_insert(new_asset(&event.symbol));
+    apply_buy(asset, quantity, price);
+
+    sync_cash(con, &mut buyer_state).await?;
+    revalue(&mut buyer_state.portfolio, &prices);
+
+    let updated_buyer_state_json = serde_json::to_string(&buyer_state)?;
+    let _: () = con.set(user_key(&event.buyer_user_id), updated_buyer_state_json).await?;
+
+    debug!("Updated buyer's portfolio: {:?}", buyer_state.portfolio);
+
+    // Update seller's portfolio, re-read in case the seller is also the buyer
+    let mut seller_state = load_user_state(con, &event.seller_user_id).await?.unwrap_or(seller_state);
+    let mut asset = seller_state.portfolio.assets[&event.symbol].clone();
+    let realized = apply_sell(&mut asset, quantity, price, state.config.cost_basis_method);
+
+    sync_cash(con, &mut seller_state).await?;
+    seller_state.portfolio.realized_pnl += realized;
+
+    if asset.shares == 0 {
+        seller_state.portfolio.assets.remove(&event.symbol);
+    } else {
+        seller_state.portfolio.assets.insert(event.symbol.clone(), asset);
+    }
+    revalue(&mut seller_state.portfolio, &prices);
+
+    let updated_seller_state_json = serde_json::to_string(&seller_state)?;
+    let _: () = con.set(user_key(&event.seller_user_id), updated_seller_state_json).await?;
+
+    debug!("Updated seller's portfolio: {:?}, realized P&L {:?}", seller_state.portfolio, realized);
 
-        let updated_buyer_state_json = serde_json::to_string(&buyer_state).unwrap();
-        let _: () = con.set(event.buyer_user_id.clone(), updated_buyer_state_json).await.unwrap();
+    info!("Order matched and portfolios updated: buyer = {:?}, seller = {:?}", event.buyer, event.seller);
+    Ok(true)
+}
+
+// Redis key holding the (block number, log index) of the last contract log applied
+pub const LISTENER_CHECKPOINT_KEY: &str = "listener_checkpoint";
+
+// Redis hash of contract fills that could not be settled, keyed by venue and log, each
+// with the event and the reason. Reconciling with repair retries them; one that settles
+// is dropped from the hash.
+pub const UNSETTLED_FILLS_KEY: &str = "unsettled_fills";
+
+fn unsettled_field(event: &OrderMatchedEvent) -> String {
+    match event.position {
+        Some((block, log_index)) => format!("{}:{}:{}", event.venue, block, log_index),
+        None => format!("{}:{}:{}", event.venue, event.buyer_order_id, event.seller_order_id),
+    }
+}
 
-        println!("Updated buyer's portfolio: {:?}", buyer_state.portfolio);
+async fn record_unsettled(con: &mut MultiplexedConnection, event: &OrderMatchedEvent, reason: &str) -> Result<(), ApiError> {
+    if event.source != FillSource::Contract {
+        return Ok(());
     }
+    let entry = json!({ "event": event, "reason": reason, "recorded_at": chrono::Utc::now() });
+    let _: () = con.hset(UNSETTLED_FILLS_KEY, unsettled_field(event), serde_json::to_string(&entry)?).await?;
+    Ok(())
+}
 
-    // Update seller's portfolio
-    if let Ok(seller_state_json) = con.get::<String, String>(event.seller_user_id.clone()).await {
-        println!("Updating seller's portfolio for seller: {:?}", event.seller);
+fn settled_log_key(venue: &str, block: u64, log_index: u64) -> String {
+    format!("settled_log:{}:{}:{}", venue, block, log_index)
+}
 
-        let mut seller_state: UserState = serde_json::from_str(&seller_state_json).unwrap();
-        if let Some(mut asset) = seller_state.portfolio.assets.get_mut(&event.symbol).cloned() {
-            if asset.shares >= event.quantity.as_u64() as u32 {
-                asset.shares -= event.quantity.as_u64() as u32;
-                asset.market_value = asset.shares as f64 * event.price.as_u64() as f64;
+// Contract ids restart on every venue, so venues other than the default one prefix them
+fn book_key(venue: &str, side: OrderSide, id: U256) -> String {
+    if venue == DEFAULT_VENUE {
+        format!("{}_order:{}", side.as_str(), id)
+    } else {
+        format!("{}_order:{}:{}", side.as_str(), venue, id)
+    }
+}
 
-                // Print event quantity and price as u64
-                println!("Event quantity as u64: {:?}", event.quantity.as_u64());
-                println!("Event price as u64: {:?}", event.price);
-                println!("Seller money {:?}", seller_state.portfolio.total_money);
+// The default venue keeps the key from before venues, so its listener resumes in place
+fn checkpoint_key(venue: &str) -> String {
+    if venue == DEFAULT_VENUE {
+        LISTENER_CHECKPOINT_KEY.to_string()
+    } else {
+        format!("{}:{}", LISTENER_CHECKPOINT_KEY, venue)
+    }
+}
 
-                seller_state.portfolio.total_money += event.quantity.as_u64() as f64 * event.price.as_u64() as f64;
+pub async fn load_checkpoint(con: &mut MultiplexedConnection, venue: &str) -> Result<Option<(u64, u64)>, ApiError> {
+    let checkpoint_json: Option<String> = con.get(checkpoint_key(venue)).await?;
+    Ok(checkpoint_json.map(|checkpoint_json| serde_json::from_str(&checkpoint_json)).transpose()?)
+}
 
-                for asset in seller_state.portfolio.assets.values_mut() {
-                    asset.portfolio_diversity = asset.market_value / seller_state.portfolio.total_money;
-                }
+async fn save_checkpoint(con: &mut MultiplexedConnection, venue: &str, position: (u64, u64)) -> Result<(), ApiError> {
+    let _: () = con.set(checkpoint_key(venue), serde_json::to_string(&position)?).await?;
+    Ok(())
+}
 
-                if asset.shares == 0 {
-                    seller_state.portfolio.assets.remove(&event.symbol);
-                } else {
-                    seller_state.portfolio.assets.insert(event.symbol.clone(), asset);
-                }
+pub fn log_position(log: &Log) -> Option<(u64, u64)> {
+    Some((log.block_number?.as_u64(), log.log_index?.as_u64()))
+}
 
-                let updated_seller_state_json = serde_json::to_string(&seller_state).unwrap();
-                let _: () = con.set(event.seller_user_id.clone(), updated_seller_state_json).await.unwrap();
+fn redis_down(e: ApiError) -> web3::Error {
+    web3::Error::from(format!("Redis unavailable: {}", e))
+}
 
-                println!("Updated seller's portfolio: {:?}", seller_state.portfolio);
-            }
+// Applies a log unless the checkpoint shows it was already applied, then advances the
+// checkpoint past it. A fill that did not settle is only passed once it is recorded as
+// unsettled, so the checkpoint never moves past a fill that is neither.
+async fn apply_log(data: &web::Data<AsyncMutex<AppState>>, venue: &Venue, log: Log) -> Result<(), web3::Error> {
+    let position = log_position(&log);
+    let mut con = {
+        let state = data.lock().await;
+        state.redis_client.get_multiplexed_async_connection().await.map_err(|e| redis_down(e.into()))?
+    };
+    if let (Some(position), Some(checkpoint)) = (position, load_checkpoint(&mut con, &venue.name).await.map_err(redis_down)?) {
+        if position <= checkpoint {
+            return Ok(());
         }
     }
 
-    println!("Order matched and portfolios updated: buyer = {:?}, seller = {:?}", event.buyer, event.seller);
+    if let Ok(event) = parse_log(venue, log) {
+        // Not checkpointed on failure, so the restarted listener applies the log again;
+        // settle_fill skips it if it had already started posting
+        let settled = handle_event(data.clone(), event).await
+            .map_err(|e| web3::Error::from(format!("Failed to settle fill: {}", e)))?;
+        if settled {
+            venue.health.record_event();
+        }
+    }
+    if let Some(position) = position {
+        save_checkpoint(&mut con, &venue.name, position).await.map_err(redis_down)?;
+    }
+    Ok(())
 }
 
-pub async fn listen_for_events(data: web::Data<AsyncMutex<AppState>>) {
-    let transport = web3::transports::WebSocket::new("ws://localhost:8545").await.unwrap();
-    let web3 = web3::Web3::new(transport);
+// Subscribes to the venue contract's logs, first replaying anything emitted since the
+// venue's checkpoint, and applies them until the stream ends or fails. Run under
+// supervise_listener, which restarts it.
+pub async fn listen_for_events(data: web::Data<AsyncMutex<AppState>>, venue: Arc<Venue>) -> Result<(), web3::Error> {
+    let contract_address = venue.contract_address;
+    let checkpoint = {
+        let state = data.lock().await;
+        let mut con = state.redis_client.get_multiplexed_async_connection().await.map_err(|e| redis_down(e.into()))?;
+        load_checkpoint(&mut con, &venue.name).await.map_err(redis_down)?
+    };
+    // Also replaces the handle order-path calls use, so they follow the reconnect
+    let web3 = venue.reconnect().await.map_err(|e| web3::Error::from(e.to_string()))?;
 
-    let contract_address: H160 = env::var("CONTRACT_ADDRESS").expect("CONTRACT_ADDRESS not set in .env file").parse().expect("Invalid contract address");
-    let filter = ethabi::FilterBuilder::default()
+    let filter = FilterBuilder::default()
         .address(vec![contract_address])
         .build();
 
-    let mut event_stream = web3.eth_subscribe().subscribe_logs(filter).await.unwrap();
-
-    while let Some(log) = event_stream.next().await {
-        match log {
-            Ok(log) => {
-                if let Ok(event) = parse_log(log) {
-                    handle_event(data.clone(), event).await;
-                }
-            }
-            Err(e) => {
-                eprintln!("Error receiving log: {:?}", e);
-            }
+    // Subscribe before replaying so nothing falls between the two; overlap is skipped
+    // by the checkpoint
+    let mut event_stream = web3.eth_subscribe().subscribe_logs(filter).await?;
+    let mut head_stream = web3.eth_subscribe().subscribe_new_heads().await?;
+
+    if let Some((block_number, _)) = checkpoint {
+        let backfill = FilterBuilder::default()
+            .address(vec![contract_address])
+            .from_block(BlockNumber::Number(block_number.into()))
+            .build();
+        let missed = web3.eth().logs(backfill).await?;
+        info!("Replaying {} logs of {} since block {}", missed.len(), venue.name, block_number);
+        for log in missed {
+            apply_log(&data, &venue, log).await?;
+        }
+    }
+
+    venue.health.set_listener_up(true);
+    info!("Event listener for {} subscribed to {:?}", venue.name, contract_address);
+
+    loop {
+        tokio::select! {
+            log = event_stream.next() => match log {
+                Some(Ok(log)) => apply_log(&data, &venue, log).await?,
+                Some(Err(e)) => error!("Error receiving log from {}: {:?}", venue.name, e),
+                None => break,
+            },
+            // Heads arrive with or without contract logs, so readiness can tell an idle
+            // contract from a listener that fell behind
+            head = head_stream.next() => match head {
+                Some(Ok(head)) => {
+                    if let Some(number) = head.number {
+                        venue.health.record_block(number.as_u64());
+                    }
+                },
+                Some(Err(e)) => error!("Error receiving block head from {}: {:?}", venue.name, e),
+                None => break,
+            },
         }
     }
+    Ok(())
 }
@@ -0,0 +1,164 @@
+use chrono::{Duration, Utc};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+
+use crate::errors::ApiError;
+use crate::models::{FeeSchedule, Liquidity};
+
+// Redis key holding the active FeeSchedule as JSON
+pub const FEE_SCHEDULE_KEY: &str = "fee_schedule";
+// Redis hash of symbol -> fees collected
+pub const FEE_REVENUE_KEY: &str = "fee_revenue";
+// Ledger account that fee revenue is credited to
+pub const FEE_ACCOUNT: &str = "revenue:fees";
+
+const VOLUME_WINDOW_DAYS: i64 = 30;
+
+fn volume_key(user_id: &str) -> String {
+    format!("volume:{}", user_id)
+}
+
+// Without a stored schedule trading is free, which matches settlement before fees existed
+pub async fn load_schedule(con: &mut MultiplexedConnection) -> Result<FeeSchedule, ApiError> {
+    let schedule_json: Option<String> = con.get(FEE_SCHEDULE_KEY).await?;
+    match schedule_json {
+        Some(schedule_json) => Ok(serde_json::from_str(&schedule_json)?),
+        None => Ok(FeeSchedule::default()),
+    }
+}
+
+pub async fn save_schedule(con: &mut MultiplexedConnection, schedule: &FeeSchedule) -> Result<(), ApiError> {
+    let _: () = con.set(FEE_SCHEDULE_KEY, serde_json::to_string(schedule)?).await?;
+    Ok(())
+}
+
+// The contract assigns ids from an increasing counter, so the lower id of a match
+// is the order that was resting on the book.
+pub fn liquidity(order_id: u64, counterparty_order_id: u64) -> Liquidity {
+    if order_id < counterparty_order_id {
+        Liquidity::Maker
+    } else {
+        Liquidity::Taker
+    }
+}
+
+// Notional traded by the user over the trailing 30 days, bucketed per UTC day
+pub async fn rolling_volume(con: &mut MultiplexedConnection, user_id: &str) -> Result<f64, ApiError> {
+    let daily: HashMap<String, f64> = con.hgetall(volume_key(user_id)).await?;
+    let cutoff = (Utc::now() - Duration::days(VOLUME_WINDOW_DAYS)).format("%Y-%m-%d").to_string();
+    Ok(daily.iter()
+        .filter(|(day, _)| day.as_str() > cutoff.as_str())
+        .map(|(_, notional)| notional)
+        .sum())
+}
+
+pub async fn record_volume(con: &mut MultiplexedConnection, user_id: &str, notional: f64) -> Result<(), ApiError> {
+    let today = Utc::now().format("%Y-%m-%d").to_string();
+    let _: () = con.hincr(volume_key(user_id), today, notional).await?;
+    Ok(())
+}
+
+// Picks the rate for this fill: a symbol override wins, otherwise the highest
+// volume tier the user qualifies for, otherwise the base rate.
+pub fn rate(schedule: &FeeSchedule, symbol: &str, liquidity: Liquidity, volume: f64) -> f64 {
+    if let Some(rates) = schedule.symbol_rates.get(symbol) {
+        return rates.for_liquidity(liquidity);
+    }
+    schedule.tiers.iter()
+        .filter(|tier| volume >= tier.min_volume)
+        .max_by(|a, b| a.min_volume.partial_cmp(&b.min_volume).unwrap())
+        .map(|tier| tier.rates.for_liquidity(liquidity))
+        .unwrap_or(schedule.base_rates.for_liquidity(liquidity))
+}
+
+pub fn fee(schedule: &FeeSchedule, rate: f64, notional: f64) -> f64 {
+    if rate == 0.0 {
+        return 0.0;
+    }
+    (notional * rate).max(schedule.minimum_fee)
+}
+
+// Fee owed by one side of a fill, computed before the fill counts toward volume
+pub async fn fee_for_fill(
+    con: &mut MultiplexedConnection,
+    schedule: &FeeSchedule,
+    user_id: &str,
+    symbol: &str,
+    liquidity: Liquidity,
+    notional: f64,
+) -> Result<f64, ApiError> {
+    let volume = rolling_volume(con, user_id).await?;
+    Ok(fee(schedule, rate(schedule, symbol, liquidity, volume), notional))
+}
+
+pub async fn record_revenue(con: &mut MultiplexedConnection, symbol: &str, amount: f64) -> Result<(), ApiError> {
+    let _: () = con.hincr(FEE_REVENUE_KEY, symbol, amount).await?;
+    Ok(())
+}
+
+pub async fn revenue_by_symbol(con: &mut MultiplexedConnection) -> Result<HashMap<String, f64>, ApiError> {
+    Ok(con.hgetall(FEE_REVENUE_KEY).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{FeeRates, FeeTier};
+
+    // Base 10/20 bps, 8/16 bps from 1m of volume, 5/10 bps from 10m, and ETH at 1/2 bps.
+    // Tiers are listed out of order to check that the highest qualifying one wins.
+    fn schedule() -> FeeSchedule {
+        FeeSchedule {
+            base_rates: FeeRates { maker: 0.001, taker: 0.002 },
+            tiers: vec![
+                FeeTier { min_volume: 10_000_000.0, rates: FeeRates { maker: 0.0005, taker: 0.001 } },
+                FeeTier { min_volume: 1_000_000.0, rates: FeeRates { maker: 0.0008, taker: 0.0016 } },
+            ],
+            symbol_rates: HashMap::from([("ETH".to_string(), FeeRates { maker: 0.0001, taker: 0.0002 })]),
+            minimum_fee: 0.5,
+        }
+    }
+
+    #[test]
+    fn the_resting_order_is_the_maker() {
+        assert_eq!(liquidity(3, 7), Liquidity::Maker);
+        assert_eq!(liquidity(7, 3), Liquidity::Taker);
+    }
+
+    #[test]
+    fn new_users_pay_the_base_rate() {
+        assert_eq!(rate(&schedule(), "BTC", Liquidity::Maker, 0.0), 0.001);
+        assert_eq!(rate(&schedule(), "BTC", Liquidity::Taker, 999_999.99), 0.002);
+    }
+
+    #[test]
+    fn a_tier_applies_from_its_minimum_volume() {
+        assert_eq!(rate(&schedule(), "BTC", Liquidity::Taker, 1_000_000.0), 0.0016);
+        assert_eq!(rate(&schedule(), "BTC", Liquidity::Taker, 9_999_999.0), 0.0016);
+        assert_eq!(rate(&schedule(), "BTC", Liquidity::Maker, 10_000_000.0), 0.0005);
+        assert_eq!(rate(&schedule(), "BTC", Liquidity::Maker, 50_000_000.0), 0.0005);
+    }
+
+    #[test]
+    fn a_symbol_override_ignores_volume() {
+        assert_eq!(rate(&schedule(), "ETH", Liquidity::Maker, 0.0), 0.0001);
+        assert_eq!(rate(&schedule(), "ETH", Liquidity::Taker, 50_000_000.0), 0.0002);
+    }
+
+    #[test]
+    fn an_empty_schedule_is_free() {
+        let schedule = FeeSchedule::default();
+        let rate = rate(&schedule, "BTC", Liquidity::Taker, 0.0);
+        assert_eq!(rate, 0.0);
+        assert_eq!(fee(&schedule, rate, 1000.0), 0.0);
+    }
+
+    #[test]
+    fn small_fills_pay_the_minimum_fee() {
+        assert_eq!(fee(&schedule(), 0.002, 100_000.0), 200.0);
+        assert_eq!(fee(&schedule(), 0.002, 100.0), 0.5);
+        // A zero rate stays free rather than charging the minimum
+        assert_eq!(fee(&schedule(), 0.0, 100.0), 0.0);
+    }
+}
@@ -0,0 +1,161 @@
+use log::warn;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use serde_json::{json, Value};
+use web3::contract::Options;
+use web3::transports::WebSocket;
+use web3::types::{BlockId, BlockNumber, Transaction, TransactionRequest, U256, U64};
+use web3::Web3;
+
+use crate::config::{Config, FeeMode};
+use crate::errors::ApiError;
+use crate::models::TxRecord;
+
+// Totals across every order transaction that reached a final status
+pub const GAS_METRICS_KEY: &str = "gas_metrics";
+
+fn gwei(amount: u64) -> U256 {
+    U256::from(amount) * U256::exp10(9)
+}
+
+fn percent_of(amount: U256, percent: u64) -> U256 {
+    amount * U256::from(percent) / U256::from(100)
+}
+
+// Adds the safety margin to an estimate, never going over the configured gas limit
+pub fn gas_with_margin(estimate: U256, config: &Config) -> U256 {
+    let gas = percent_of(estimate, 100 + config.gas_margin_percent);
+    let limit = U256::from(config.gas_limit);
+    if gas > limit {
+        warn!("Gas estimate {} with margin exceeds gas_limit {}; capping", estimate, config.gas_limit);
+        return limit;
+    }
+    gas
+}
+
+// Per-gas fees for a transaction, in wei
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fees {
+    pub gas_price: Option<U256>,
+    pub max_fee_per_gas: Option<U256>,
+    pub max_priority_fee_per_gas: Option<U256>,
+}
+
+impl Fees {
+    pub fn apply(&self, options: &mut Options) {
+        options.gas_price = self.gas_price;
+        options.max_fee_per_gas = self.max_fee_per_gas;
+        options.max_priority_fee_per_gas = self.max_priority_fee_per_gas;
+        options.transaction_type = self.transaction_type();
+    }
+
+    pub fn transaction_type(&self) -> Option<U64> {
+        self.max_fee_per_gas.map(|_| U64::from(2))
+    }
+}
+
+fn capped(fee: U256, config: &Config) -> U256 {
+    match config.max_fee_gwei {
+        Some(max_fee) => fee.min(gwei(max_fee)),
+        None => fee,
+    }
+}
+
+// Fees for a new order transaction. EIP-1559 fees default to twice the latest base
+// fee plus the priority fee, which stays valid through several full blocks.
+pub async fn current_fees(web3: &Web3<WebSocket>, config: &Config) -> Result<Fees, web3::Error> {
+    match config.fee_mode {
+        FeeMode::Legacy => {
+            let gas_price = match config.gas_price_gwei {
+                Some(gas_price) => gwei(gas_price),
+                None => web3.eth().gas_price().await?,
+            };
+            Ok(Fees { gas_price: Some(capped(gas_price, config)), ..Default::default() })
+        },
+        FeeMode::Eip1559 => {
+            let block = web3.eth().block(BlockId::Number(BlockNumber::Latest)).await?;
+            let base_fee = block
+                .and_then(|block| block.base_fee_per_gas)
+                .ok_or_else(|| web3::Error::Decoder("node does not report a base fee; use fee_mode = \"legacy\"".to_string()))?;
+            let priority_fee = gwei(config.priority_fee_gwei);
+            let max_fee = capped(base_fee * 2 + priority_fee, config);
+            Ok(Fees {
+                gas_price: None,
+                max_fee_per_gas: Some(max_fee),
+                max_priority_fee_per_gas: Some(priority_fee.min(max_fee)),
+            })
+        },
+    }
+}
+
+// Fees for resending a stuck transaction, raised by fee_bump_percent over what it was
+// sent with. None if the bump would pass max_fee_gwei.
+pub fn bumped_fees(transaction: &Transaction, config: &Config) -> Option<Fees> {
+    let bump = |fee: U256| percent_of(fee, 100 + config.fee_bump_percent);
+    let within_cap = |fee: U256| config.max_fee_gwei.is_none_or(|max_fee| fee <= gwei(max_fee));
+
+    let fees = match transaction.max_fee_per_gas {
+        Some(max_fee) => Fees {
+            gas_price: None,
+            max_fee_per_gas: Some(bump(max_fee)),
+            max_priority_fee_per_gas: transaction.max_priority_fee_per_gas.map(bump),
+        },
+        None => Fees { gas_price: transaction.gas_price.map(bump), ..Default::default() },
+    };
+    let fee = fees.max_fee_per_gas.or(fees.gas_price)?;
+    if within_cap(fee) {
+        Some(fees)
+    } else {
+        None
+    }
+}
+
+// The same call as `transaction`, with the same nonce so it replaces it, at new fees
+pub fn replacement(transaction: &Transaction, fees: Fees) -> TransactionRequest {
+    TransactionRequest {
+        from: transaction.from.unwrap_or_default(),
+        to: transaction.to,
+        gas: Some(transaction.gas),
+        gas_price: fees.gas_price,
+        value: Some(transaction.value),
+        data: Some(transaction.input.clone()),
+        nonce: Some(transaction.nonce),
+        transaction_type: fees.transaction_type(),
+        max_fee_per_gas: fees.max_fee_per_gas,
+        max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+        ..Default::default()
+    }
+}
+
+pub async fn record_gas_metrics(con: &mut MultiplexedConnection, record: &TxRecord) -> Result<(), ApiError> {
+    let gas_used = record.gas_used.unwrap_or(0);
+    let cost_gwei = record.gas_cost_wei.map_or(0.0, |cost| cost.as_u128() as f64 / 1e9);
+    let _: () = redis::pipe()
+        .hincr(GAS_METRICS_KEY, "transactions", 1).ignore()
+        .hincr(GAS_METRICS_KEY, "orders", record.batch.len().max(1)).ignore()
+        .hincr(GAS_METRICS_KEY, "gas_used", gas_used).ignore()
+        .hincr(GAS_METRICS_KEY, "cost_gwei", cost_gwei).ignore()
+        .hincr(GAS_METRICS_KEY, "fee_bumps", record.replacements.len()).ignore()
+        .query_async(con)
+        .await?;
+    Ok(())
+}
+
+pub async fn gas_metrics(con: &mut MultiplexedConnection) -> Result<Value, ApiError> {
+    type Totals = (Option<u64>, Option<u64>, Option<u64>, Option<f64>, Option<u64>);
+    let (transactions, orders, gas_used, cost_gwei, fee_bumps): Totals = con
+        .hget(GAS_METRICS_KEY, &["transactions", "orders", "gas_used", "cost_gwei", "fee_bumps"])
+        .await?;
+    let transactions = transactions.unwrap_or(0);
+    // Totals from before batching have one order per transaction
+    let orders = orders.unwrap_or(transactions);
+    let gas_used = gas_used.unwrap_or(0);
+    Ok(json!({
+        "transactions": transactions,
+        "orders": orders,
+        "gas_used": gas_used,
+        "cost_gwei": cost_gwei.unwrap_or(0.0),
+        "fee_bumps": fee_bumps.unwrap_or(0),
+        "average_gas_per_order": gas_used.checked_div(orders).unwrap_or(0)
+    }))
+}
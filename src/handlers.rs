@@ -1,219 +1,1060 @@
-use actix_web::{web, HttpResponse, HttpRequest, Responder, Error};
+use actix_web::{web, HttpResponse, HttpRequest, Responder};
 use bcrypt::{hash, verify};
-use jsonwebtoken::{encode, Header, EncodingKey, decode, DecodingKey, Validation, TokenData};
+use jsonwebtoken::TokenData;
 use serde_json::json;
 use uuid::Uuid;
-use log::{info, error};
+use log::{info, warn};
 use redis::AsyncCommands;
-use std::env;
+use std::collections::HashMap;
 use tokio::sync::Mutex as AsyncMutex;
 
 use crate::models::*;
-use crate::state::AppState; // Assuming AppState is defined in state.rs
-
-// Import other necessary dependencies
-use web3::contract::{Contract, Options};
-use web3::types::{H160, U256, Value};
+use crate::state::AppState;
+use crate::auth::{bootstrap_admin, Principal};
+use crate::errors::ApiError;
+use crate::users::{all_user_states, user_key, index_user, load_user_state, remove_user};
+use crate::credentials::{clear_failed_logins, lockout_remaining, record_failed_login, validate_password, validate_username};
+use crate::kill_switch::{all_kill_switches, arm_cancel_on_disconnect, blocking_kill_switch, cancel_queued_orders, cancel_resting_orders, engage, get_kill_switch, release};
+use crate::venues::Venues;
+use crate::readiness::Dependencies;
+use crate::rate_limit::{order_throttle_remaining, record_order_entry};
+use crate::api_keys::{create_api_key, delete_api_key, user_api_keys, validate_allowlist};
+use crate::tokens::{consume_refresh_token, decode_access_token, is_revoked, issue_access_token, issue_refresh_token, revoke_access_token, revoke_user_tokens};
+use crate::valuation::{last_prices, value_portfolio};
+use crate::instruments::{all_instruments, delete_instrument, get_instrument, save_instrument, validate_instrument, validate_order};
+use crate::orders::submit_order;
+use crate::receipts::get_tx_record;
+use crate::gas::gas_metrics;
+use crate::reconcile::{latest_report, reconcile};
+use crate::auction::indicative;
+use crate::sessions::{get_calendar, get_halt, halt_symbol, queue_order, queued_orders, refresh_halt, resume_symbol, save_calendar, save_circuit_breaker, trading_phase, validate_calendar};
+use crate::fees::{load_schedule, revenue_by_symbol, save_schedule, FEE_ACCOUNT};
+use crate::ledger::{account_entries, balance, cash_account, ensure_opening_balance, sync_cash, transfer, EXTERNAL_ACCOUNT};
 
 // Function to validate JWT token
-pub fn validate_token(req: &HttpRequest, secret: &str) -> Result<TokenData<Claims>, Error> {
+pub async fn validate_token(req: &HttpRequest, state: &AppState) -> Result<TokenData<Claims>, ApiError> {
     if let Some(auth_header) = req.headers().get("Authorization") {
         if let Ok(auth_str) = auth_header.to_str() {
             let token = auth_str.trim_start_matches("Bearer ").to_string();
-            return decode::<Claims>(&token, &DecodingKey::from_secret(secret.as_ref()), &Validation::default())
-                .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"));
+            let token_data = decode_access_token(&state.config.jwt_keys, &token)
+                .map_err(|_| ApiError::Unauthorized("Invalid token".to_string()))?;
+
+            let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+            if is_revoked(&mut con, &token_data.claims).await? {
+                return Err(ApiError::Unauthorized("Token has been revoked".to_string()));
+            }
+            return Ok(token_data);
         }
     }
-    Err(actix_web::error::ErrorUnauthorized("Missing or invalid Authorization header"))
+    Err(ApiError::Unauthorized("Missing or invalid Authorization header".to_string()))
 }
 
 pub async fn register_user(
     data: web::Data<AsyncMutex<AppState>>,
     user: web::Json<RegisterUser>
-) -> impl Responder {
-    println!("Registering user: {:?}", user);
+) -> Result<HttpResponse, ApiError> {
+    info!("Registering user: {}", user.username);
 
-    let hashed_password = match hash(&user.password, 4) {
-        Ok(h) => h,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to hash password"),
-    };
+    validate_username(&user.username).map_err(ApiError::Validation)?;
+    validate_password(&user.username, &user.password).map_err(ApiError::Validation)?;
+
+    // Hashed before taking the lock for the rest, as bcrypt is slow on purpose
+    let bcrypt_cost = data.lock().await.config.bcrypt_cost;
+    let hashed_password = hash(&user.password, bcrypt_cost)?;
     let user_id = Uuid::new_v4().to_string();
     let portfolio_id = Uuid::new_v4().to_string();
     let username = user.username.clone();
     let state = data.lock().await;
 
-    let mut con = state.redis_client.get_multiplexed_async_connection().await.unwrap();
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
     let user_state = UserState {
         user_id: user_id.clone(),
         username: username.clone(),
-        password: hashed_password.clone(),
+        password: hashed_password,
         orders: vec![],
         transactions: vec![],
         portfolio: Portfolio {
             portfolio_id: portfolio_id.clone(),
             total_money: 0.0,
             assets: HashMap::new(),
+            realized_pnl: 0.0,
         },
+        role: Role::Trader,
     };
 
-    let user_state_json = serde_json::to_string(&user_state).unwrap();
-    let _: () = con.set(&username, user_state_json).await.unwrap();
+    // SETNX so a second registration can never overwrite an existing account
+    let user_state_json = serde_json::to_string(&user_state)?;
+    let created: bool = con.set_nx(user_key(&username), user_state_json).await?;
+    if !created {
+        return Err(ApiError::Conflict("Username already taken".to_string()));
+    }
+    index_user(&mut con, &user_state).await?;
 
-    println!("User successfully registered and saved to Redis with username: {}", username);
+    info!("User successfully registered with username: {}", username);
 
-    HttpResponse::Ok().json(json!({
+    Ok(HttpResponse::Ok().json(json!({
         "user_id": user_id,
         "username": username,
-        "password": hashed_password,
         "portfolio_id": portfolio_id
-    }))
+    })))
 }
 
 pub async fn login(
+    req: HttpRequest,
     data: web::Data<AsyncMutex<AppState>>,
     user: web::Json<LoginUser>
-) -> impl Responder {
-    println!("Logging in user: {:?}", user);
+) -> Result<HttpResponse, ApiError> {
+    info!("Logging in user: {}", user.username);
 
     let state = data.lock().await;
-    let mut con = state.redis_client.get_multiplexed_async_connection().await.unwrap();
-    let user_state_json: Option<String> = con.get(&user.username).await.unwrap();
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let ip = req.peer_addr().map(|addr| addr.ip());
 
-    if let Some(user_state_json) = user_state_json {
-        let user_state: UserState = serde_json::from_str(&user_state_json).unwrap();
-        println!("Stored user data: {:?}", user_state);
-        println!("Provided password: {:?}", user.password);
+    if let Some(retry_after) = lockout_remaining(&mut con, &state.config, &user.username, ip).await? {
+        warn!("Login locked out for user {} from {:?}", user.username, ip);
+        return Err(ApiError::RateLimited {
+            message: "Too many failed login attempts, try again later".to_string(),
+            retry_after,
+        });
+    }
 
-        if verify(&user.password, &user_state.password).unwrap() {
-            let my_claims = Claims { sub: user.username.clone(), exp: 10000000000 };
-            let token = match encode(&Header::default(), &my_claims, &EncodingKey::from_secret(state.secret.as_ref())) {
-                Ok(t) => t,
-                Err(_) => return HttpResponse::InternalServerError().body("Failed to generate token"),
-            };
-            return HttpResponse::Ok().json(json!({
+    if let Some(user_state) = load_user_state(&mut con, &user.username).await? {
+        if verify(&user.password, &user_state.password).unwrap_or(false) {
+            clear_failed_logins(&mut con, &user.username, ip).await?;
+            let token = issue_access_token(&state.config.jwt_keys, &user.username, user_state.role)?;
+            let refresh_token = issue_refresh_token(&mut con, &user.username, state.config.refresh_token_ttl_seconds).await?;
+            return Ok(HttpResponse::Ok().json(json!({
                 "token": token,
-                "user_id": user_state.user_id
-            }));
+                "refresh_token": refresh_token,
+                "expires_in": state.config.jwt_keys.access_token_ttl,
+                "user_id": user_state.user_id,
+                "role": user_state.role
+            })));
         } else {
-            println!("Password verification failed");
+            info!("Password verification failed for user: {}", user.username);
         }
     } else {
-        println!("User not found in Redis");
+        info!("Login for unknown user: {}", user.username);
+    }
+    record_failed_login(&mut con, &state.config, &user.username, ip).await?;
+    Err(ApiError::Unauthorized("Invalid username or password".to_string()))
+}
+
+// Requires the current password even though the caller holds a token, so a stolen
+// token alone cannot take over the account
+pub async fn change_password(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<PasswordChangeRequest>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let mut user_state = load_user_state(&mut con, &principal.username).await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    if !verify(&body.current_password, &user_state.password).unwrap_or(false) {
+        return Err(ApiError::Unauthorized("Current password is incorrect".to_string()));
     }
-    HttpResponse::Unauthorized().body("Invalid username or password")
+    validate_password(&user_state.username, &body.new_password).map_err(ApiError::Validation)?;
+
+    user_state.password = hash(&body.new_password, state.config.bcrypt_cost)?;
+    let user_state_json = serde_json::to_string(&user_state)?;
+    let _: () = con.set(user_key(&user_state.username), user_state_json).await?;
+    revoke_user_tokens(&mut con, &state.config.jwt_keys, &user_state.username).await?;
+
+    info!("Password changed for user {}, existing tokens revoked", user_state.username);
+    Ok(HttpResponse::Ok().json(json!({
+        "message": "Password changed"
+    })))
+}
+
+// Exchanges a refresh token for a new access token and a new refresh token. The old
+// refresh token is consumed, so each one can be used exactly once.
+pub async fn refresh(
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<RefreshRequest>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+
+    let expired = || ApiError::Unauthorized("Invalid or expired refresh token".to_string());
+    let username = consume_refresh_token(&mut con, &body.refresh_token).await?.ok_or_else(expired)?;
+    let user_state = load_user_state(&mut con, &username).await?.ok_or_else(expired)?;
+
+    // Re-read the role so role changes apply on refresh, not only on login
+    let token = issue_access_token(&state.config.jwt_keys, &username, user_state.role)?;
+    let refresh_token = issue_refresh_token(&mut con, &username, state.config.refresh_token_ttl_seconds).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "token": token,
+        "refresh_token": refresh_token,
+        "expires_in": state.config.jwt_keys.access_token_ttl,
+        "user_id": user_state.user_id,
+        "role": user_state.role
+    })))
 }
 
-pub async fn signout(req: HttpRequest) -> impl Responder {
-    // Invalidate the token or clear the client-side storage of the token
-    // Since JWT is stateless, just a response indicating the sign-out is enough
-    println!("User signed out");
-    HttpResponse::Ok().json(json!({
+// Revokes the presented access token and, if one is sent, the refresh token
+pub async fn signout(
+    req: HttpRequest,
+    data: web::Data<AsyncMutex<AppState>>,
+    body: Option<web::Json<RefreshRequest>>
+) -> Result<HttpResponse, ApiError> {
+    let token_data = validate_token(&req, &*data.lock().await).await?;
+
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    revoke_access_token(&mut con, &token_data.claims).await?;
+    if let Some(body) = body {
+        consume_refresh_token(&mut con, &body.refresh_token).await?;
+    }
+
+    info!("User signed out: {}", token_data.claims.sub);
+    Ok(HttpResponse::Ok().json(json!({
         "message": "Successfully signed out"
-    }))
+    })))
 }
 
-// Similarly, move other handler functions here...
+// Shared body of the buy and sell routes: venue health, instrument checks, kill
+// switches and the order-to-trade throttle, then queued or sent by session phase
+async fn place_order(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    order: web::Json<OrderRequest>,
+    side: OrderSide,
+) -> Result<HttpResponse, ApiError> {
+    let username = principal.username;
+    let side_name = side.as_str();
+
+    info!("Placing {} order for user: {}, order: {:?}", side_name, username, order);
+
+    // A copy of the handles, so the AppState lock is not held across the order's RPCs
+    let state = data.lock().await.clone();
+    if !state.venues.for_symbol(&order.symbol).health.accepting_orders() {
+        info!("Rejected {} order for user {}: settlement unavailable", side_name, username);
+        return Err(ApiError::ServiceUnavailable("Order entry is suspended while settlement is unavailable".to_string()));
+    }
+
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    refresh_halt(&mut con, &order.symbol).await?;
+    let instrument = get_instrument(&mut con, &order.symbol).await?;
+    if let Err(reason) = validate_order(instrument.as_ref(), &order) {
+        info!("Rejected {} order for user {}: {}", side_name, username, reason);
+        return Err(ApiError::OrderRejected(reason));
+    }
+    if let Some(kill_switch) = blocking_kill_switch(&mut con, &username, &order.symbol).await? {
+        info!("Rejected {} order for user {}: kill switch engaged", side_name, username);
+        return Err(ApiError::KillSwitchEngaged(kill_switch.reason));
+    }
+    if let Some(retry_after) = order_throttle_remaining(&mut con, &username).await? {
+        info!("Rejected {} order for user {}: throttled for order-to-trade ratio", side_name, username);
+        return Err(ApiError::RateLimited {
+            message: "Order entry throttled: too many orders per trade".to_string(),
+            retry_after,
+        });
+    }
+    record_order_entry(&mut con, &state.config, &username).await?;
+
+    let order_id = Uuid::new_v4().to_string();
+
+    match trading_phase(&mut con, &order.symbol).await? {
+        SessionPhase::Continuous => {},
+        SessionPhase::Closed => {
+            info!("Rejected {} order for user {}: market closed for {}", side_name, username, order.symbol);
+            return Err(ApiError::OrderRejected(format!("Market is closed for {}", order.symbol)));
+        },
+        phase => {
+            queue_order(&mut con, &QueuedOrder {
+                order_id: order_id.clone(),
+                username: username.clone(),
+                side,
+                order: order.clone(),
+                queued_at: chrono::Utc::now(),
+            }).await?;
+            info!("Queued {} order {} for user {} during {:?}", side_name, order_id, username, phase);
+            return Ok(HttpResponse::Accepted().json(json!({
+                "order_id": order_id,
+                "status": "queued",
+                "session": phase
+            })));
+        },
+    }
+
+    let new_order = submit_order(&data, &state, &mut con, &username, &order_id, &order, side).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "order_id": new_order.order_id,
+        "user_id": new_order.user_id,
+        "symbol": new_order.symbol,
+        "quantity": new_order.quantity,
+        "price": new_order.price,
+        "order_type": new_order.order_type,
+        "status": new_order.status
+    })))
+}
 
 pub async fn place_buy_order(
-    req: HttpRequest,
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    order: web::Json<OrderRequest>
+) -> Result<HttpResponse, ApiError> {
+    place_order(principal, data, order, OrderSide::Buy).await
+}
+
+pub async fn place_sell_order(
+    principal: Principal,
     data: web::Data<AsyncMutex<AppState>>,
     order: web::Json<OrderRequest>
-) -> Result<HttpResponse, Error> {
-    let token_data = validate_token(&req, &data.lock().await.secret)?;
-    let username = token_data.claims.sub;
+) -> Result<HttpResponse, ApiError> {
+    place_order(principal, data, order, OrderSide::Sell).await
+}
+
+pub async fn get_user_portfolio(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let user_state = principal.target_user(&mut con, &path.into_inner()).await?;
 
-    println!("Placing buy order for user: {}, order: {:?}", username, order);
+    let prices = last_prices(&mut con).await?;
+    Ok(HttpResponse::Ok().json(value_portfolio(&user_state.portfolio, &prices)))
+}
 
+pub async fn get_portfolio_by_id(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
     let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let user_state = principal.target_portfolio(&mut con, &path.into_inner()).await?;
 
-    let contract_abi: Value = serde_json::from_slice(include_bytes!("../build/contracts/OrderBook.json")).unwrap();
-    let abi = contract_abi.get("abi").unwrap();
+    let prices = last_prices(&mut con).await?;
+    Ok(HttpResponse::Ok().json(value_portfolio(&user_state.portfolio, &prices)))
+}
 
-    let contract = Contract::from_json(state.web3.eth(), state.contract_address, abi.to_string().as_bytes()).unwrap();
+pub async fn get_user_orders(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let user_state = principal.target_user(&mut con, &path.into_inner()).await?;
 
-    let order_id = Uuid::new_v4().to_string();
-    let user_id = username.clone(); // Assuming username is unique and used as user_id
+    Ok(HttpResponse::Ok().json(user_state.orders))
+}
+
+pub async fn get_order_by_id(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let order_json: Option<String> = con.get(path.into_inner()).await?;
+    let order: Order = order_json
+        .and_then(|order_json| serde_json::from_str(&order_json).ok())
+        .ok_or_else(|| ApiError::NotFound("Order not found".to_string()))?;
+
+    principal.authorize_order(&mut con, &order).await?;
+    Ok(HttpResponse::Ok().json(order))
+}
+
+pub async fn get_user_transactions(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let user_state = principal.target_user(&mut con, &principal.username).await?;
+
+    Ok(HttpResponse::Ok().json(user_state.transactions))
+}
+
+// Receipt status of one of the caller's order transactions
+pub async fn get_transaction(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let mut record = get_tx_record(&mut con, &path.into_inner()).await?
+        .ok_or_else(|| ApiError::NotFound("Transaction not found".to_string()))?;
 
-    info!("Placing buy order: {:?}", order);
+    if !principal.is_admin() {
+        // Users only see their own orders of a batch
+        record.batch.retain(|queued| queued.username == principal.username);
+        if record.username != principal.username && record.batch.is_empty() {
+            return Err(ApiError::Forbidden("Not allowed to access another user's data".to_string()));
+        }
+    }
+    Ok(HttpResponse::Ok().json(record))
+}
 
-    // Log the parameters
-    println!("Symbol: {}", order.symbol);
-    println!("Quantity: {}", order.quantity);
-    println!("Price: {}", order.price);
-    println!("User ID: {}", user_id);
-    println!("Order ID: {}", order_id);
-    println!("Order Type: {:?}", order.order_type);
+// /me aliases of the user-scoped routes, resolved from the caller's token
+pub async fn get_my_portfolio(principal: Principal, data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let username = principal.username.clone();
+    get_user_portfolio(principal, data, web::Path::from(username)).await
+}
+
+pub async fn get_my_orders(principal: Principal, data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let username = principal.username.clone();
+    get_user_orders(principal, data, web::Path::from(username)).await
+}
+
+// Shared body of deposit and withdraw: journals the movement against the external
+// account and mirrors the new ledger balance onto the user's portfolio.
+async fn move_cash(
+    data: web::Data<AsyncMutex<AppState>>,
+    username: String,
+    amount: f64,
+    kind: EntryKind,
+) -> Result<HttpResponse, ApiError> {
+    if !amount.is_finite() || amount <= 0.0 {
+        return Err(ApiError::Validation("Amount must be a positive number".to_string()));
+    }
 
-    let options = Options {
-        gas: Some(3_000_000.into()),
-        ..Default::default()
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let user_state_json: Option<String> = con.get(user_key(&username)).await?;
+    let mut user_state: UserState = match user_state_json {
+        Some(user_state_json) => serde_json::from_str(&user_state_json)?,
+        None => return Err(ApiError::NotFound("User not found".to_string())),
     };
 
-    let order_type = match order.order_type {
-        OrderType::Limit => U256::from(0),
-        OrderType::Market => U256::from(1),
-        OrderType::Stop => U256::from(2),
+    ensure_opening_balance(&mut con, &user_state).await?;
+    let account = cash_account(&username);
+    let reference = Uuid::new_v4().to_string();
+
+    let entry = match kind {
+        EntryKind::Withdrawal => {
+            if balance(&mut con, &account).await? < amount {
+                return Err(ApiError::InsufficientFunds);
+            }
+            transfer(&mut con, kind, &reference, &account, EXTERNAL_ACCOUNT, amount).await?
+        },
+        _ => transfer(&mut con, kind, &reference, EXTERNAL_ACCOUNT, &account, amount).await?,
     };
 
-    let result = contract.call(
-        "placeBuyOrder",
-        (
-            order.symbol.clone(),
-            U256::from(order.quantity),
-            U256::from(order.price),
-            user_id.clone(),
-            order_id.clone(),
-            order_type,
-        ),
-        state.account,
-        options,
-    ).await;
-
-    match result {
-        Ok(tx_id) => {
-            info!("Buy order placed successfully: tx_id = {:?}", tx_id);
-            let mut con = state.redis_client.get_multiplexed_async_connection().await.unwrap();
-            let user_state_json: String = con.get(&username).await.unwrap();
-            let mut user_state: UserState = serde_json::from_str(&user_state_json).unwrap();
-
-            let new_order = Order {
-                order_id: order_id.clone(),
-                user_id: user_state.user_id.clone(),
-                symbol: order.symbol.clone(),
-                quantity: order.quantity,
-                price: order.price,
-                order_type: order.order_type.as_str().to_string(),
-            };
-
-            // Update user's portfolio
-            user_state.orders.push(new_order.clone());
-            user_state.transactions.push(Transaction {
-                order_id: order_id.clone(),
-                transaction_id: format!("{:?}", tx_id),
-            });
-
-            // Store the order in Redis
-            let order_json = serde_json::to_string(&new_order).unwrap();
-            let _: () = con.set(order_id.clone(), order_json).await.unwrap();
-
-            // Update user state in Redis
-            let user_state_json = serde_json::to_string(&user_state).unwrap();
-            let _: () = con.set(&username, user_state_json).await.unwrap();
-
-            Ok(HttpResponse::Ok().json(json!({
-                "order_id": new_order.order_id,
-                "user_id": new_order.user_id,
-                "symbol": new_order.symbol,
-                "quantity": new_order.quantity,
-                "price": new_order.price,
-                "order_type": new_order.order_type
-            })))
+    sync_cash(&mut con, &mut user_state).await?;
+    let user_state_json = serde_json::to_string(&user_state)?;
+    let _: () = con.set(user_key(&username), user_state_json).await?;
+
+    info!("Posted {:?} of {} for user {}", entry.kind, amount, username);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "entry": entry,
+        "total_money": user_state.portfolio.total_money
+    })))
+}
+
+// Admin only: a user cannot credit their own account out of thin air
+pub async fn deposit(
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<DepositRequest>
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    move_cash(data, body.username, body.amount, EntryKind::Deposit).await
+}
+
+pub async fn withdraw(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<CashRequest>
+) -> Result<HttpResponse, ApiError> {
+    move_cash(data, principal.username, body.amount, EntryKind::Withdrawal).await
+}
+
+pub async fn get_ledger(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>
+) -> Result<HttpResponse, ApiError> {
+    let username = principal.username;
+
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let account = cash_account(&username);
+    let entries = account_entries(&mut con, &account).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "account": account,
+        "balance": balance(&mut con, &account).await?,
+        "entries": entries
+    })))
+}
+
+pub async fn get_fee_schedule(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    Ok(HttpResponse::Ok().json(load_schedule(&mut con).await?))
+}
+
+pub async fn set_fee_schedule(
+    data: web::Data<AsyncMutex<AppState>>,
+    schedule: web::Json<FeeSchedule>
+) -> Result<HttpResponse, ApiError> {
+    let rates = std::iter::once(&schedule.base_rates)
+        .chain(schedule.tiers.iter().map(|tier| &tier.rates))
+        .chain(schedule.symbol_rates.values());
+    for rates in rates {
+        if rates.maker < 0.0 || rates.taker < 0.0 || rates.maker >= 1.0 || rates.taker >= 1.0 {
+            return Err(ApiError::Validation("Fee rates must be fractions between 0 and 1".to_string()));
+        }
+    }
+    if schedule.minimum_fee < 0.0 {
+        return Err(ApiError::Validation("Minimum fee cannot be negative".to_string()));
+    }
+
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    save_schedule(&mut con, &schedule).await?;
+
+    info!("Fee schedule updated: {:?}", schedule);
+    Ok(HttpResponse::Ok().json(schedule.into_inner()))
+}
+
+pub async fn get_fee_revenue(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "total": balance(&mut con, FEE_ACCOUNT).await?,
+        "by_symbol": revenue_by_symbol(&mut con).await?
+    })))
+}
+
+pub async fn get_instruments(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    Ok(HttpResponse::Ok().json(all_instruments(&mut con).await?))
+}
+
+pub async fn get_instrument_by_symbol(
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let instrument = get_instrument(&mut con, &path.into_inner()).await?
+        .ok_or_else(|| ApiError::NotFound("Instrument not found".to_string()))?;
+    Ok(HttpResponse::Ok().json(instrument))
+}
+
+// Creates the instrument or replaces an existing definition for the same symbol
+pub async fn upsert_instrument(
+    data: web::Data<AsyncMutex<AppState>>,
+    instrument: web::Json<Instrument>
+) -> Result<HttpResponse, ApiError> {
+    validate_instrument(&instrument).map_err(ApiError::Validation)?;
+
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    save_instrument(&mut con, &instrument).await?;
+
+    info!("Instrument saved: {:?}", instrument);
+    Ok(HttpResponse::Ok().json(instrument.into_inner()))
+}
+
+pub async fn remove_instrument(
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let symbol = path.into_inner();
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    if !delete_instrument(&mut con, &symbol).await? {
+        return Err(ApiError::NotFound("Instrument not found".to_string()));
+    }
+
+    info!("Instrument removed: {}", symbol);
+    Ok(HttpResponse::Ok().json(json!({ "message": format!("Instrument {} removed", symbol) })))
+}
+
+pub async fn get_session(
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let symbol = path.into_inner();
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    refresh_halt(&mut con, &symbol).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "symbol": symbol,
+        "phase": trading_phase(&mut con, &symbol).await?,
+        "calendar": get_calendar(&mut con, &symbol).await?,
+        "halt": get_halt(&mut con, &symbol).await?,
+        "queued_orders": queued_orders(&mut con, &symbol).await?.len()
+    })))
+}
+
+pub async fn upsert_session_calendar(
+    data: web::Data<AsyncMutex<AppState>>,
+    calendar: web::Json<SessionCalendar>
+) -> Result<HttpResponse, ApiError> {
+    validate_calendar(&calendar).map_err(ApiError::Validation)?;
+
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    save_calendar(&mut con, &calendar).await?;
+
+    info!("Session calendar saved: {:?}", calendar);
+    Ok(HttpResponse::Ok().json(calendar.into_inner()))
+}
+
+pub async fn upsert_circuit_breaker(
+    data: web::Data<AsyncMutex<AppState>>,
+    breaker: web::Json<CircuitBreaker>
+) -> Result<HttpResponse, ApiError> {
+    if breaker.max_move_pct <= 0.0 || breaker.halt_seconds <= 0 {
+        return Err(ApiError::Validation("Circuit breaker threshold and halt duration must be positive".to_string()));
+    }
+
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    save_circuit_breaker(&mut con, &breaker).await?;
+
+    info!("Circuit breaker saved: {:?}", breaker);
+    Ok(HttpResponse::Ok().json(breaker.into_inner()))
+}
+
+pub async fn halt_trading(
+    data: web::Data<AsyncMutex<AppState>>,
+    halt: web::Json<HaltRequest>
+) -> Result<HttpResponse, ApiError> {
+    let resume_at = halt.duration_seconds.map(|seconds| chrono::Utc::now() + chrono::Duration::seconds(seconds));
+
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    if !halt_symbol(&mut con, &halt.symbol, &halt.reason, resume_at).await? {
+        return Err(ApiError::NotFound("Instrument not found".to_string()));
+    }
+    Ok(HttpResponse::Ok().json(get_halt(&mut con, &halt.symbol).await?))
+}
+
+pub async fn resume_trading(
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let symbol = path.into_inner();
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    if !resume_symbol(&mut con, &symbol).await? {
+        return Err(ApiError::Validation("Symbol is not halted".to_string()));
+    }
+    Ok(HttpResponse::Ok().json(json!({ "message": format!("Trading resumed for {}", symbol) })))
+}
+
+// Indicative uncrossing price and imbalance of the symbol's current call book
+pub async fn get_auction(
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let symbol = path.into_inner();
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "phase": trading_phase(&mut con, &symbol).await?,
+        "indicative": indicative(&mut con, &symbol).await?
+    })))
+}
+
+// Roles are carried in the token, so a change applies from the user's next login
+pub async fn set_user_role(
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<RoleRequest>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let user_state_json: Option<String> = con.get(user_key(&body.username)).await?;
+    let mut user_state: UserState = match user_state_json {
+        Some(user_state_json) => serde_json::from_str(&user_state_json)?,
+        None => return Err(ApiError::NotFound("User not found".to_string())),
+    };
+
+    user_state.role = body.role;
+    let user_state_json = serde_json::to_string(&user_state)?;
+    let _: () = con.set(user_key(&body.username), user_state_json).await?;
+
+    info!("Role of {} set to {:?}", body.username, body.role);
+    Ok(HttpResponse::Ok().json(json!({
+        "username": body.username,
+        "role": body.role
+    })))
+}
+
+// API keys are managed with a login token; the secret is only ever returned here
+pub async fn create_user_api_key(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<ApiKeyRequest>
+) -> Result<HttpResponse, ApiError> {
+    if body.scopes.is_empty() {
+        return Err(ApiError::Validation("At least one scope is required".to_string()));
+    }
+    validate_allowlist(&body.ip_allowlist).map_err(ApiError::Validation)?;
+
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let api_key = create_api_key(&mut con, &principal.username, &body.label, body.scopes.clone(), body.ip_allowlist.clone()).await?;
+
+    info!("Created API key {} for user {} with scopes {:?}", api_key.key_id, principal.username, api_key.scopes);
+    Ok(HttpResponse::Ok().json(api_key))
+}
+
+pub async fn get_user_api_keys(principal: Principal, data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let api_keys: Vec<ApiKeySummary> = user_api_keys(&mut con, &principal.username).await?
+        .into_iter()
+        .map(ApiKeySummary::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(api_keys))
+}
+
+pub async fn revoke_user_api_key(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let key_id = path.into_inner();
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    if !delete_api_key(&mut con, &principal.username, &key_id).await? {
+        return Err(ApiError::NotFound("API key not found".to_string()));
+    }
+
+    info!("Revoked API key {} of user {}", key_id, principal.username);
+    Ok(HttpResponse::Ok().json(json!({ "message": "API key revoked" })))
+}
+
+// Engages the kill switch, cancels the queued orders it covers and sends the
+// cancellations of those resting on the OrderBook contracts
+async fn engage_kill_switch_for(
+    data: web::Data<AsyncMutex<AppState>>,
+    kill_switch: KillSwitch,
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    engage(&mut con, &kill_switch).await?;
+    let cancelled = cancel_queued_orders(&mut con, kill_switch.username.as_deref(), kill_switch.symbol.as_deref()).await?;
+    let cancellations = cancel_resting_orders(&state, &mut con, kill_switch.username.as_deref(), kill_switch.symbol.as_deref()).await?;
+
+    warn!(
+        "Kill switch engaged by {} for user {:?}, symbol {:?}: {} ({} queued orders cancelled, {} cancellations of resting orders sent)",
+        kill_switch.engaged_by, kill_switch.username, kill_switch.symbol, kill_switch.reason, cancelled.len(), cancellations.len()
+    );
+    Ok(HttpResponse::Ok().json(json!({
+        "kill_switch": kill_switch,
+        "cancelled_orders": cancelled.iter().map(|queued| &queued.order_id).collect::<Vec<_>>(),
+        "cancellation_transactions": cancellations,
+        "message": "Queued orders in scope were cancelled. Orders resting on the OrderBook contract are pulled off its book once the cancellation transactions are mined."
+    })))
+}
+
+pub async fn engage_kill_switch(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<KillSwitchRequest>
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    engage_kill_switch_for(data, KillSwitch {
+        username: body.username,
+        symbol: body.symbol,
+        reason: body.reason,
+        engaged_by: principal.username,
+        engaged_at: chrono::Utc::now(),
+    }).await
+}
+
+pub async fn release_kill_switch(
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<KillSwitchRequest>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    if !release(&mut con, body.username.as_deref(), body.symbol.as_deref()).await? {
+        return Err(ApiError::NotFound("No kill switch engaged with that scope".to_string()));
+    }
+
+    info!("Kill switch released for user {:?}, symbol {:?}", body.username, body.symbol);
+    Ok(HttpResponse::Ok().json(json!({ "message": "Kill switch released" })))
+}
+
+pub async fn get_kill_switches(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    Ok(HttpResponse::Ok().json(all_kill_switches(&mut con).await?))
+}
+
+// Self-service kill switch, always scoped to the caller
+pub async fn engage_my_kill_switch(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<KillSwitchRequest>
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    {
+        // Re-engaging must not take ownership of a switch risk engaged
+        let state = data.lock().await;
+        let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+        if let Some(existing) = get_kill_switch(&mut con, Some(&principal.username), body.symbol.as_deref()).await? {
+            if existing.engaged_by != principal.username {
+                return Err(ApiError::Conflict("Kill switch already engaged by risk".to_string()));
+            }
+        }
+    }
+    engage_kill_switch_for(data, KillSwitch {
+        username: Some(principal.username.clone()),
+        symbol: body.symbol,
+        reason: body.reason,
+        engaged_by: principal.username,
+        engaged_at: chrono::Utc::now(),
+    }).await
+}
+
+// Users may only lift kill switches they engaged themselves
+pub async fn release_my_kill_switch(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<KillSwitchRequest>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    match get_kill_switch(&mut con, Some(&principal.username), body.symbol.as_deref()).await? {
+        Some(kill_switch) if kill_switch.engaged_by == principal.username => {
+            release(&mut con, Some(&principal.username), body.symbol.as_deref()).await?;
+            info!("Kill switch released by {} for symbol {:?}", principal.username, body.symbol);
+            Ok(HttpResponse::Ok().json(json!({ "message": "Kill switch released" })))
         },
-        Err(e) => {
-            error!("Error placing buy order: {:?}", e);
-            Ok(HttpResponse::InternalServerError().body("Error placing buy order"))
+        Some(_) => Err(ApiError::Forbidden("Kill switch was engaged by risk and can only be released by risk".to_string())),
+        None => Err(ApiError::NotFound("No kill switch engaged with that scope".to_string())),
+    }
+}
+
+// Heartbeat for cancel-on-disconnect: queued orders are cancelled if the next call
+// does not arrive within timeout_seconds
+pub async fn set_cancel_on_disconnect(
+    principal: Principal,
+    data: web::Data<AsyncMutex<AppState>>,
+    body: web::Json<CancelOnDisconnectRequest>
+) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    arm_cancel_on_disconnect(&mut con, &principal.username, body.timeout_seconds).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "armed": body.timeout_seconds > 0,
+        "timeout_seconds": body.timeout_seconds.max(0)
+    })))
+}
+
+// The account a user's orders are sent from, which must hold gas when accounts are derived per user
+pub async fn get_signing_account(
+    data: web::Data<AsyncMutex<AppState>>,
+    path: web::Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let username = path.into_inner();
+    let state = data.lock().await;
+    let account = match &state.signer {
+        Some(signer) => signer.account_for(&username),
+        None => state.config.account,
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "username": username,
+        "account": account,
+        "local_signer": state.signer.is_some()
+    })))
+}
+
+// Gas spent by order transactions that reached a final status
+pub async fn get_gas_metrics(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    Ok(HttpResponse::Ok().json(gas_metrics(&mut con).await?))
+}
+
+// Checks the contract's book against Redis now; {"repair": true} also fixes what it finds
+pub async fn run_reconciliation(
+    data: web::Data<AsyncMutex<AppState>>,
+    body: Option<web::Json<ReconcileRequest>>
+) -> Result<HttpResponse, ApiError> {
+    let repair = body.is_some_and(|body| body.repair);
+    let report = reconcile(&data, repair).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+pub async fn get_reconciliation(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let report = latest_report(&mut con).await?
+        .ok_or_else(|| ApiError::NotFound("No reconciliation has run yet".to_string()))?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+// Every user without their password hash
+pub async fn get_all_users(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let users: Vec<_> = all_user_states(&mut con).await?
+        .into_iter()
+        .map(|user_state| json!({
+            "user_id": user_state.user_id,
+            "username": user_state.username,
+            "portfolio_id": user_state.portfolio.portfolio_id,
+            "role": user_state.role
+        }))
+        .collect();
+    Ok(HttpResponse::Ok().json(users))
+}
+
+// Every user's orders, keyed by username
+pub async fn get_order_book(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let orders: HashMap<String, Vec<Order>> = all_user_states(&mut con).await?
+        .into_iter()
+        .map(|user_state| (user_state.username, user_state.orders))
+        .collect();
+    Ok(HttpResponse::Ok().json(orders))
+}
+
+// Every user's portfolio marked to market, keyed by username
+pub async fn get_all_portfolios(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let prices = last_prices(&mut con).await?;
+    let portfolios: HashMap<String, PortfolioValuation> = all_user_states(&mut con).await?
+        .into_iter()
+        .map(|user_state| (user_state.username, value_portfolio(&user_state.portfolio, &prices)))
+        .collect();
+    Ok(HttpResponse::Ok().json(portfolios))
+}
+
+// Seeds a trader with cash and shares for testing. The cash is journaled as an
+// opening balance on the user's first ledger movement.
+pub async fn initialize_user(
+    data: web::Data<AsyncMutex<AppState>>,
+    user: web::Json<InitializeUserRequest>
+) -> Result<HttpResponse, ApiError> {
+    validate_username(&user.username).map_err(ApiError::Validation)?;
+    validate_password(&user.username, &user.password).map_err(ApiError::Validation)?;
+    if !user.total_money.is_finite() || user.total_money < 0.0 {
+        return Err(ApiError::Validation("total_money must be a non-negative number".to_string()));
+    }
+
+    let user = user.into_inner();
+    let bcrypt_cost = data.lock().await.config.bcrypt_cost;
+    let user_state = UserState {
+        user_id: Uuid::new_v4().to_string(),
+        username: user.username.clone(),
+        password: hash(&user.password, bcrypt_cost)?,
+        orders: vec![],
+        transactions: vec![],
+        portfolio: Portfolio {
+            portfolio_id: Uuid::new_v4().to_string(),
+            total_money: user.total_money,
+            assets: user.assets,
+            realized_pnl: 0.0,
         },
+        role: Role::Trader,
+    };
+
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let created: bool = con.set_nx(user_key(&user_state.username), serde_json::to_string(&user_state)?).await?;
+    if !created {
+        return Err(ApiError::Conflict("Username already taken".to_string()));
+    }
+    index_user(&mut con, &user_state).await?;
+
+    info!("Initialized user {} with {} in cash and {} assets", user_state.username, user.total_money, user_state.portfolio.assets.len());
+    Ok(HttpResponse::Ok().json(json!({
+        "user_id": user_state.user_id,
+        "username": user_state.username,
+        "portfolio_id": user_state.portfolio.portfolio_id
+    })))
+}
+
+// Wipes the database, then recreates the bootstrap admin so the caller is not locked out
+pub async fn delete_all_data(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let _: () = redis::cmd("FLUSHDB").query_async(&mut con).await?;
+    bootstrap_admin(&state.redis_client, state.config.bcrypt_cost).await?;
+
+    warn!("Deleted all data");
+    Ok(HttpResponse::Ok().json(json!({ "message": "All data deleted" })))
+}
+
+// Deletes every user except admins
+pub async fn delete_all_users(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let mut deleted = 0;
+    for user_state in all_user_states(&mut con).await? {
+        if user_state.role != Role::Admin {
+            remove_user(&mut con, &user_state).await?;
+            deleted += 1;
+        }
+    }
+
+    warn!("Deleted {} users", deleted);
+    Ok(HttpResponse::Ok().json(json!({ "message": "Users deleted", "deleted": deleted })))
+}
+
+// Deletes every saved order and clears each user's order list
+pub async fn delete_all_orders(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let mut deleted = 0;
+    for mut user_state in all_user_states(&mut con).await? {
+        for order in user_state.orders.drain(..) {
+            let _: () = con.del(&order.order_id).await?;
+            deleted += 1;
+        }
+        let _: () = con.set(user_key(&user_state.username), serde_json::to_string(&user_state)?).await?;
+    }
+
+    warn!("Deleted {} orders", deleted);
+    Ok(HttpResponse::Ok().json(json!({ "message": "Orders deleted", "deleted": deleted })))
+}
+
+// Empties every portfolio's holdings. Cash mirrors the ledger, so it is left as is.
+pub async fn delete_all_portfolios(data: web::Data<AsyncMutex<AppState>>) -> Result<HttpResponse, ApiError> {
+    let state = data.lock().await;
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+    let mut cleared = 0;
+    for mut user_state in all_user_states(&mut con).await? {
+        user_state.portfolio.assets.clear();
+        user_state.portfolio.realized_pnl = 0.0;
+        let _: () = con.set(user_key(&user_state.username), serde_json::to_string(&user_state)?).await?;
+        cleared += 1;
     }
+
+    warn!("Cleared {} portfolios", cleared);
+    Ok(HttpResponse::Ok().json(json!({ "message": "Portfolios cleared", "cleared": cleared })))
 }
 
-// Similarly, implement other handler functions like place_sell_order, get_user_orders, etc.
+// Read from its own app data so it answers even while the AppState lock is held
+pub async fn get_health(venues: web::Data<Venues>) -> impl Responder {
+    HttpResponse::Ok().json(venues.status())
+}
+
+// Liveness probe: answers as long as the process can serve HTTP
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+// Readiness probe: 503 with the failing checks unless every dependency is usable
+pub async fn readyz(dependencies: web::Data<Dependencies>) -> impl Responder {
+    let (ready, report) = dependencies.readiness().await;
+    if ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
@@ -0,0 +1,176 @@
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+
+use crate::errors::ApiError;
+use crate::models::{Instrument, OrderRequest, OrderType, TradingStatus};
+
+// Redis hash of symbol -> Instrument JSON
+pub const INSTRUMENTS_KEY: &str = "instruments";
+
+pub async fn get_instrument(con: &mut MultiplexedConnection, symbol: &str) -> Result<Option<Instrument>, ApiError> {
+    let instrument_json: Option<String> = con.hget(INSTRUMENTS_KEY, symbol).await?;
+    Ok(instrument_json.map(|instrument_json| serde_json::from_str(&instrument_json)).transpose()?)
+}
+
+pub async fn all_instruments(con: &mut MultiplexedConnection) -> Result<Vec<Instrument>, ApiError> {
+    let instruments: Vec<String> = con.hvals(INSTRUMENTS_KEY).await?;
+    Ok(instruments.iter().map(|instrument| serde_json::from_str(instrument)).collect::<Result<_, _>>()?)
+}
+
+pub async fn save_instrument(con: &mut MultiplexedConnection, instrument: &Instrument) -> Result<(), ApiError> {
+    let _: () = con.hset(INSTRUMENTS_KEY, &instrument.symbol, serde_json::to_string(instrument)?).await?;
+    Ok(())
+}
+
+// Returns whether the symbol existed
+pub async fn delete_instrument(con: &mut MultiplexedConnection, symbol: &str) -> Result<bool, ApiError> {
+    let removed: u32 = con.hdel(INSTRUMENTS_KEY, symbol).await?;
+    Ok(removed > 0)
+}
+
+// Sanity checks on an instrument definition before it is stored
+pub fn validate_instrument(instrument: &Instrument) -> Result<(), String> {
+    if instrument.symbol.is_empty() {
+        return Err("Symbol cannot be empty".to_string());
+    }
+    if instrument.tick_size == 0 || instrument.lot_size == 0 {
+        return Err("Tick size and lot size must be positive".to_string());
+    }
+    if instrument.min_quantity > instrument.max_quantity {
+        return Err("Minimum quantity exceeds maximum quantity".to_string());
+    }
+    if instrument.min_price > instrument.max_price {
+        return Err("Lower price band exceeds upper price band".to_string());
+    }
+    Ok(())
+}
+
+// Checks an incoming order against the instrument's trading rules. Market orders
+// carry no price, so tick size and price bands only apply to priced orders.
+pub fn validate_order(instrument: Option<&Instrument>, order: &OrderRequest) -> Result<(), String> {
+    let instrument = match instrument {
+        Some(instrument) => instrument,
+        None => return Err(format!("Unknown symbol {}", order.symbol)),
+    };
+
+    match instrument.status {
+        TradingStatus::Active => {},
+        TradingStatus::Halted => return Err(format!("Trading in {} is halted", instrument.symbol)),
+        TradingStatus::Delisted => return Err(format!("{} is delisted", instrument.symbol)),
+    }
+
+    if order.quantity < instrument.min_quantity || order.quantity > instrument.max_quantity {
+        return Err(format!(
+            "Quantity must be between {} and {}",
+            instrument.min_quantity, instrument.max_quantity
+        ));
+    }
+    if !order.quantity.is_multiple_of(instrument.lot_size) {
+        return Err(format!("Quantity must be a multiple of the lot size {}", instrument.lot_size));
+    }
+
+    if !matches!(order.order_type, OrderType::Market) {
+        if !order.price.is_multiple_of(instrument.tick_size) {
+            return Err(format!("Price must be a multiple of the tick size {}", instrument.tick_size));
+        }
+        if order.price < instrument.min_price || order.price > instrument.max_price {
+            return Err(format!(
+                "Price must be within the band {} to {}",
+                instrument.min_price, instrument.max_price
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ticks of 5, lots of 10, 10 to 1000 shares and a 100 to 500 price band
+    fn eth() -> Instrument {
+        Instrument {
+            symbol: "ETH".to_string(),
+            description: "Ether".to_string(),
+            tick_size: 5,
+            lot_size: 10,
+            min_quantity: 10,
+            max_quantity: 1000,
+            min_price: 100,
+            max_price: 500,
+            status: TradingStatus::Active,
+        }
+    }
+
+    fn limit(quantity: u32, price: u32) -> OrderRequest {
+        OrderRequest { symbol: "ETH".to_string(), quantity, price, order_type: OrderType::Limit }
+    }
+
+    #[test]
+    fn accepts_an_order_on_the_grid() {
+        assert_eq!(validate_order(Some(&eth()), &limit(10, 100)), Ok(()));
+        assert_eq!(validate_order(Some(&eth()), &limit(1000, 500)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_symbols() {
+        let err = validate_order(None, &limit(10, 100)).unwrap_err();
+        assert_eq!(err, "Unknown symbol ETH");
+    }
+
+    #[test]
+    fn rejects_halted_and_delisted_instruments() {
+        let mut instrument = eth();
+        instrument.status = TradingStatus::Halted;
+        assert!(validate_order(Some(&instrument), &limit(10, 100)).unwrap_err().contains("halted"));
+
+        instrument.status = TradingStatus::Delisted;
+        assert!(validate_order(Some(&instrument), &limit(10, 100)).unwrap_err().contains("delisted"));
+    }
+
+    #[test]
+    fn enforces_quantity_limits_and_lot_size() {
+        let instrument = eth();
+        assert!(validate_order(Some(&instrument), &limit(0, 100)).is_err());
+        assert!(validate_order(Some(&instrument), &limit(1010, 100)).is_err());
+
+        let err = validate_order(Some(&instrument), &limit(15, 100)).unwrap_err();
+        assert!(err.contains("lot size 10"), "{}", err);
+    }
+
+    #[test]
+    fn enforces_tick_size_and_price_band() {
+        let instrument = eth();
+        let err = validate_order(Some(&instrument), &limit(10, 102)).unwrap_err();
+        assert!(err.contains("tick size 5"), "{}", err);
+
+        assert!(validate_order(Some(&instrument), &limit(10, 95)).unwrap_err().contains("band"));
+        assert!(validate_order(Some(&instrument), &limit(10, 505)).unwrap_err().contains("band"));
+    }
+
+    #[test]
+    fn stop_orders_are_priced_like_limits() {
+        let order = OrderRequest { order_type: OrderType::Stop, ..limit(10, 102) };
+        assert!(validate_order(Some(&eth()), &order).is_err());
+    }
+
+    #[test]
+    fn market_orders_skip_the_price_checks() {
+        let order = OrderRequest { order_type: OrderType::Market, ..limit(10, 0) };
+        assert_eq!(validate_order(Some(&eth()), &order), Ok(()));
+
+        // Size rules still apply
+        let order = OrderRequest { order_type: OrderType::Market, ..limit(15, 0) };
+        assert!(validate_order(Some(&eth()), &order).is_err());
+    }
+
+    #[test]
+    fn validates_instrument_definitions() {
+        assert!(validate_instrument(&eth()).is_ok());
+        assert!(validate_instrument(&Instrument { symbol: String::new(), ..eth() }).is_err());
+        assert!(validate_instrument(&Instrument { tick_size: 0, ..eth() }).is_err());
+        assert!(validate_instrument(&Instrument { min_quantity: 2000, ..eth() }).is_err());
+        assert!(validate_instrument(&Instrument { min_price: 600, ..eth() }).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use actix_web::web;
+use chrono::Utc;
+use log::{error, info, warn};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::collections::{BTreeSet, HashMap};
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::batch::cancel_batched_orders;
+use crate::bindings::functions::CancelOrders;
+use crate::errors::ApiError;
+use crate::instruments::INSTRUMENTS_KEY;
+use crate::models::{KillSwitch, OrderStatus, QueuedOrder};
+use crate::orders::{order_book, send_call};
+use crate::sessions::{queued_orders, queued_orders_key};
+use crate::state::AppState;
+use crate::users::{load_user_state, USER_IDS_KEY};
+
+// Redis hash of engaged kill switches keyed by "username:symbol", '*' standing for all
+pub const KILL_SWITCHES_KEY: &str = "kill_switches";
+// Redis hash of username -> unix time their cancel-on-disconnect timer fires
+pub const CANCEL_ON_DISCONNECT_KEY: &str = "cancel_on_disconnect";
+
+fn kill_switch_field(username: Option<&str>, symbol: Option<&str>) -> String {
+    format!("{}:{}", username.unwrap_or("*"), symbol.unwrap_or("*"))
+}
+
+pub async fn engage(con: &mut MultiplexedConnection, kill_switch: &KillSwitch) -> Result<(), ApiError> {
+    let field = kill_switch_field(kill_switch.username.as_deref(), kill_switch.symbol.as_deref());
+    let _: () = con.hset(KILL_SWITCHES_KEY, field, serde_json::to_string(kill_switch)?).await?;
+    Ok(())
+}
+
+pub async fn get_kill_switch(con: &mut MultiplexedConnection, username: Option<&str>, symbol: Option<&str>) -> Result<Option<KillSwitch>, ApiError> {
+    let kill_switch_json: Option<String> = con.hget(KILL_SWITCHES_KEY, kill_switch_field(username, symbol)).await?;
+    Ok(kill_switch_json.map(|kill_switch_json| serde_json::from_str(&kill_switch_json)).transpose()?)
+}
+
+// Returns whether a kill switch was engaged with exactly this scope
+pub async fn release(con: &mut MultiplexedConnection, username: Option<&str>, symbol: Option<&str>) -> Result<bool, ApiError> {
+    let removed: u32 = con.hdel(KILL_SWITCHES_KEY, kill_switch_field(username, symbol)).await?;
+    Ok(removed > 0)
+}
+
+pub async fn all_kill_switches(con: &mut MultiplexedConnection) -> Result<Vec<KillSwitch>, ApiError> {
+    let kill_switches: Vec<String> = con.hvals(KILL_SWITCHES_KEY).await?;
+    Ok(kill_switches.iter().map(|kill_switch| serde_json::from_str(kill_switch)).collect::<Result<_, _>>()?)
+}
+
+// The kill switch blocking this user from trading this symbol, most specific first
+pub async fn blocking_kill_switch(con: &mut MultiplexedConnection, username: &str, symbol: &str) -> Result<Option<KillSwitch>, ApiError> {
+    for (user_scope, symbol_scope) in [(Some(username), Some(symbol)), (Some(username), None), (None, Some(symbol)), (None, None)] {
+        if let Some(kill_switch) = get_kill_switch(con, user_scope, symbol_scope).await? {
+            return Ok(Some(kill_switch));
+        }
+    }
+    Ok(None)
+}
+
+// Drops queued orders matching the scope, both those waiting for their session and
+// those waiting for the order batcher
+pub async fn cancel_queued_orders(con: &mut MultiplexedConnection, username: Option<&str>, symbol: Option<&str>) -> Result<Vec<QueuedOrder>, ApiError> {
+    let symbols: Vec<String> = match symbol {
+        Some(symbol) => vec![symbol.to_string()],
+        None => con.hkeys(INSTRUMENTS_KEY).await?,
+    };
+
+    let mut cancelled = vec![];
+    for symbol in symbols {
+        for queued in queued_orders(con, &symbol).await? {
+            if username.is_none_or(|username| queued.username == username) {
+                let _: () = con.lrem(queued_orders_key(&symbol), 1, serde_json::to_string(&queued)?).await?;
+                cancelled.push(queued);
+            }
+        }
+    }
+    cancelled.extend(cancel_batched_orders(con, username, symbol).await?);
+    Ok(cancelled)
+}
+
+// Pulls orders resting on the OrderBook contracts in scope off their books, with one
+// cancelOrders call per user and venue for each user that has orders there that may
+// still be open. A call that fails is logged and skipped, so the queued cancellations
+// and the kill switch itself still apply. Returns the cancellation transactions sent.
+pub async fn cancel_resting_orders(
+    state: &AppState,
+    con: &mut MultiplexedConnection,
+    username: Option<&str>,
+    symbol: Option<&str>,
+) -> Result<Vec<String>, ApiError> {
+    let usernames: Vec<String> = match username {
+        Some(username) => vec![username.to_string()],
+        None => con.hvals(USER_IDS_KEY).await?,
+    };
+
+    let mut tx_hashes = vec![];
+    for username in usernames {
+        let user_state = match load_user_state(con, &username).await? {
+            Some(user_state) => user_state,
+            None => continue,
+        };
+        let venues: BTreeSet<&str> = user_state.orders.iter()
+            .filter(|order| matches!(order.status, OrderStatus::Submitted | OrderStatus::Accepted))
+            .filter(|order| symbol.is_none_or(|symbol| order.symbol == symbol))
+            .map(|order| state.venues.for_symbol(&order.symbol).name.as_str())
+            .collect();
+
+        for venue_name in venues {
+            let venue = match state.venues.get(venue_name) {
+                Some(venue) => venue,
+                None => continue,
+            };
+            let call = CancelOrders { user_id: username.clone(), symbol: symbol.unwrap_or_default().to_string() };
+            let sent = match venue.web3() {
+                Ok(web3) => send_call(state, venue, &order_book(&web3, venue.contract_address), call, &username).await,
+                Err(e) => Err(e.into()),
+            };
+            match sent {
+                Ok((tx_hash, _)) => {
+                    info!("Cancelling resting orders of {} on {} in {}", username, venue.name, tx_hash);
+                    tx_hashes.push(tx_hash);
+                },
+                Err(e) => error!("Could not cancel resting orders of {} on {}: {}", username, venue.name, e),
+            }
+        }
+    }
+    Ok(tx_hashes)
+}
+
+// Arms or re-arms the user's timer; clients keep calling this as a heartbeat
+pub async fn arm_cancel_on_disconnect(con: &mut MultiplexedConnection, username: &str, timeout_seconds: i64) -> Result<(), ApiError> {
+    if timeout_seconds <= 0 {
+        let _: () = con.hdel(CANCEL_ON_DISCONNECT_KEY, username).await?;
+        return Ok(());
+    }
+    let deadline = Utc::now().timestamp() + timeout_seconds;
+    let _: () = con.hset(CANCEL_ON_DISCONNECT_KEY, username, deadline).await?;
+    Ok(())
+}
+
+// Called from the session scheduler: cancels the queued and resting orders of every
+// user whose heartbeat has lapsed and disarms their timer
+pub async fn fire_cancel_on_disconnect(
+    data: &web::Data<AsyncMutex<AppState>>,
+    state: &AppState,
+    con: &mut MultiplexedConnection,
+) -> Result<(), ApiError> {
+    let deadlines: HashMap<String, i64> = con.hgetall(CANCEL_ON_DISCONNECT_KEY).await?;
+    let now = Utc::now().timestamp();
+    for (username, deadline) in deadlines {
+        if deadline > now {
+            continue;
+        }
+        let cancelled = {
+            let _state = data.lock().await;
+            cancel_queued_orders(con, Some(&username), None).await?
+        };
+        let cancellations = cancel_resting_orders(state, con, Some(&username), None).await?;
+        let _: () = con.hdel(CANCEL_ON_DISCONNECT_KEY, &username).await?;
+        warn!(
+            "Heartbeat lapsed for {}: cancelled {} queued orders, sent {} cancellations of resting orders",
+            username, cancelled.len(), cancellations.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use chrono::Utc;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use uuid::Uuid;
+
+use crate::errors::ApiError;
+use crate::models::{EntryKind, JournalEntry, Posting, UserState};
+
+// Every journal entry, in posting order
+pub const LEDGER_KEY: &str = "ledger";
+// Redis hash of account -> balance, only ever moved together with a journal entry
+pub const BALANCES_KEY: &str = "ledger_balances";
+// Contra account for cash entering or leaving the platform
+pub const EXTERNAL_ACCOUNT: &str = "external";
+
+// Cash accounts are keyed by username, like user states
+pub fn cash_account(username: &str) -> String {
+    format!("cash:{}", username)
+}
+
+fn account_ledger_key(account: &str) -> String {
+    format!("ledger:{}", account)
+}
+
+// Appends a balanced journal entry and applies its postings to the account balances
+// in a single MULTI so a balance never moves without its entry.
+pub async fn post_entry(
+    con: &mut MultiplexedConnection,
+    kind: EntryKind,
+    reference: &str,
+    postings: Vec<Posting>,
+) -> Result<JournalEntry, ApiError> {
+    let total: f64 = postings.iter().map(|posting| posting.amount).sum();
+    if total.abs() >= 1e-6 {
+        return Err(ApiError::Internal(format!("Unbalanced journal entry: postings sum to {}", total)));
+    }
+
+    let entry = JournalEntry {
+        entry_id: Uuid::new_v4().to_string(),
+        kind,
+        reference: reference.to_string(),
+        timestamp: Utc::now(),
+        postings,
+    };
+    let entry_json = serde_json::to_string(&entry)?;
+
+    let mut pipe = redis::pipe();
+    pipe.atomic().rpush(LEDGER_KEY, &entry_json).ignore();
+    for posting in &entry.postings {
+        pipe.hincr(BALANCES_KEY, &posting.account, posting.amount).ignore();
+        pipe.rpush(account_ledger_key(&posting.account), &entry_json).ignore();
+    }
+    let _: () = pipe.query_async(con).await?;
+
+    Ok(entry)
+}
+
+// Moves `amount` from one account to another as a two-legged entry
+pub async fn transfer(
+    con: &mut MultiplexedConnection,
+    kind: EntryKind,
+    reference: &str,
+    from: &str,
+    to: &str,
+    amount: f64,
+) -> Result<JournalEntry, ApiError> {
+    post_entry(con, kind, reference, vec![
+        Posting { account: from.to_string(), amount: -amount },
+        Posting { account: to.to_string(), amount },
+    ]).await
+}
+
+pub async fn balance(con: &mut MultiplexedConnection, account: &str) -> Result<f64, ApiError> {
+    let balance: Option<f64> = con.hget(BALANCES_KEY, account).await?;
+    Ok(balance.unwrap_or(0.0))
+}
+
+pub async fn account_entries(con: &mut MultiplexedConnection, account: &str) -> Result<Vec<JournalEntry>, ApiError> {
+    let entries: Vec<String> = con.lrange(account_ledger_key(account), 0, -1).await?;
+    Ok(entries.iter().map(|entry| serde_json::from_str(entry)).collect::<Result<_, _>>()?)
+}
+
+// Users seeded through initialize_user carry a total_money that was never journaled.
+// Book it as an opening balance the first time their cash account is touched.
+pub async fn ensure_opening_balance(con: &mut MultiplexedConnection, user_state: &UserState) -> Result<(), ApiError> {
+    let account = cash_account(&user_state.username);
+    let has_entries: bool = con.exists(account_ledger_key(&account)).await?;
+    if !has_entries && user_state.portfolio.total_money != 0.0 {
+        transfer(
+            con,
+            EntryKind::OpeningBalance,
+            &user_state.portfolio.portfolio_id,
+            EXTERNAL_ACCOUNT,
+            &account,
+            user_state.portfolio.total_money,
+        ).await?;
+    }
+    Ok(())
+}
+
+// Refreshes the cached cash figure on the portfolio from the ledger
+pub async fn sync_cash(con: &mut MultiplexedConnection, user_state: &mut UserState) -> Result<(), ApiError> {
+    user_state.portfolio.total_money = balance(con, &cash_account(&user_state.username)).await?;
+    Ok(())
+}
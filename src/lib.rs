@@ -0,0 +1,31 @@
+// The server as a library, so the integration tests can serve it in-process
+mod models;
+mod handlers;
+mod events;
+mod state;
+mod ledger;
+mod fees;
+mod instruments;
+mod orders;
+mod sessions;
+mod auction;
+mod valuation;
+mod auth;
+mod users;
+mod tokens;
+mod api_keys;
+mod credentials;
+mod rate_limit;
+mod kill_switch;
+pub mod config;
+mod supervisor;
+mod readiness;
+mod errors;
+mod receipts;
+mod signer;
+mod gas;
+mod batch;
+mod reconcile;
+mod venues;
+mod bindings;
+pub mod app;
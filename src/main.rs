@@ -1,77 +1,60 @@
-mod models;
-mod handlers;
-mod events;
-mod state;
-
-use actix_web::{web, App, HttpServer};
-use actix_web::middleware::Logger;
+use actix_web::HttpServer;
 use dotenv::dotenv;
-use env_logger;
-use std::env;
-use tokio::sync::Mutex as AsyncMutex;
+use log::{error, info, warn};
+use std::time::Duration;
 
-use state::AppState;
-use handlers::*;
-use events::listen_for_events;
+use hft_trading_server::app::Services;
+use hft_trading_server::config::Config;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
 
-    let ws_url = env::var("WS_URL").expect("WS_URL not set in .env file");
-    let contract_address: H160 = env::var("CONTRACT_ADDRESS").expect("CONTRACT_ADDRESS not set in .env file")
-        .parse().expect("Invalid contract address");
-    let account: H160 = env::var("ACCOUNT_ADDRESS").expect("ACCOUNT_ADDRESS not set in .env file")
-        .parse().expect("Invalid account address");
-    let secret = env::var("SECRET_KEY").expect("SECRET_KEY not set in .env file");
-    let redis_url = env::var("REDIS_CLIENT_URL").expect("REDIS_CLIENT_URL not set in .env file");
-
-    let transport = web3::transports::WebSocket::new(&ws_url).await.unwrap();
-    let web3 = web3::Web3::new(transport);
-    let redis_client = redis::Client::open(redis_url).expect("Invalid Redis URL");
+    let config = Config::load().unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+    info!("Loaded {} configuration", config.profile.name());
 
-    let state = web::Data::new(AsyncMutex::new(AppState { 
-        web3: web3.clone(), 
-        contract_address, 
-        account, 
-        secret,
-        redis_client,
-    }));
+    let services = Services::start(&config).await.unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
 
-    let listen_data = state.clone();
+    let app_services = services.clone();
+    let server = HttpServer::new(move || app_services.app())
+        .bind(config.bind_addr)?
+        .shutdown_timeout(config.shutdown_timeout_seconds)
+        .disable_signals()
+        .run();
+
+    // On SIGINT/SIGTERM stop taking orders first, then let the server finish the
+    // requests it already has
+    let server_handle = server.handle();
+    let signal_services = services.clone();
     tokio::spawn(async move {
-        listen_for_events(listen_data).await;
+        wait_for_shutdown_signal().await;
+        info!("Shutdown requested: suspending order entry");
+        signal_services.begin_shutdown();
+        server_handle.stop(true).await;
     });
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(state.clone())
-            .wrap(Logger::default())
+    server.await?;
 
-            // Application routes
-            .route("/register", web::post().to(register_user))
-            .route("/login", web::post().to(login))
-            .route("/signout", web::post().to(signout))
-            .route("/buy", web::post().to(place_buy_order))
-            .route("/sell", web::post().to(place_sell_order))
-            .route("/order/user/{user_id}", web::get().to(get_user_orders))
-            .route("/order/id/{order_id}", web::get().to(get_order_by_id))
-            .route("/portfolio/user/{user_id}", web::get().to(get_user_portfolio))
-            .route("/portfolio/id/{portfolio_id}", web::get().to(get_portfolio_by_id))
-            .route("/transactions", web::get().to(get_user_transactions))
+    // Contract calls started by the session scheduler outlive the HTTP requests
+    if !services.drain(Duration::from_secs(config.shutdown_timeout_seconds)).await {
+        warn!("Shut down with contract calls still in flight; their fills are replayed from the listener checkpoint on restart");
+    }
+    info!("Shutdown complete");
+    Ok(())
+}
 
-            // Utility routes
-            .route("utils/get/users", web::get().to(get_all_users))
-            .route("utils/get/orders", web::get().to(get_order_book))
-            .route("utils/get/portfolios", web::get().to(get_all_portfolios))
-            .route("utils/post/initialize_user", web::post().to(initialize_user))
-            .route("utils/delete/all_data", web::delete().to(delete_all_data))
-            .route("utils/delete/users", web::delete().to(delete_all_users))
-            .route("utils/delete/orders", web::delete().to(delete_all_orders))
-            .route("utils/delete/portfolios", web::delete().to(delete_all_portfolios))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+async fn wait_for_shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
 }
@@ -1,29 +1,100 @@
+use chrono::{DateTime, NaiveTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use uuid::Uuid;
-use web3::types::{Address, H160, U256};
+use web3::types::{Address, U256};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Role {
+    #[default]
+    Trader,
+    ReadOnly,
+    Admin,
+    RiskOfficer,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    pub role: Role,
+    // Token id, the handle used to revoke a single access token. Tokens from before
+    // revocation have none and are refused, as they could never be signed out.
+    pub jti: String,
+    // Issue time, so every token a user held can be revoked at once by a password change
+    #[serde(default)]
+    pub iat: usize,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct RegisterUser {
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+// What a request signed with an API key may do; the owner's role still applies on top
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiScope {
+    Read,
+    Trade,
+    Withdraw,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiKey {
+    pub key_id: String,
+    pub secret: String,
     pub username: String,
-    pub password: String,
+    pub label: String,
+    pub scopes: Vec<ApiScope>,
+    // Addresses or CIDR ranges requests must come from; empty allows any address
+    pub ip_allowlist: Vec<String>,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct LoginUser {
+pub struct ApiKeyRequest {
+    pub label: String,
+    pub scopes: Vec<ApiScope>,
+    #[serde(default)]
+    pub ip_allowlist: Vec<String>,
+}
+
+// ApiKey as listed back to its owner, without the secret
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiKeySummary {
+    pub key_id: String,
+    pub label: String,
+    pub scopes: Vec<ApiScope>,
+    pub ip_allowlist: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<ApiKey> for ApiKeySummary {
+    fn from(api_key: ApiKey) -> Self {
+        ApiKeySummary {
+            key_id: api_key.key_id,
+            label: api_key.label,
+            scopes: api_key.scopes,
+            ip_allowlist: api_key.ip_allowlist,
+            created_at: api_key.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RegisterUser {
     pub username: String,
     pub password: String,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct User {
-    pub user_id: String,
+pub struct PasswordChangeRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LoginUser {
     pub username: String,
     pub password: String,
 }
@@ -43,13 +114,19 @@ impl OrderType {
             OrderType::Stop => "stop",
         }
     }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
 
-    pub fn from_str(s: &str) -> Option<Self> {
-        match s {
-            "limit" => Some(OrderType::Limit),
-            "market" => Some(OrderType::Market),
-            "stop" => Some(OrderType::Stop),
-            _ => None,
+impl OrderSide {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderSide::Buy => "buy",
+            OrderSide::Sell => "sell",
         }
     }
 }
@@ -62,6 +139,25 @@ pub struct Order {
     pub quantity: u32,
     pub price: u32,
     pub order_type: String,
+    #[serde(default)]
+    pub status: OrderStatus,
+    // Filled in from the receipt once the order's transaction is mined
+    #[serde(default)]
+    pub gas_used: Option<u64>,
+    #[serde(default)]
+    pub gas_cost_wei: Option<U256>,
+}
+
+// Submitted until the order's transaction is mined; Rejected if it reverted or was dropped.
+// Expired if a call auction ended without it trading.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    #[default]
+    Submitted,
+    Accepted,
+    Rejected,
+    Expired,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -76,6 +172,54 @@ pub struct OrderRequest {
 pub struct Transaction {
     pub order_id: String,
     pub transaction_id: String,
+    #[serde(default)]
+    pub status: TxStatus,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStatus {
+    #[default]
+    Pending,
+    Mined,
+    Reverted,
+    Dropped,
+}
+
+// What the receipt tracker knows about a contract transaction. Kept until it is
+// final: mined with enough confirmations, reverted or dropped.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TxRecord {
+    pub tx_hash: String,
+    pub order_id: String,
+    pub username: String,
+    // Venue the transaction was sent to; empty for records from before venues
+    #[serde(default)]
+    pub venue: String,
+    // Sending account, so its nonces can be reseeded if the transaction is dropped
+    #[serde(default)]
+    pub from: Address,
+    pub status: TxStatus,
+    pub submitted_at: DateTime<Utc>,
+    pub block_number: Option<u64>,
+    pub gas_used: Option<u64>,
+    #[serde(default)]
+    pub effective_gas_price: Option<U256>,
+    #[serde(default)]
+    pub gas_cost_wei: Option<U256>,
+    pub confirmations: u64,
+    // Hashes of fee-bumped resends, newest last; any of them may be the one mined
+    #[serde(default)]
+    pub replacements: Vec<String>,
+    // Hash of the transaction that was mined, once one is
+    #[serde(default)]
+    pub mined_hash: Option<String>,
+    #[serde(default)]
+    pub last_sent_at: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+    // Orders carried by a placeOrders batch; empty for a single order's transaction
+    #[serde(default)]
+    pub batch: Vec<QueuedOrder>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -83,6 +227,8 @@ pub struct Portfolio {
     pub portfolio_id: String,
     pub total_money: f64,
     pub assets: HashMap<String, Asset>,
+    #[serde(default)]
+    pub realized_pnl: f64,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -100,6 +246,30 @@ pub struct Asset {
     pub market_value: f64,
     pub average_cost: f64,
     pub portfolio_diversity: f64,
+    #[serde(default)]
+    pub unrealized_pnl: f64,
+    #[serde(default)]
+    pub realized_pnl: f64,
+    #[serde(default)]
+    pub lots: Vec<Lot>,
+}
+
+// Open tax lot, only consumed when the cost basis method is FIFO
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Lot {
+    pub quantity: u32,
+    pub price: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PortfolioValuation {
+    pub portfolio_id: String,
+    pub total_money: f64,
+    pub market_value: f64,
+    pub total_equity: f64,
+    pub realized_pnl: f64,
+    pub unrealized_pnl: f64,
+    pub assets: HashMap<String, Asset>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -110,10 +280,37 @@ pub struct UserState {
     pub orders: Vec<Order>,
     pub transactions: Vec<Transaction>,
     pub portfolio: Portfolio,
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoleRequest {
+    pub username: String,
+    pub role: Role,
+}
+
+// Where a fill was matched. Contract fills carry the contract's order ids. Call auction
+// fills are matched off-chain, carry no contract ids (both 0) and name the maker side,
+// the earlier-queued order of the pair.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FillSource {
+    #[default]
+    Contract,
+    Auction { maker: OrderSide },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OrderMatchedEvent {
+    #[serde(default)]
+    pub source: FillSource,
+    // Venue whose contract matched the fill, or whose symbol an auction uncrossed
+    #[serde(default)]
+    pub venue: String,
+    // Block number and log index of a contract fill's OrderMatched log; None for auction fills
+    #[serde(default)]
+    pub position: Option<(u64, u64)>,
     pub buy_order_id: U256,
     pub sell_order_id: U256,
     pub symbol: String,
@@ -126,3 +323,243 @@ pub struct OrderMatchedEvent {
     pub seller_user_id: String,
     pub seller_order_id: String,
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CashRequest {
+    pub amount: f64,
+}
+
+// Credits a user's cash account from outside the platform
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DepositRequest {
+    pub username: String,
+    pub amount: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    OpeningBalance,
+    Deposit,
+    Withdrawal,
+    Fill,
+    Fee,
+}
+
+// One side of a journal entry; positive amounts credit the account, negative debit it
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Posting {
+    pub account: String,
+    pub amount: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub entry_id: String,
+    pub kind: EntryKind,
+    pub reference: String,
+    pub timestamp: DateTime<Utc>,
+    pub postings: Vec<Posting>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+// Rates are fractions of notional, e.g. 0.001 for 10 bps
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FeeRates {
+    pub maker: f64,
+    pub taker: f64,
+}
+
+impl FeeRates {
+    pub fn for_liquidity(&self, liquidity: Liquidity) -> f64 {
+        match liquidity {
+            Liquidity::Maker => self.maker,
+            Liquidity::Taker => self.taker,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FeeTier {
+    pub min_volume: f64,
+    pub rates: FeeRates,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FeeSchedule {
+    pub base_rates: FeeRates,
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+    #[serde(default)]
+    pub symbol_rates: HashMap<String, FeeRates>,
+    #[serde(default)]
+    pub minimum_fee: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum TradingStatus {
+    Active,
+    Halted,
+    Delisted,
+}
+
+// Reference data for a tradable symbol; prices and sizes use the same integer units as orders
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Instrument {
+    pub symbol: String,
+    pub description: String,
+    pub tick_size: u32,
+    pub lot_size: u32,
+    pub min_quantity: u32,
+    pub max_quantity: u32,
+    pub min_price: u32,
+    pub max_price: u32,
+    pub status: TradingStatus,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum SessionPhase {
+    PreOpen,
+    OpeningAuction,
+    Continuous,
+    ClosingAuction,
+    Closed,
+}
+
+// Daily schedule for a symbol in UTC; each time is when that phase begins
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SessionCalendar {
+    pub symbol: String,
+    pub pre_open: NaiveTime,
+    pub opening_auction: NaiveTime,
+    pub continuous: NaiveTime,
+    pub closing_auction: NaiveTime,
+    pub close: NaiveTime,
+    // ISO weekdays the symbol trades on, 1 = Monday
+    pub trading_days: Vec<u32>,
+    // Uncross orders collected during the auction phases at a single price instead
+    // of releasing them to continuous trading one by one
+    #[serde(default)]
+    pub auctions: bool,
+}
+
+// Halts the symbol when a fill moves more than max_move_pct away from the reference price
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CircuitBreaker {
+    pub symbol: String,
+    pub max_move_pct: f64,
+    pub halt_seconds: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Halt {
+    pub symbol: String,
+    pub reason: String,
+    pub halted_at: DateTime<Utc>,
+    pub resume_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HaltRequest {
+    pub symbol: String,
+    pub reason: String,
+    pub duration_seconds: Option<i64>,
+}
+
+// Order held back from the contract, until the session allows it or until the
+// order batcher sends it
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QueuedOrder {
+    pub order_id: String,
+    pub username: String,
+    pub side: OrderSide,
+    pub order: OrderRequest,
+    pub queued_at: DateTime<Utc>,
+}
+
+// Indicative (during the call) or final (at uncross) outcome of a call auction
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuctionIndicative {
+    pub symbol: String,
+    pub price: Option<u32>,
+    pub executable_volume: u64,
+    pub imbalance: u64,
+    pub imbalance_side: Option<OrderSide>,
+}
+
+// Blocks order entry for a user, a symbol, or both; None matches everyone / every symbol
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KillSwitch {
+    pub username: Option<String>,
+    pub symbol: Option<String>,
+    pub reason: String,
+    pub engaged_by: String,
+    pub engaged_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KillSwitchRequest {
+    pub username: Option<String>,
+    pub symbol: Option<String>,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CancelOnDisconnectRequest {
+    // 0 disarms the timer
+    pub timeout_seconds: i64,
+}
+
+// What a reconciliation run found between the contract's book and Redis
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscrepancyKind {
+    // In a user's orders but missing its own order record
+    MissingOrderRecord,
+    // The user's copy of an order disagrees with the order record
+    StaleUserOrder,
+    // Accepted in Redis but not in the contract's book
+    OrphanedOrder,
+    // In the contract's book but rejected in Redis
+    UnconfirmedOrder,
+    // Symbol, price or quantity differ between the contract and Redis
+    MismatchedOrder,
+    // The contract filled more of an order than Redis has settled
+    MissedFill,
+    // Redis has settled more of an order than the contract filled
+    ExcessFill,
+    // In the contract's book without a Redis order
+    UnknownChainOrder,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Discrepancy {
+    pub kind: DiscrepancyKind,
+    pub order_id: Option<String>,
+    pub username: Option<String>,
+    // The order's id in the contract's buyOrders/sellOrders
+    pub contract_order_id: Option<u64>,
+    pub detail: String,
+    pub repaired: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReconciliationReport {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub repair: bool,
+    pub contract_orders: u64,
+    pub redis_orders: usize,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReconcileRequest {
+    #[serde(default)]
+    pub repair: bool,
+}
@@ -0,0 +1,197 @@
+use actix_web::web;
+use chrono::Utc;
+use log::info;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use web3::contract::{Contract, Options};
+use web3::signing::{Key, SecretKeyRef};
+use web3::transports::WebSocket;
+use web3::types::{Address, U256};
+use web3::Web3;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::batch::enqueue_order;
+use crate::errors::ApiError;
+use crate::bindings::{abi, Function};
+use crate::bindings::functions::{PlaceBuyOrder, PlaceSellOrder};
+use crate::models::{Order, OrderRequest, OrderSide, OrderStatus, OrderType, QueuedOrder, Transaction, TxStatus};
+use crate::gas::{current_fees, gas_with_margin};
+use crate::receipts::track_transaction;
+use crate::state::AppState;
+use crate::venues::Venue;
+use crate::users::{load_user_state, save_user_state};
+
+pub fn order_book(web3: &Web3<WebSocket>, contract_address: Address) -> Contract<WebSocket> {
+    Contract::new(web3.eth(), contract_address, abi().clone())
+}
+
+pub fn contract_order_type(order_type: &OrderType) -> U256 {
+    match order_type {
+        OrderType::Limit => U256::from(0),
+        OrderType::Market => U256::from(1),
+        OrderType::Stop => U256::from(2),
+    }
+}
+
+// Estimates, prices and sends one contract call on the venue, signed locally for
+// `username` when there is a signer. Returns the transaction hash and the sending account.
+pub async fn send_call<F: Function>(
+    state: &AppState,
+    venue: &Venue,
+    contract: &Contract<WebSocket>,
+    call: F,
+    username: &str,
+) -> Result<(String, Address), web3::contract::Error> {
+    let key = state.signer.as_ref().map(|signer| signer.key_for(username));
+    let from = key.as_ref().map_or(state.config.account, |key| SecretKeyRef::new(key).address());
+
+    let _in_flight = venue.health.track();
+    // Estimated per call, so cheap orders do not reserve the whole gas limit
+    let estimate = contract.estimate_gas(F::NAME, call.clone(), from, Options::default()).await?;
+    let mut options = Options {
+        gas: Some(gas_with_margin(estimate, &state.config)),
+        ..Default::default()
+    };
+    let web3 = venue.web3()?;
+    current_fees(&web3, &state.config).await?.apply(&mut options);
+
+    let tx_id = match (&state.signer, key) {
+        (Some(signer), Some(key)) => {
+            options.nonce = Some(signer.nonces.next(&web3, venue.chain_id(), from).await?);
+            match contract.signed_call(F::NAME, call, options, &key).await {
+                Ok(tx_id) => tx_id,
+                Err(e) => {
+                    // The nonce may not have been used, which would stall every later order
+                    signer.nonces.resync(venue.chain_id(), from).await;
+                    return Err(e.into());
+                },
+            }
+        },
+        _ => contract.call(F::NAME, call, from, options).await?,
+    };
+    Ok((format!("{:?}", tx_id), from))
+}
+
+// Sends the order to its symbol's venue and records it against the user. With batching
+// on, the order is recorded now and sent by the order batcher instead.
+// Shared by the HTTP order routes and the session scheduler releasing queued orders,
+// which pass a copy of the state; the lock is only taken for the user state writes.
+pub async fn submit_order(
+    data: &web::Data<AsyncMutex<AppState>>,
+    state: &AppState,
+    con: &mut MultiplexedConnection,
+    username: &str,
+    order_id: &str,
+    order: &OrderRequest,
+    side: OrderSide,
+) -> Result<Order, ApiError> {
+    if state.config.batch_window_ms > 0 {
+        info!("Batching {} order {}: {:?}", side.as_str(), order_id, order);
+        let _state = data.lock().await;
+        let new_order = record_order(con, username, order_id, order, OrderStatus::Submitted).await?;
+        enqueue_order(con, &QueuedOrder {
+            order_id: order_id.to_string(),
+            username: username.to_string(),
+            side,
+            order: order.clone(),
+            queued_at: Utc::now(),
+        }).await?;
+        return Ok(new_order);
+    }
+
+    let venue = state.venues.for_symbol(&order.symbol);
+    let (tx_hash, from) = send_order(state, venue, username, order_id, order, side).await?;
+    let _state = data.lock().await;
+    let new_order = record_order(con, username, order_id, order, OrderStatus::Submitted).await?;
+    attach_transaction(con, username, order_id, &tx_hash).await?;
+
+    // The call only returns the hash; whether the order was accepted is known once it is mined
+    track_transaction(con, &tx_hash, order_id, username, &venue.name, from, vec![]).await?;
+
+    Ok(new_order)
+}
+
+// Sends the order in a transaction of its own
+pub async fn send_order(
+    state: &AppState,
+    venue: &Venue,
+    username: &str,
+    order_id: &str,
+    order: &OrderRequest,
+    side: OrderSide,
+) -> Result<(String, Address), web3::contract::Error> {
+    let contract = order_book(&venue.web3()?, venue.contract_address);
+
+    let user_id = username.to_string(); // Assuming username is unique and used as user_id
+
+    info!("Placing {} order on {}: {:?}", side.as_str(), venue.name, order);
+
+    let symbol = order.symbol.clone();
+    let quantity = U256::from(order.quantity);
+    let price = U256::from(order.price);
+    let order_id = order_id.to_string();
+    let order_type = contract_order_type(&order.order_type);
+
+    let (tx_hash, from) = match side {
+        OrderSide::Buy => {
+            let call = PlaceBuyOrder { symbol, quantity, price, user_id, order_id, order_type };
+            send_call(state, venue, &contract, call, username).await?
+        },
+        OrderSide::Sell => {
+            let call = PlaceSellOrder { symbol, quantity, price, user_id, order_id, order_type };
+            send_call(state, venue, &contract, call, username).await?
+        },
+    };
+
+    info!("{} order placed successfully: tx_id = {}", side.as_str(), tx_hash);
+    Ok((tx_hash, from))
+}
+
+// Records the order against the user, replacing any earlier record of the same order
+pub async fn record_order(
+    con: &mut MultiplexedConnection,
+    username: &str,
+    order_id: &str,
+    order: &OrderRequest,
+    status: OrderStatus,
+) -> Result<Order, ApiError> {
+    let mut user_state = load_user_state(con, username).await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    let new_order = Order {
+        order_id: order_id.to_string(),
+        user_id: user_state.user_id.clone(),
+        symbol: order.symbol.clone(),
+        quantity: order.quantity,
+        price: order.price,
+        order_type: order.order_type.as_str().to_string(),
+        status,
+        gas_used: None,
+        gas_cost_wei: None,
+    };
+
+    // Update user's portfolio
+    user_state.orders.retain(|existing| existing.order_id != order_id);
+    user_state.orders.push(new_order.clone());
+
+    // Store the order in Redis
+    let order_json = serde_json::to_string(&new_order)?;
+    let _: () = con.set(order_id, order_json).await?;
+
+    // Update user state in Redis
+    save_user_state(con, &user_state).await?;
+
+    Ok(new_order)
+}
+
+// Adds the transaction carrying an order to the user's transactions
+pub async fn attach_transaction(con: &mut MultiplexedConnection, username: &str, order_id: &str, tx_hash: &str) -> Result<(), ApiError> {
+    let mut user_state = load_user_state(con, username).await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+    user_state.transactions.push(Transaction {
+        order_id: order_id.to_string(),
+        transaction_id: tx_hash.to_string(),
+        status: TxStatus::Pending,
+    });
+    save_user_state(con, &user_state).await
+}
@@ -0,0 +1,250 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpMessage, ResponseError};
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use log::warn;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use crate::config::Config;
+use crate::errors::ApiError;
+use crate::models::{ApiKey, Claims};
+
+// Refills `rate` tokens per second up to `burst`; each request takes one token
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl Limit {
+    // "rate:burst", as the rate limit settings are written
+    pub fn parse(limit: &str) -> Option<Limit> {
+        let (rate, burst) = limit.trim().split_once(':')?;
+        let limit = Limit { rate: rate.parse().ok()?, burst: burst.parse().ok()? };
+        if limit.rate > 0.0 && limit.burst >= 1.0 {
+            Some(limit)
+        } else {
+            None
+        }
+    }
+}
+
+// Order entry sends a gas-bearing transaction per request, so it gets a tighter default
+pub fn route_limit(config: &Config, path: &str) -> Limit {
+    match (config.rate_limits.get(path), path) {
+        (Some(limit), _) => *limit,
+        (None, "/buy" | "/sell") => Limit { rate: 2.0, burst: 5.0 },
+        (None, _) => config.rate_limit_default,
+    }
+}
+
+const TOKEN_BUCKET: &str = r"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(bucket[1]) or burst
+local updated = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate)
+local retry_after = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    retry_after = math.ceil((1 - tokens) / rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', tostring(now))
+redis.call('EXPIRE', KEYS[1], math.ceil(burst / rate) + 1)
+return retry_after
+";
+
+// Takes a token from the bucket, returning the seconds to wait if it was empty. The
+// bucket lives in Redis so every server instance draws from the same one.
+pub async fn take_token(con: &mut MultiplexedConnection, bucket: &str, limit: Limit) -> Result<Option<i64>, ApiError> {
+    let now = Utc::now().timestamp_millis() as f64 / 1000.0;
+    let retry_after: i64 = redis::Script::new(TOKEN_BUCKET)
+        .key(format!("rate_limit:{}", bucket))
+        .arg(limit.rate)
+        .arg(limit.burst)
+        .arg(now)
+        .invoke_async(con)
+        .await?;
+    if retry_after > 0 {
+        Ok(Some(retry_after))
+    } else {
+        Ok(None)
+    }
+}
+
+// What the middleware reads on every request, registered as its own app data so
+// rate limiting never waits on the AppState lock
+pub struct RateLimiter {
+    pub redis_client: redis::Client,
+    pub config: Config,
+}
+
+const PROBE_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+enum Subject {
+    // One bucket per client address across every route
+    Ip,
+    // One bucket per API key, or per user for token callers, on a single route
+    Caller(&'static str),
+}
+
+// Middleware returning 429 with Retry-After once a bucket runs dry. Caller limits read
+// the identity RequireRole leaves in the request extensions, so they must be wrapped
+// inside it.
+pub struct RateLimit {
+    subject: Rc<Subject>,
+}
+
+impl RateLimit {
+    pub fn per_ip() -> Self {
+        RateLimit { subject: Rc::new(Subject::Ip) }
+    }
+
+    pub fn per_caller(route: &'static str) -> Self {
+        RateLimit { subject: Rc::new(Subject::Caller(route)) }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            subject: self.subject.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    subject: Rc<Subject>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let subject = self.subject.clone();
+
+        Box::pin(async move {
+            // Probes must not be throttled by the orchestrator
+            if PROBE_PATHS.contains(&req.path()) {
+                return service.call(req).await.map(ServiceResponse::map_into_left_body);
+            }
+
+            let limiter = match req.app_data::<web::Data<RateLimiter>>() {
+                Some(limiter) => limiter.clone(),
+                None => {
+                    let response = ApiError::Internal("Rate limiter is not configured".to_string()).error_response();
+                    return Ok(req.into_response(response).map_into_right_body());
+                },
+            };
+            let (bucket, limit) = match &*subject {
+                Subject::Ip => {
+                    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+                    (format!("ip:{}", ip), limiter.config.rate_limit_ip)
+                },
+                Subject::Caller(route) => {
+                    let caller = match (req.extensions().get::<ApiKey>(), req.extensions().get::<Claims>()) {
+                        (Some(api_key), _) => format!("key:{}", api_key.key_id),
+                        (None, Some(claims)) => format!("user:{}", claims.sub),
+                        (None, None) => "anonymous".to_string(),
+                    };
+                    (format!("{}:{}", route, caller), route_limit(&limiter.config, route))
+                },
+            };
+
+            let taken = async {
+                let mut con = limiter.redis_client.get_multiplexed_async_connection().await?;
+                take_token(&mut con, &bucket, limit).await
+            };
+            match taken.await {
+                Ok(None) => service.call(req).await.map(ServiceResponse::map_into_left_body),
+                Ok(Some(retry_after)) => {
+                    warn!("Rate limited {} on {}", bucket, req.path());
+                    let response = ApiError::RateLimited { message: "Rate limit exceeded".to_string(), retry_after }.error_response();
+                    Ok(req.into_response(response).map_into_right_body())
+                },
+                Err(e) => Ok(req.into_response(e.error_response()).map_into_right_body()),
+            }
+        })
+    }
+}
+
+// Order-to-trade monitoring. Orders and fills are counted per user over a fixed
+// window; a user whose orders outnumber their fills by more than the allowed ratio is
+// barred from entering orders for a while.
+
+fn order_to_trade_key(username: &str) -> String {
+    format!("order_to_trade:{}", username)
+}
+
+fn throttled_key(username: &str) -> String {
+    format!("order_throttle:{}", username)
+}
+
+async fn count(con: &mut MultiplexedConnection, config: &Config, username: &str, field: &str) -> Result<i64, ApiError> {
+    let key = order_to_trade_key(username);
+    let counted: i64 = con.hincr(&key, field, 1).await?;
+    let ttl: i64 = con.ttl(&key).await?;
+    if ttl < 0 {
+        let _: () = con.expire(&key, config.otr_window_seconds).await?;
+    }
+    Ok(counted)
+}
+
+// Seconds left on the user's throttle, if any
+pub async fn order_throttle_remaining(con: &mut MultiplexedConnection, username: &str) -> Result<Option<i64>, ApiError> {
+    let ttl: i64 = con.ttl(throttled_key(username)).await?;
+    if ttl > 0 {
+        Ok(Some(ttl))
+    } else {
+        Ok(None)
+    }
+}
+
+// Counts an accepted order and throttles the user once the window holds at least
+// otr_min_orders orders at more than otr_max_ratio orders per fill
+pub async fn record_order_entry(con: &mut MultiplexedConnection, config: &Config, username: &str) -> Result<(), ApiError> {
+    let orders = count(con, config, username, "orders").await?;
+    let trades: Option<i64> = con.hget(order_to_trade_key(username), "trades").await?;
+    let ratio = orders as f64 / trades.unwrap_or(0).max(1) as f64;
+
+    if orders >= config.otr_min_orders && ratio > config.otr_max_ratio {
+        let throttle_seconds = config.otr_throttle_seconds;
+        let _: () = con.set_ex(throttled_key(username), ratio, throttle_seconds as u64).await?;
+        let _: () = con.del(order_to_trade_key(username)).await?;
+        warn!("Throttled {} for {}s: order-to-trade ratio {:.1}", username, throttle_seconds, ratio);
+    }
+    Ok(())
+}
+
+pub async fn record_trade(con: &mut MultiplexedConnection, config: &Config, username: &str) -> Result<(), ApiError> {
+    count(con, config, username, "trades").await?;
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use chrono::Utc;
+use futures::future::join_all;
+use log::warn;
+use serde_json::{json, Map, Value};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use web3::types::{BlockId, BlockNumber};
+
+use crate::events::load_checkpoint;
+use crate::venues::{Venue, Venues};
+
+// Handles the readiness probe checks through. Registered as its own app data, like
+// Venues, so probes never queue behind the AppState lock.
+pub struct Dependencies {
+    pub redis_client: redis::Client,
+    pub venues: Arc<Venues>,
+    pub max_listener_lag_blocks: u64,
+    // 0 disables the check, as dev chains only mine when there is a transaction
+    pub max_block_age_seconds: u64,
+}
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Runs a check under the timeout, recording how long it took
+async fn timed<F, T>(check: F) -> (Result<T, String>, u128)
+where
+    F: Future<Output = Result<T, String>>,
+{
+    let started = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {}s", CHECK_TIMEOUT.as_secs())),
+    };
+    (result, started.elapsed().as_millis())
+}
+
+fn failed(error: String, latency_ms: u128) -> Value {
+    json!({ "ok": false, "error": error, "latency_ms": latency_ms })
+}
+
+impl Dependencies {
+    // Also reads each venue's listener checkpoint, in venue order. Redis is down, and
+    // the probe fails, if either the PING or any checkpoint read errors.
+    async fn check_redis(&self) -> (Value, Vec<Option<(u64, u64)>>) {
+        let (result, latency_ms) = timed(async {
+            let mut con = self.redis_client.get_multiplexed_async_connection().await.map_err(|e| e.to_string())?;
+            let _: String = redis::cmd("PING").query_async(&mut con).await.map_err(|e| e.to_string())?;
+            let mut checkpoints = vec![];
+            for venue in self.venues.all() {
+                checkpoints.push(load_checkpoint(&mut con, &venue.name).await.map_err(|e| e.to_string())?);
+            }
+            Ok(checkpoints)
+        }).await;
+
+        match result {
+            Ok(checkpoints) => (json!({ "ok": true, "status": "up", "latency_ms": latency_ms }), checkpoints),
+            Err(e) => {
+                warn!("Readiness: Redis is down: {}", e);
+                let mut check = failed(e, latency_ms);
+                check["status"] = json!("down");
+                (check, vec![None; self.venues.all().len()])
+            },
+        }
+    }
+
+    async fn check_chain(&self, venue: &Venue) -> (Value, Option<u64>) {
+        let (result, latency_ms) = timed(async {
+            let web3 = venue.web3().map_err(|e| e.to_string())?;
+            let block_number = web3.eth().block_number().await.map_err(|e| e.to_string())?;
+            let block = web3.eth().block(BlockId::Number(BlockNumber::Number(block_number))).await.map_err(|e| e.to_string())?;
+            let age = block.map(|block| Utc::now().timestamp() - block.timestamp.as_u64() as i64);
+            Ok((block_number.as_u64(), age))
+        }).await;
+
+        match result {
+            Ok((block_number, age)) => {
+                let stale = self.max_block_age_seconds > 0 && age.is_none_or(|age| age > self.max_block_age_seconds as i64);
+                let mut check = json!({
+                    "ok": !stale,
+                    "chain_id": venue.chain_id(),
+                    "block_number": block_number,
+                    "block_age_seconds": age,
+                    "latency_ms": latency_ms
+                });
+                if stale {
+                    check["error"] = json!(format!("latest block is older than {}s", self.max_block_age_seconds));
+                }
+                (check, Some(block_number))
+            },
+            Err(e) => (failed(e, latency_ms), None),
+        }
+    }
+
+    async fn check_contract(&self, venue: &Venue) -> Value {
+        let (result, latency_ms) = timed(async {
+            let web3 = venue.web3().map_err(|e| e.to_string())?;
+            web3.eth().code(venue.contract_address, None).await.map_err(|e| e.to_string())
+        }).await;
+
+        match result {
+            Ok(code) if !code.0.is_empty() => json!({ "ok": true, "address": venue.contract_address, "latency_ms": latency_ms }),
+            Ok(_) => failed(format!("no contract code at {:?}", venue.contract_address), latency_ms),
+            Err(e) => failed(e, latency_ms),
+        }
+    }
+
+    // Lag is counted in blocks from the chain head to the last log the listener applied.
+    // Without a checkpoint yet there is nothing to lag behind.
+    fn check_listener(&self, venue: &Venue, head: Option<u64>, checkpoint: Option<(u64, u64)>) -> Value {
+        let status = venue.health.status();
+        // The checkpoint only moves with contract logs, the last block with every head
+        let observed = checkpoint.map(|(block_number, _)| block_number).max(status.last_block);
+        let lag = match (head, observed) {
+            (Some(head), Some(block_number)) => Some(head.saturating_sub(block_number)),
+            _ => None,
+        };
+        let lagging = lag.is_some_and(|lag| lag > self.max_listener_lag_blocks);
+
+        let mut check = json!({
+            "ok": status.listener_up && !lagging,
+            "listener_up": status.listener_up,
+            "lag_blocks": lag,
+            "restarts": status.listener_restarts,
+            "last_event_at": status.last_event_at
+        });
+        if !status.listener_up {
+            check["error"] = json!("event listener is down");
+        } else if lagging {
+            check["error"] = json!(format!("listener is more than {} blocks behind", self.max_listener_lag_blocks));
+        }
+        check
+    }
+
+    async fn check_venue(&self, venue: &Venue) -> ((Value, Option<u64>), Value) {
+        tokio::join!(self.check_chain(venue), self.check_contract(venue))
+    }
+
+    // Returns whether every dependency of every venue is ready, with the breakdown per
+    // dependency and venue
+    pub async fn readiness(&self) -> (bool, Value) {
+        let ((redis, checkpoints), venue_checks) = tokio::join!(
+            self.check_redis(),
+            join_all(self.venues.all().iter().map(|venue| self.check_venue(venue)))
+        );
+        let shutting_down = self.venues.default_venue().health.shutting_down();
+        let mut ready = !shutting_down && redis["ok"] == json!(true);
+
+        let mut venues = Map::new();
+        for ((venue, ((chain, head), contract)), checkpoint) in self.venues.all().iter().zip(venue_checks).zip(checkpoints) {
+            let listener = self.check_listener(venue, head, checkpoint);
+            ready &= [&chain, &contract, &listener].iter().all(|check| check["ok"] == json!(true));
+            venues.insert(venue.name.clone(), json!({
+                "chain": chain,
+                "contract": contract,
+                "listener": listener
+            }));
+        }
+
+        (ready, json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "shutting_down": shutting_down,
+            "checks": {
+                "redis": redis,
+                "venues": venues
+            }
+        }))
+    }
+}
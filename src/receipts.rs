@@ -0,0 +1,377 @@
+use actix_web::web;
+use chrono::Utc;
+use log::{error, info, warn};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use tokio::sync::Mutex as AsyncMutex;
+use web3::types::{Address, TransactionId, TransactionParameters, H256, U256};
+
+use crate::batch::{placed_order_ids, resubmit_singly};
+use crate::config::Config;
+use crate::errors::ApiError;
+use crate::gas::{bumped_fees, record_gas_metrics, replacement};
+use crate::models::{Order, OrderStatus, QueuedOrder, TxRecord, TxStatus};
+use crate::signer::Signer;
+use crate::state::AppState;
+use crate::users::{load_user_state, save_user_state};
+use crate::venues::Venue;
+
+// Set of transaction hashes the tracker still polls
+pub const PENDING_TXS_KEY: &str = "pending_txs";
+
+fn tx_key(tx_hash: &str) -> String {
+    format!("tx:{}", tx_hash)
+}
+
+pub async fn get_tx_record(con: &mut MultiplexedConnection, tx_hash: &str) -> Result<Option<TxRecord>, ApiError> {
+    let record_json: Option<String> = con.get(tx_key(tx_hash)).await?;
+    Ok(record_json.map(|record_json| serde_json::from_str(&record_json)).transpose()?)
+}
+
+async fn save_tx_record(con: &mut MultiplexedConnection, record: &TxRecord) -> Result<(), ApiError> {
+    let _: () = con.set(tx_key(&record.tx_hash), serde_json::to_string(record)?).await?;
+    Ok(())
+}
+
+// Starts tracking a transaction submitted for an order, or for a batch of them
+pub async fn track_transaction(
+    con: &mut MultiplexedConnection,
+    tx_hash: &str,
+    order_id: &str,
+    username: &str,
+    venue: &str,
+    from: Address,
+    batch: Vec<QueuedOrder>,
+) -> Result<(), ApiError> {
+    let now = Utc::now();
+    save_tx_record(con, &TxRecord {
+        tx_hash: tx_hash.to_string(),
+        order_id: order_id.to_string(),
+        username: username.to_string(),
+        venue: venue.to_string(),
+        from,
+        status: TxStatus::Pending,
+        submitted_at: now,
+        block_number: None,
+        gas_used: None,
+        effective_gas_price: None,
+        gas_cost_wei: None,
+        confirmations: 0,
+        replacements: vec![],
+        mined_hash: None,
+        last_sent_at: Some(now),
+        updated_at: now,
+        batch,
+    }).await?;
+    let _: () = con.sadd(PENDING_TXS_KEY, tx_hash).await?;
+    Ok(())
+}
+
+// Mirrors the transaction's outcome onto one of its orders and the user's copies of it.
+// Call with the AppState lock held, as fills and new orders also rewrite the user state.
+async fn update_order(con: &mut MultiplexedConnection, record: &TxRecord, order_id: &str, username: &str, order_status: OrderStatus) -> Result<(), ApiError> {
+    // A batch's gas is split evenly between its orders
+    let orders = record.batch.len().max(1) as u64;
+    let gas_used = record.gas_used.map(|gas_used| gas_used / orders);
+    let gas_cost_wei = record.gas_cost_wei.map(|cost| cost / U256::from(orders));
+
+    let order_json: Option<String> = con.get(order_id).await?;
+    if let Some(order_json) = order_json {
+        let mut order: Order = serde_json::from_str(&order_json)?;
+        order.status = order_status;
+        order.gas_used = gas_used;
+        order.gas_cost_wei = gas_cost_wei;
+        let _: () = con.set(order_id, serde_json::to_string(&order)?).await?;
+    }
+
+    if let Some(mut user_state) = load_user_state(con, username).await? {
+        for order in user_state.orders.iter_mut().filter(|order| order.order_id == order_id) {
+            order.status = order_status;
+            order.gas_used = gas_used;
+            order.gas_cost_wei = gas_cost_wei;
+        }
+        for transaction in user_state.transactions.iter_mut().filter(|transaction| transaction.transaction_id == record.tx_hash) {
+            transaction.status = record.status;
+        }
+        save_user_state(con, &user_state).await?;
+    }
+    Ok(())
+}
+
+// Mined transactions stay tracked until they have enough confirmations
+fn is_final(record: &TxRecord, config: &Config) -> bool {
+    match record.status {
+        TxStatus::Pending => false,
+        TxStatus::Mined => record.confirmations >= config.tx_confirmations,
+        TxStatus::Reverted | TxStatus::Dropped => true,
+    }
+}
+
+fn parse_hash(tx_hash: &str) -> Result<H256, web3::Error> {
+    tx_hash.parse().map_err(|_| web3::Error::Decoder(format!("invalid transaction hash {}", tx_hash)))
+}
+
+// Resends the newest of the record's transactions with the same nonce and higher fees.
+// Only the node's own account or our signer can replace it, so the replacement is
+// signed the same way the original was.
+async fn bump_fees(
+    venue: &Venue,
+    config: &Config,
+    signer: Option<&Signer>,
+    record: &mut TxRecord,
+    newest: H256,
+) -> Result<(), web3::Error> {
+    let web3 = venue.web3()?;
+    let transaction = match web3.eth().transaction(TransactionId::Hash(newest)).await? {
+        Some(transaction) => transaction,
+        None => return Ok(()),
+    };
+    let fees = match bumped_fees(&transaction, config) {
+        Some(fees) => fees,
+        None => {
+            warn!("Transaction {} for order {} is stuck at max_fee_gwei", record.tx_hash, record.order_id);
+            return Ok(());
+        }
+    };
+
+    let request = replacement(&transaction, fees);
+    let tx_hash = match signer {
+        Some(signer) => {
+            let key = signer.key_for(&record.username);
+            let signed = web3.accounts().sign_transaction(TransactionParameters {
+                nonce: request.nonce,
+                to: request.to,
+                gas: transaction.gas,
+                gas_price: request.gas_price,
+                value: transaction.value,
+                data: transaction.input.clone(),
+                transaction_type: request.transaction_type,
+                max_fee_per_gas: request.max_fee_per_gas,
+                max_priority_fee_per_gas: request.max_priority_fee_per_gas,
+                ..Default::default()
+            }, &key).await?;
+            web3.eth().send_raw_transaction(signed.raw_transaction).await?
+        },
+        None => web3.eth().send_transaction(request).await?,
+    };
+
+    info!("Bumped fees of order {}: {:?} replaces {:?} ({:?})", record.order_id, tx_hash, newest, fees);
+    record.replacements.push(format!("{:?}", tx_hash));
+    record.last_sent_at = Some(Utc::now());
+    Ok(())
+}
+
+// Polls one transaction and any fee-bumped replacements, records what changed and
+// returns the updated record
+async fn poll_transaction(
+    data: &web::Data<AsyncMutex<AppState>>,
+    venue: &Venue,
+    config: &Config,
+    signer: Option<&Signer>,
+    con: &mut MultiplexedConnection,
+    head: u64,
+    mut record: TxRecord,
+) -> Result<TxRecord, web3::Error> {
+    let web3 = venue.web3()?;
+    let previous = record.status;
+    let hashes = std::iter::once(&record.tx_hash)
+        .chain(record.replacements.iter())
+        .map(|tx_hash| parse_hash(tx_hash))
+        .collect::<Result<Vec<H256>, _>>()?;
+
+    // Only one of them can be mined, as they share a nonce
+    let mut mined = None;
+    let mut placed = None;
+    for hash in hashes.iter().rev() {
+        if let Some(receipt) = web3.eth().transaction_receipt(*hash).await? {
+            mined = Some(receipt);
+            break;
+        }
+    }
+
+    match mined {
+        Some(receipt) => {
+            let block_number = receipt.block_number.map(|block_number| block_number.as_u64());
+            record.mined_hash = Some(format!("{:?}", receipt.transaction_hash));
+            record.block_number = block_number;
+            record.gas_used = receipt.gas_used.map(|gas_used| gas_used.as_u64());
+            record.effective_gas_price = receipt.effective_gas_price;
+            record.gas_cost_wei = receipt.gas_used.zip(receipt.effective_gas_price).map(|(gas_used, price)| gas_used * price);
+            record.confirmations = block_number.map_or(0, |block_number| head.saturating_sub(block_number) + 1);
+            // Receipts without a status predate Byzantium, when failures could not be told apart
+            record.status = match receipt.status.map(|status| status.as_u64()) {
+                Some(0) => TxStatus::Reverted,
+                _ => TxStatus::Mined,
+            };
+            if !record.batch.is_empty() {
+                placed = Some(placed_order_ids(&receipt.logs, venue.contract_address));
+            }
+        },
+        None => {
+            // Also covers a mined transaction whose block was reorganised away
+            let mut known = false;
+            for hash in &hashes {
+                known |= web3.eth().transaction(TransactionId::Hash(*hash)).await?.is_some();
+            }
+            let last_sent_at = record.last_sent_at.unwrap_or(record.submitted_at);
+            let waiting = (Utc::now() - last_sent_at).num_seconds();
+            record.status = if !known && waiting > config.tx_drop_seconds as i64 {
+                TxStatus::Dropped
+            } else {
+                TxStatus::Pending
+            };
+            record.mined_hash = None;
+            record.block_number = None;
+            record.gas_used = None;
+            record.effective_gas_price = None;
+            record.gas_cost_wei = None;
+            record.confirmations = 0;
+
+            let stuck = known
+                && config.fee_bump_after_seconds > 0
+                && waiting >= config.fee_bump_after_seconds as i64
+                && (record.replacements.len() as u64) < config.max_fee_bumps;
+            if stuck {
+                let newest = *hashes.last()
+                    .ok_or_else(|| web3::Error::from(format!("Transaction {} has no hashes", record.tx_hash)))?;
+                if let Err(e) = bump_fees(venue, config, signer, &mut record, newest).await {
+                    error!("Could not bump fees of order {}: {:?}", record.order_id, e);
+                }
+            }
+        },
+    }
+    record.updated_at = Utc::now();
+    save_tx_record(con, &record).await.map_err(|e| web3::Error::from(e.to_string()))?;
+
+    if record.status != previous {
+        let order_status = match record.status {
+            TxStatus::Pending => OrderStatus::Submitted,
+            TxStatus::Mined => OrderStatus::Accepted,
+            // A failed batch's orders are still in flight, resubmitted one by one
+            TxStatus::Reverted | TxStatus::Dropped if !record.batch.is_empty() => OrderStatus::Submitted,
+            TxStatus::Reverted | TxStatus::Dropped => OrderStatus::Rejected,
+        };
+        match record.status {
+            TxStatus::Reverted | TxStatus::Dropped if !record.batch.is_empty() => warn!(
+                "Batch {} failed: transaction {} {:?}; resubmitting its {} orders singly",
+                record.order_id, record.tx_hash, record.status, record.batch.len()
+            ),
+            TxStatus::Reverted | TxStatus::Dropped => warn!(
+                "Order {} rejected: transaction {} {:?}", record.order_id, record.tx_hash, record.status
+            ),
+            _ => info!("Transaction {} for order {} is {:?}", record.tx_hash, record.order_id, record.status),
+        }
+        // Only the user state writes take the lock; the RPCs above run without it. A
+        // failed write leaves the transaction pending, so the next poll retries it.
+        let _state = data.lock().await;
+        if record.batch.is_empty() {
+            update_order(con, &record, &record.order_id, &record.username, order_status).await
+                .map_err(|e| web3::Error::from(e.to_string()))?;
+        }
+        for queued in &record.batch {
+            // The contract skips orders it cannot place without failing the rest of the batch
+            let order_status = match &placed {
+                Some(placed) if !placed.contains(&queued.order_id) => OrderStatus::Rejected,
+                _ => order_status,
+            };
+            update_order(con, &record, &queued.order_id, &queued.username, order_status).await
+                .map_err(|e| web3::Error::from(e.to_string()))?;
+        }
+    }
+
+    Ok(record)
+}
+
+// Polls the receipts of pending order transactions until each is confirmed, reverted
+// or dropped
+pub async fn run_receipt_tracker(data: web::Data<AsyncMutex<AppState>>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(2));
+    loop {
+        interval.tick().await;
+
+        // Only the handles are needed, so the AppState lock is not held across RPCs
+        let (venues, redis_client, config, signer) = {
+            let state = data.lock().await;
+            (state.venues.clone(), state.redis_client.clone(), state.config.clone(), state.signer.clone())
+        };
+        let mut con = match redis_client.get_multiplexed_async_connection().await {
+            Ok(con) => con,
+            Err(e) => {
+                error!("Receipt tracker could not connect to Redis: {:?}", e);
+                continue;
+            }
+        };
+
+        let pending: Vec<String> = con.smembers(PENDING_TXS_KEY).await.unwrap_or_default();
+        if pending.is_empty() {
+            continue;
+        }
+        // Chain heads, read once per venue per round
+        let mut heads: HashMap<String, u64> = HashMap::new();
+
+        for tx_hash in pending {
+            let record = match get_tx_record(&mut con, &tx_hash).await {
+                Ok(Some(record)) => record,
+                Err(e) => {
+                    error!("Receipt tracker could not read transaction {}: {}", tx_hash, e);
+                    continue;
+                },
+                Ok(None) => {
+                    if let Err(e) = con.srem::<_, _, ()>(PENDING_TXS_KEY, &tx_hash).await {
+                        error!("Could not untrack unknown transaction {}: {:?}", tx_hash, e);
+                    }
+                    continue;
+                }
+            };
+            let venue = match venues.get(&record.venue) {
+                Some(venue) => venue.clone(),
+                None => {
+                    error!("Transaction {} was sent to unknown venue {}", tx_hash, record.venue);
+                    continue;
+                }
+            };
+            let head = match heads.get(&venue.name) {
+                Some(head) => *head,
+                None => {
+                    let head = match venue.web3() {
+                        Ok(web3) => web3.eth().block_number().await,
+                        Err(e) => Err(e),
+                    };
+                    match head {
+                        Ok(head) => *heads.entry(venue.name.clone()).or_insert(head.as_u64()),
+                        Err(e) => {
+                            error!("Receipt tracker could not read the block number of {}: {:?}", venue.name, e);
+                            continue;
+                        }
+                    }
+                },
+            };
+            match poll_transaction(&data, &venue, &config, signer.as_deref(), &mut con, head, record).await {
+                Ok(record) if is_final(&record, &config) => {
+                    // Left pending if this fails, so the follow-up below runs once on a later round
+                    if let Err(e) = con.srem::<_, _, ()>(PENDING_TXS_KEY, &tx_hash).await {
+                        error!("Could not untrack final transaction {}: {:?}", tx_hash, e);
+                        continue;
+                    }
+                    if record.status != TxStatus::Dropped {
+                        if let Err(e) = record_gas_metrics(&mut con, &record).await {
+                            error!("Could not record gas metrics of transaction {}: {}", tx_hash, e);
+                        }
+                    }
+                    // A dropped transaction leaves a nonce gap the next order has to fill
+                    if let (TxStatus::Dropped, Some(signer)) = (record.status, &signer) {
+                        signer.nonces.resync(venue.chain_id(), record.from).await;
+                    }
+                    if matches!(record.status, TxStatus::Reverted | TxStatus::Dropped) && !record.batch.is_empty() {
+                        if let Err(e) = resubmit_singly(&mut con, &record.batch).await {
+                            error!("Could not resubmit the orders of {}: {}", record.order_id, e);
+                        }
+                    }
+                },
+                Ok(_) => {},
+                Err(e) => error!("Error polling receipt of {}: {:?}", tx_hash, e),
+            }
+        }
+    }
+}
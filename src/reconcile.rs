@@ -0,0 +1,376 @@
+use actix_web::web;
+use chrono::Utc;
+use log::{error, info, warn};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::Mutex as AsyncMutex;
+use web3::types::{BlockNumber, FilterBuilder, U256};
+
+use crate::bindings::events::OrderMatched;
+use crate::bindings::functions::{GetBuyOrder, GetSellOrder, OrderCount};
+use crate::bindings::{query, Event, Order as BookOrder};
+use crate::config::DEFAULT_VENUE;
+use crate::errors::ApiError;
+use crate::events::{load_checkpoint, log_position, parse_log, settle_fill};
+use crate::models::{Discrepancy, DiscrepancyKind, FillSource, Order, OrderSide, OrderStatus, ReconciliationReport};
+use crate::orders::order_book;
+use crate::state::AppState;
+use crate::users::{load_user_state, save_user_state, USER_IDS_KEY};
+use crate::venues::Venue;
+
+// The latest report, manual or periodic
+pub const RECONCILIATION_KEY: &str = "reconciliation_report";
+
+// An order as a venue contract's book holds it; quantity is what is still unmatched
+struct ChainOrder {
+    venue: String,
+    id: u64,
+    side: OrderSide,
+    symbol: String,
+    quantity: u64,
+    price: u64,
+    order_id: String,
+}
+
+// A settled fill from the order history
+#[derive(Deserialize)]
+struct RecordedFill {
+    #[serde(default)]
+    source: FillSource,
+    // Fills recorded before venues were named all came from the default one
+    #[serde(default = "default_venue")]
+    venue: String,
+    buy_order_id: U256,
+    sell_order_id: U256,
+    quantity: u64,
+    buyer_order_id: String,
+    seller_order_id: String,
+}
+
+fn default_venue() -> String {
+    DEFAULT_VENUE.to_string()
+}
+
+fn chain_order(venue: &Venue, side: OrderSide, order: BookOrder) -> Option<ChainOrder> {
+    // Ids without an order on this side come back zeroed
+    if order.id.is_zero() {
+        return None;
+    }
+    Some(ChainOrder {
+        venue: venue.name.clone(),
+        id: order.id.as_u64(),
+        side,
+        symbol: order.symbol,
+        quantity: order.quantity.as_u64(),
+        price: order.price.as_u64(),
+        order_id: order.order_id,
+    })
+}
+
+// Reads the orders in the contract's book from id `first` on, returning them with the
+// last id read. Buy and sell orders share one id sequence, so each id is one or the other.
+async fn read_book(venue: &Venue, first: u64) -> Result<(Vec<ChainOrder>, u64), web3::contract::Error> {
+    let contract = order_book(&venue.web3()?, venue.contract_address);
+    let count = query(&contract, OrderCount).await?.as_u64();
+
+    let mut book = vec![];
+    for id in first..=count {
+        let buy = query(&contract, GetBuyOrder { id: U256::from(id) }).await?;
+        if let Some(order) = chain_order(venue, OrderSide::Buy, buy) {
+            book.push(order);
+            continue;
+        }
+        let sell = query(&contract, GetSellOrder { id: U256::from(id) }).await?;
+        if let Some(order) = chain_order(venue, OrderSide::Sell, sell) {
+            book.push(order);
+        }
+    }
+    Ok((book, count))
+}
+
+async fn recorded_fills(con: &mut MultiplexedConnection) -> Result<Vec<RecordedFill>, ApiError> {
+    let history: Vec<String> = con.lrange("order_history", 0, -1).await?;
+    Ok(history.iter().filter_map(|fill| serde_json::from_str(fill).ok()).collect())
+}
+
+fn found(kind: DiscrepancyKind, order_id: &str, username: Option<&str>, contract_order_id: Option<u64>, detail: String) -> Discrepancy {
+    Discrepancy {
+        kind,
+        order_id: Some(order_id.to_string()),
+        username: username.map(|username| username.to_string()),
+        contract_order_id,
+        detail,
+        repaired: false,
+    }
+}
+
+// Settles a venue contract's OrderMatched events for `orders` that never reached the
+// order history. Only events up to the venue's listener checkpoint are considered, as
+// later ones are still on their way through the listener. Returns the orders that got
+// a fill.
+async fn settle_missed_fills(
+    state: &AppState,
+    venue: &Venue,
+    con: &mut MultiplexedConnection,
+    fills: &[RecordedFill],
+    orders: &HashSet<String>,
+) -> Result<HashSet<String>, ApiError> {
+    let mut repaired = HashSet::new();
+    let checkpoint = match load_checkpoint(con, &venue.name).await? {
+        Some(checkpoint) => checkpoint,
+        None => return Ok(repaired),
+    };
+    let settled: HashSet<(U256, U256)> = fills.iter()
+        .filter(|fill| fill.source == FillSource::Contract && fill.venue == venue.name)
+        .map(|fill| (fill.buy_order_id, fill.sell_order_id))
+        .collect();
+
+    let filter = FilterBuilder::default()
+        .address(vec![venue.contract_address])
+        .topics(Some(vec![OrderMatched::topic()]), None, None, None)
+        .from_block(BlockNumber::Earliest)
+        .to_block(BlockNumber::Number(checkpoint.0.into()))
+        .build();
+
+    for log in venue.web3()?.eth().logs(filter).await? {
+        if log_position(&log).is_none_or(|position| position > checkpoint) {
+            continue;
+        }
+        let event = match parse_log(venue, log) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        // A buy and sell order pair can only match once on a venue
+        if settled.contains(&(event.buy_order_id, event.sell_order_id)) {
+            continue;
+        }
+        if !orders.contains(&event.buyer_order_id) && !orders.contains(&event.seller_order_id) {
+            continue;
+        }
+        warn!("Settling missed fill of {} and {}", event.buyer_order_id, event.seller_order_id);
+        if settle_fill(state, con, &event).await? {
+            repaired.insert(event.buyer_order_id.clone());
+            repaired.insert(event.seller_order_id.clone());
+        }
+    }
+    Ok(repaired)
+}
+
+// Compares every venue contract's book with the Redis order records and each user's orders.
+// With `repair`, also fixes what Redis alone can: missing or stale copies of orders, the
+// status of orders the contract does or does not hold, and unsettled fills. Orders
+// still submitted are in flight and only checked once their transaction is final.
+pub async fn reconcile(data: &web::Data<AsyncMutex<AppState>>, repair: bool) -> Result<ReconciliationReport, ApiError> {
+    let started_at = Utc::now();
+
+    // The books are read without the AppState lock, as they take two calls per order
+    let venues = data.lock().await.venues.clone();
+    let mut book = vec![];
+    let mut read_up_to = HashMap::new();
+    for venue in venues.all() {
+        let (orders, count) = read_book(venue, 1).await?;
+        book.extend(orders);
+        read_up_to.insert(venue.name.clone(), count);
+    }
+
+    // Orders accepted while the books were read are picked up under the lock, which
+    // receipts need to mark an order accepted, so none is mistaken for an orphan
+    let state = data.lock().await;
+    for venue in venues.all() {
+        let (orders, _) = read_book(venue, read_up_to[&venue.name] + 1).await?;
+        book.extend(orders);
+    }
+    let chain_orders: HashMap<&str, &ChainOrder> = book.iter().map(|chain| (chain.order_id.as_str(), chain)).collect();
+
+    let mut con = state.redis_client.get_multiplexed_async_connection().await?;
+
+    // Auction fills are settled off-chain and never reach the contract, so they neither
+    // count toward an order's contract fills nor leave it on the book
+    let fills = recorded_fills(&mut con).await?;
+    let mut settled: HashMap<(&str, &str, u64), u64> = HashMap::new();
+    let mut auctioned: HashSet<&str> = HashSet::new();
+    for fill in &fills {
+        if fill.source != FillSource::Contract {
+            auctioned.insert(fill.buyer_order_id.as_str());
+            auctioned.insert(fill.seller_order_id.as_str());
+            continue;
+        }
+        *settled.entry((fill.buyer_order_id.as_str(), fill.venue.as_str(), fill.buy_order_id.as_u64())).or_insert(0) += fill.quantity;
+        *settled.entry((fill.seller_order_id.as_str(), fill.venue.as_str(), fill.sell_order_id.as_u64())).or_insert(0) += fill.quantity;
+    }
+
+    let mut discrepancies = vec![];
+    let mut redis_orders = 0;
+    let mut seen = HashSet::new();
+    let mut missed_fills: HashMap<&str, HashSet<String>> = HashMap::new();
+
+    let usernames: Vec<String> = con.hvals(USER_IDS_KEY).await?;
+    for username in usernames {
+        let mut user_state = match load_user_state(&mut con, &username).await? {
+            Some(user_state) => user_state,
+            None => continue,
+        };
+        let mut user_changed = false;
+
+        for user_order in user_state.orders.iter_mut() {
+            redis_orders += 1;
+            let order_json: Option<String> = con.get(&user_order.order_id).await?;
+            let mut order: Order = match order_json {
+                Some(order_json) => serde_json::from_str(&order_json)?,
+                None => {
+                    let mut discrepancy = found(
+                        DiscrepancyKind::MissingOrderRecord, &user_order.order_id, Some(&username), None,
+                        "Order record is missing; restored from the user's copy".to_string(),
+                    );
+                    if repair {
+                        let _: () = con.set(&user_order.order_id, serde_json::to_string(&*user_order)?).await?;
+                        discrepancy.repaired = true;
+                    }
+                    discrepancies.push(discrepancy);
+                    user_order.clone()
+                },
+            };
+
+            if order.status != user_order.status {
+                let mut discrepancy = found(
+                    DiscrepancyKind::StaleUserOrder, &order.order_id, Some(&username), None,
+                    format!("User's copy is {:?} but the order record is {:?}", user_order.status, order.status),
+                );
+                if repair {
+                    *user_order = order.clone();
+                    user_changed = true;
+                    discrepancy.repaired = true;
+                }
+                discrepancies.push(discrepancy);
+            }
+
+            let new_status = match chain_orders.get(order.order_id.as_str()) {
+                Some(chain) => {
+                    seen.insert((chain.venue.as_str(), chain.id));
+                    if order.symbol != chain.symbol || order.price as u64 != chain.price || chain.quantity > order.quantity as u64 {
+                        discrepancies.push(found(
+                            DiscrepancyKind::MismatchedOrder, &order.order_id, Some(&username), Some(chain.id),
+                            format!(
+                                "Redis has {} {} @ {}, the contract {} left of {} @ {}",
+                                order.quantity, order.symbol, order.price, chain.quantity, chain.symbol, chain.price
+                            ),
+                        ));
+                    }
+
+                    let filled = (order.quantity as u64).saturating_sub(chain.quantity);
+                    let recorded = settled.get(&(order.order_id.as_str(), chain.venue.as_str(), chain.id)).copied().unwrap_or(0);
+                    if filled > recorded {
+                        missed_fills.entry(chain.venue.as_str()).or_default().insert(order.order_id.clone());
+                        discrepancies.push(found(
+                            DiscrepancyKind::MissedFill, &order.order_id, Some(&username), Some(chain.id),
+                            format!("Contract filled {} but {} is settled", filled, recorded),
+                        ));
+                    } else if recorded > filled {
+                        discrepancies.push(found(
+                            DiscrepancyKind::ExcessFill, &order.order_id, Some(&username), Some(chain.id),
+                            format!("Contract filled {} but {} is settled", filled, recorded),
+                        ));
+                    }
+
+                    if order.status == OrderStatus::Rejected {
+                        discrepancies.push(found(
+                            DiscrepancyKind::UnconfirmedOrder, &order.order_id, Some(&username), Some(chain.id),
+                            "Rejected, but the contract holds the order".to_string(),
+                        ));
+                        Some(OrderStatus::Accepted)
+                    } else {
+                        None
+                    }
+                },
+                None if order.status == OrderStatus::Accepted && !auctioned.contains(order.order_id.as_str()) => {
+                    discrepancies.push(found(
+                        DiscrepancyKind::OrphanedOrder, &order.order_id, Some(&username), None,
+                        "Accepted, but the contract does not hold the order".to_string(),
+                    ));
+                    Some(OrderStatus::Rejected)
+                },
+                None => None,
+            };
+
+            if let (true, Some(new_status)) = (repair, new_status) {
+                order.status = new_status;
+                let _: () = con.set(&order.order_id, serde_json::to_string(&order)?).await?;
+                *user_order = order;
+                user_changed = true;
+                discrepancies.last_mut().unwrap().repaired = true;
+            }
+        }
+
+        if user_changed {
+            save_user_state(&mut con, &user_state).await?;
+        }
+    }
+
+    for chain in book.iter().filter(|chain| !seen.contains(&(chain.venue.as_str(), chain.id))) {
+        discrepancies.push(found(
+            DiscrepancyKind::UnknownChainOrder, &chain.order_id, None, Some(chain.id),
+            format!(
+                "{:?} order on venue {} with {} {} @ {} left and no Redis order",
+                chain.side, chain.venue, chain.quantity, chain.symbol, chain.price
+            ),
+        ));
+    }
+
+    if repair && !missed_fills.is_empty() {
+        let mut repaired = HashSet::new();
+        for (name, orders) in &missed_fills {
+            if let Some(venue) = venues.get(name) {
+                repaired.extend(settle_missed_fills(&state, venue, &mut con, &fills, orders).await?);
+            }
+        }
+        for discrepancy in discrepancies.iter_mut().filter(|discrepancy| discrepancy.kind == DiscrepancyKind::MissedFill) {
+            discrepancy.repaired = discrepancy.order_id.as_ref().is_some_and(|order_id| repaired.contains(order_id));
+        }
+    }
+
+    let report = ReconciliationReport {
+        started_at,
+        finished_at: Utc::now(),
+        repair,
+        contract_orders: book.len() as u64,
+        redis_orders,
+        discrepancies,
+    };
+    let _: () = con.set(RECONCILIATION_KEY, serde_json::to_string(&report)?).await?;
+
+    if report.discrepancies.is_empty() {
+        info!("Reconciled {} contract orders with {} Redis orders", report.contract_orders, report.redis_orders);
+    } else {
+        warn!(
+            "Reconciliation found {} discrepancies ({} repaired)",
+            report.discrepancies.len(),
+            report.discrepancies.iter().filter(|discrepancy| discrepancy.repaired).count()
+        );
+    }
+    Ok(report)
+}
+
+pub async fn latest_report(con: &mut MultiplexedConnection) -> Result<Option<ReconciliationReport>, ApiError> {
+    let report_json: Option<String> = con.get(RECONCILIATION_KEY).await?;
+    Ok(report_json.map(|report_json| serde_json::from_str(&report_json)).transpose()?)
+}
+
+// Reconciles every reconcile_interval_seconds, repairing if reconcile_repair is set.
+// Not started when the interval is 0.
+pub async fn run_reconciler(data: web::Data<AsyncMutex<AppState>>) {
+    let (every, repair) = {
+        let state = data.lock().await;
+        (state.config.reconcile_interval_seconds, state.config.reconcile_repair)
+    };
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(every));
+    // The first tick is immediate; give the listener a period to catch up first
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = reconcile(&data, repair).await {
+            error!("Reconciliation failed: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use actix_web::web;
+use chrono::{DateTime, Datelike, Duration, Utc};
+use log::{error, info};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::auction::run_auction;
+use crate::kill_switch::fire_cancel_on_disconnect;
+use crate::errors::ApiError;
+use crate::instruments::{get_instrument, save_instrument};
+use crate::models::{CircuitBreaker, Halt, OrderStatus, QueuedOrder, SessionCalendar, SessionPhase, TradingStatus};
+use crate::orders::{record_order, submit_order};
+use crate::state::AppState;
+
+// Redis hashes keyed by symbol
+pub const CALENDARS_KEY: &str = "session_calendars";
+pub const CIRCUIT_BREAKERS_KEY: &str = "circuit_breakers";
+pub const HALTS_KEY: &str = "halts";
+// Price circuit breaker moves are measured from, reset whenever trading resumes
+pub const REFERENCE_PRICE_KEY: &str = "reference_price";
+// Phase each calendar symbol was in at the scheduler's previous tick
+pub const SESSION_PHASE_KEY: &str = "session_phase";
+
+pub fn queued_orders_key(symbol: &str) -> String {
+    format!("queued_orders:{}", symbol)
+}
+
+pub fn phase_at(calendar: &SessionCalendar, now: DateTime<Utc>) -> SessionPhase {
+    if !calendar.trading_days.contains(&now.weekday().number_from_monday()) {
+        return SessionPhase::Closed;
+    }
+
+    let time = now.time();
+    if time < calendar.pre_open {
+        SessionPhase::Closed
+    } else if time < calendar.opening_auction {
+        SessionPhase::PreOpen
+    } else if time < calendar.continuous {
+        SessionPhase::OpeningAuction
+    } else if time < calendar.closing_auction {
+        SessionPhase::Continuous
+    } else if time < calendar.close {
+        SessionPhase::ClosingAuction
+    } else {
+        SessionPhase::Closed
+    }
+}
+
+pub async fn get_calendar(con: &mut MultiplexedConnection, symbol: &str) -> Result<Option<SessionCalendar>, ApiError> {
+    let calendar_json: Option<String> = con.hget(CALENDARS_KEY, symbol).await?;
+    Ok(calendar_json.map(|calendar_json| serde_json::from_str(&calendar_json)).transpose()?)
+}
+
+pub async fn save_calendar(con: &mut MultiplexedConnection, calendar: &SessionCalendar) -> Result<(), ApiError> {
+    let _: () = con.hset(CALENDARS_KEY, &calendar.symbol, serde_json::to_string(calendar)?).await?;
+    Ok(())
+}
+
+// Symbols without a calendar trade continuously around the clock
+pub async fn trading_phase(con: &mut MultiplexedConnection, symbol: &str) -> Result<SessionPhase, ApiError> {
+    Ok(match get_calendar(con, symbol).await? {
+        Some(calendar) => phase_at(&calendar, Utc::now()),
+        None => SessionPhase::Continuous,
+    })
+}
+
+pub fn validate_calendar(calendar: &SessionCalendar) -> Result<(), String> {
+    let times = [calendar.pre_open, calendar.opening_auction, calendar.continuous, calendar.closing_auction, calendar.close];
+    if times.windows(2).any(|pair| pair[0] > pair[1]) {
+        return Err("Session times must be in order: pre_open, opening_auction, continuous, closing_auction, close".to_string());
+    }
+    if calendar.trading_days.iter().any(|day| *day < 1 || *day > 7) {
+        return Err("Trading days must be ISO weekdays between 1 (Monday) and 7 (Sunday)".to_string());
+    }
+    Ok(())
+}
+
+pub async fn get_halt(con: &mut MultiplexedConnection, symbol: &str) -> Result<Option<Halt>, ApiError> {
+    let halt_json: Option<String> = con.hget(HALTS_KEY, symbol).await?;
+    Ok(halt_json.map(|halt_json| serde_json::from_str(&halt_json)).transpose()?)
+}
+
+// Halts trading in the symbol; the instrument status is the flag orders are checked
+// against, the halt record only carries the reason and optional resume time.
+// Returns false if the symbol is not a registered instrument.
+pub async fn halt_symbol(
+    con: &mut MultiplexedConnection,
+    symbol: &str,
+    reason: &str,
+    resume_at: Option<DateTime<Utc>>,
+) -> Result<bool, ApiError> {
+    let mut instrument = match get_instrument(con, symbol).await? {
+        Some(instrument) => instrument,
+        None => return Ok(false),
+    };
+    instrument.status = TradingStatus::Halted;
+    save_instrument(con, &instrument).await?;
+
+    let halt = Halt {
+        symbol: symbol.to_string(),
+        reason: reason.to_string(),
+        halted_at: Utc::now(),
+        resume_at,
+    };
+    let _: () = con.hset(HALTS_KEY, symbol, serde_json::to_string(&halt)?).await?;
+
+    info!("Trading halted for {}: {}", symbol, reason);
+    Ok(true)
+}
+
+// Returns false if the symbol was not halted
+pub async fn resume_symbol(con: &mut MultiplexedConnection, symbol: &str) -> Result<bool, ApiError> {
+    let mut instrument = match get_instrument(con, symbol).await? {
+        Some(instrument) if instrument.status == TradingStatus::Halted => instrument,
+        _ => return Ok(false),
+    };
+    instrument.status = TradingStatus::Active;
+    save_instrument(con, &instrument).await?;
+
+    let _: () = con.hdel(HALTS_KEY, symbol).await?;
+    let _: () = con.hdel(REFERENCE_PRICE_KEY, symbol).await?;
+
+    info!("Trading resumed for {}", symbol);
+    Ok(true)
+}
+
+// Lifts a timed halt once its resume time has passed
+pub async fn refresh_halt(con: &mut MultiplexedConnection, symbol: &str) -> Result<(), ApiError> {
+    if let Some(halt) = get_halt(con, symbol).await? {
+        if halt.resume_at.is_some_and(|resume_at| resume_at <= Utc::now()) {
+            resume_symbol(con, symbol).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn save_circuit_breaker(con: &mut MultiplexedConnection, breaker: &CircuitBreaker) -> Result<(), ApiError> {
+    let _: () = con.hset(CIRCUIT_BREAKERS_KEY, &breaker.symbol, serde_json::to_string(breaker)?).await?;
+    Ok(())
+}
+
+// Called for every fill. The first fill after trading (re)starts sets the reference
+// price; a later fill too far from it halts the symbol for the configured time.
+pub async fn check_circuit_breaker(con: &mut MultiplexedConnection, symbol: &str, price: f64) -> Result<(), ApiError> {
+    let breaker_json: Option<String> = con.hget(CIRCUIT_BREAKERS_KEY, symbol).await?;
+    let breaker: CircuitBreaker = match breaker_json {
+        Some(breaker_json) => serde_json::from_str(&breaker_json)?,
+        None => return Ok(()),
+    };
+
+    let reference: Option<f64> = con.hget(REFERENCE_PRICE_KEY, symbol).await?;
+    let reference = match reference {
+        Some(reference) if reference > 0.0 => reference,
+        _ => {
+            let _: () = con.hset(REFERENCE_PRICE_KEY, symbol, price).await?;
+            return Ok(());
+        }
+    };
+
+    let move_pct = (price - reference).abs() / reference * 100.0;
+    if move_pct > breaker.max_move_pct {
+        let reason = format!("Circuit breaker: {:.2}% move from reference price {}", move_pct, reference);
+        halt_symbol(con, symbol, &reason, Some(Utc::now() + Duration::seconds(breaker.halt_seconds))).await?;
+    }
+    Ok(())
+}
+
+pub async fn queue_order(con: &mut MultiplexedConnection, queued: &QueuedOrder) -> Result<(), ApiError> {
+    let _: () = con.rpush(queued_orders_key(&queued.order.symbol), serde_json::to_string(queued)?).await?;
+    Ok(())
+}
+
+pub async fn queued_orders(con: &mut MultiplexedConnection, symbol: &str) -> Result<Vec<QueuedOrder>, ApiError> {
+    let queued: Vec<String> = con.lrange(queued_orders_key(symbol), 0, -1).await?;
+    Ok(queued.iter().map(|queued| serde_json::from_str(queued)).collect::<Result<_, _>>()?)
+}
+
+// Submits the symbol's queued orders to the contract in arrival order
+async fn release_queued_orders(
+    data: &web::Data<AsyncMutex<AppState>>,
+    state: &AppState,
+    con: &mut MultiplexedConnection,
+    symbol: &str,
+) -> Result<(), ApiError> {
+    loop {
+        let queued_json: Option<String> = con.lpop(queued_orders_key(symbol), None).await?;
+        let queued: QueuedOrder = match queued_json {
+            Some(queued_json) => serde_json::from_str(&queued_json)?,
+            None => break,
+        };
+
+        // The order never reached the contract, so the user sees it rejected rather than lost
+        if let Err(e) = submit_order(data, state, con, &queued.username, &queued.order_id, &queued.order, queued.side).await {
+            error!("Error releasing queued order {}: {:?}", queued.order_id, e);
+            let _state = data.lock().await;
+            record_order(con, &queued.username, &queued.order_id, &queued.order, OrderStatus::Rejected).await?;
+        }
+    }
+    Ok(())
+}
+
+// Moves the symbol to its current phase, uncrossing the call auction that just ended and
+// releasing queued orders once it trades continuously
+async fn advance_session(
+    data: &web::Data<AsyncMutex<AppState>>,
+    state: &AppState,
+    con: &mut MultiplexedConnection,
+    symbol: &str,
+) -> Result<(), ApiError> {
+    let calendar = match get_calendar(con, symbol).await? {
+        Some(calendar) => calendar,
+        None => return Ok(()),
+    };
+    let phase = phase_at(&calendar, Utc::now());
+    let active = get_instrument(con, symbol).await?
+        .is_some_and(|instrument| instrument.status == TradingStatus::Active);
+
+    let previous_json: Option<String> = con.hget(SESSION_PHASE_KEY, symbol).await?;
+    let previous: Option<SessionPhase> = previous_json.map(|previous_json| serde_json::from_str(&previous_json)).transpose()?;
+
+    // A halted symbol keeps its call book until trading resumes
+    if let Some(previous) = previous {
+        let auction_ended = matches!(previous, SessionPhase::OpeningAuction | SessionPhase::ClosingAuction) && previous != phase;
+        if auction_ended && calendar.auctions && !active {
+            return Ok(());
+        }
+        if auction_ended && calendar.auctions {
+            let _state = data.lock().await;
+            run_auction(state, con, symbol, previous).await?;
+        }
+    }
+    let _: () = con.hset(SESSION_PHASE_KEY, symbol, serde_json::to_string(&phase)?).await?;
+
+    if active && phase == SessionPhase::Continuous {
+        release_queued_orders(data, state, con, symbol).await?;
+    }
+    Ok(())
+}
+
+// Fires lapsed cancel-on-disconnect timers, lifts expired halts, uncrosses call auctions as their phase ends and releases
+// queued orders once a symbol enters continuous trading
+pub async fn run_session_scheduler(data: web::Data<AsyncMutex<AppState>>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        // A copy of the handles; the lock is taken for user state writes, not across the
+        // RPCs that release queued orders
+        let state = data.lock().await.clone();
+        let mut con = match state.redis_client.get_multiplexed_async_connection().await {
+            Ok(con) => con,
+            Err(e) => {
+                error!("Session scheduler could not connect to Redis: {:?}", e);
+                continue;
+            }
+        };
+
+        if let Err(e) = fire_cancel_on_disconnect(&data, &state, &mut con).await {
+            error!("Error firing cancel-on-disconnect timers: {}", e);
+        }
+
+        let halted: Vec<String> = con.hkeys(HALTS_KEY).await.unwrap_or_default();
+        for symbol in halted {
+            if let Err(e) = refresh_halt(&mut con, &symbol).await {
+                error!("Error lifting the halt of {}: {}", symbol, e);
+            }
+        }
+
+        let symbols: Vec<String> = con.hkeys(CALENDARS_KEY).await.unwrap_or_default();
+        for symbol in symbols {
+            // Auctions and queued orders wait until the symbol's venue can settle fills
+            // again; the phase is left as it was so the change is picked up then
+            if !state.venues.for_symbol(&symbol).health.accepting_orders() {
+                continue;
+            }
+            if let Err(e) = advance_session(&data, &state, &mut con, &symbol).await {
+                error!("Error advancing the session of {}: {}", symbol, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, TimeZone};
+
+    fn at(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+    }
+
+    // Weekdays 08:00 pre-open, 09:00 opening auction, 09:30 continuous,
+    // 16:00 closing auction and 16:30 close
+    fn weekdays() -> SessionCalendar {
+        SessionCalendar {
+            symbol: "ETH".to_string(),
+            pre_open: at(8, 0),
+            opening_auction: at(9, 0),
+            continuous: at(9, 30),
+            closing_auction: at(16, 0),
+            close: at(16, 30),
+            trading_days: vec![1, 2, 3, 4, 5],
+            auctions: false,
+        }
+    }
+
+    // 2024-01-01 was a Monday
+    fn monday(hour: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, hour, min, sec).unwrap()
+    }
+
+    #[test]
+    fn each_phase_begins_at_its_time() {
+        let calendar = weekdays();
+        assert_eq!(phase_at(&calendar, monday(7, 59, 59)), SessionPhase::Closed);
+        assert_eq!(phase_at(&calendar, monday(8, 0, 0)), SessionPhase::PreOpen);
+        assert_eq!(phase_at(&calendar, monday(9, 0, 0)), SessionPhase::OpeningAuction);
+        assert_eq!(phase_at(&calendar, monday(9, 29, 59)), SessionPhase::OpeningAuction);
+        assert_eq!(phase_at(&calendar, monday(9, 30, 0)), SessionPhase::Continuous);
+        assert_eq!(phase_at(&calendar, monday(16, 0, 0)), SessionPhase::ClosingAuction);
+        assert_eq!(phase_at(&calendar, monday(16, 30, 0)), SessionPhase::Closed);
+    }
+
+    #[test]
+    fn closed_across_midnight() {
+        let calendar = weekdays();
+        assert_eq!(phase_at(&calendar, monday(23, 59, 59)), SessionPhase::Closed);
+        assert_eq!(phase_at(&calendar, monday(0, 0, 0)), SessionPhase::Closed);
+    }
+
+    #[test]
+    fn closed_on_days_off() {
+        let calendar = weekdays();
+        let saturday = Utc.with_ymd_and_hms(2024, 1, 6, 12, 0, 0).unwrap();
+        let sunday = Utc.with_ymd_and_hms(2024, 1, 7, 12, 0, 0).unwrap();
+        assert_eq!(phase_at(&calendar, saturday), SessionPhase::Closed);
+        assert_eq!(phase_at(&calendar, sunday), SessionPhase::Closed);
+
+        // A midweek holiday is a weekday left out of trading_days
+        let holiday = SessionCalendar { trading_days: vec![1, 2, 4, 5], ..weekdays() };
+        let wednesday = Utc.with_ymd_and_hms(2024, 1, 3, 12, 0, 0).unwrap();
+        assert_eq!(phase_at(&holiday, wednesday), SessionPhase::Closed);
+        assert_eq!(phase_at(&holiday, monday(12, 0, 0)), SessionPhase::Continuous);
+    }
+
+    #[test]
+    fn sunday_is_day_seven() {
+        let calendar = SessionCalendar { trading_days: vec![7], ..weekdays() };
+        let sunday = Utc.with_ymd_and_hms(2024, 1, 7, 12, 0, 0).unwrap();
+        assert_eq!(phase_at(&calendar, sunday), SessionPhase::Continuous);
+        assert_eq!(phase_at(&calendar, monday(12, 0, 0)), SessionPhase::Closed);
+    }
+
+    #[test]
+    fn auctions_can_be_skipped() {
+        // Zero-length auction phases go straight from pre-open to continuous and
+        // from continuous to closed
+        let calendar = SessionCalendar {
+            continuous: at(9, 0),
+            closing_auction: at(16, 30),
+            ..weekdays()
+        };
+        assert!(validate_calendar(&calendar).is_ok());
+        assert_eq!(phase_at(&calendar, monday(9, 0, 0)), SessionPhase::Continuous);
+        assert_eq!(phase_at(&calendar, monday(16, 29, 59)), SessionPhase::Continuous);
+        assert_eq!(phase_at(&calendar, monday(16, 30, 0)), SessionPhase::Closed);
+    }
+
+    #[test]
+    fn rejects_overnight_sessions() {
+        // 22:00 to 02:00 would wrap past midnight, which a daily calendar cannot express
+        let calendar = SessionCalendar {
+            pre_open: at(21, 0),
+            opening_auction: at(21, 30),
+            continuous: at(22, 0),
+            closing_auction: at(1, 30),
+            close: at(2, 0),
+            ..weekdays()
+        };
+        assert!(validate_calendar(&calendar).unwrap_err().contains("in order"));
+    }
+
+    #[test]
+    fn rejects_days_outside_the_week() {
+        assert!(validate_calendar(&weekdays()).is_ok());
+        assert!(validate_calendar(&SessionCalendar { trading_days: vec![0, 1], ..weekdays() }).is_err());
+        assert!(validate_calendar(&SessionCalendar { trading_days: vec![8], ..weekdays() }).is_err());
+    }
+}
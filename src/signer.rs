@@ -0,0 +1,69 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use tokio::sync::Mutex as AsyncMutex;
+use web3::signing::{keccak256, Key, SecretKey, SecretKeyRef};
+use web3::transports::WebSocket;
+use web3::types::{Address, BlockNumber, U256};
+use web3::Web3;
+
+// Hands out nonces per chain and sending account without asking the node each time, so
+// orders can be signed concurrently. The next nonce is seeded from the node's pending count
+// and reseeded after a send fails or a transaction is dropped, which lets the next
+// order reuse the nonce and fill the gap.
+#[derive(Default)]
+pub struct NonceManager {
+    next: AsyncMutex<HashMap<(u64, Address), U256>>,
+}
+
+impl NonceManager {
+    pub async fn next(&self, web3: &Web3<WebSocket>, chain_id: u64, account: Address) -> Result<U256, web3::Error> {
+        let mut next = self.next.lock().await;
+        let nonce = match next.get(&(chain_id, account)) {
+            Some(nonce) => *nonce,
+            None => web3.eth().transaction_count(account, Some(BlockNumber::Pending)).await?,
+        };
+        next.insert((chain_id, account), nonce + 1);
+        Ok(nonce)
+    }
+
+    pub async fn resync(&self, chain_id: u64, account: Address) {
+        if self.next.lock().await.remove(&(chain_id, account)).is_some() {
+            warn!("Nonces of {:?} on chain {} will be reseeded from the node", account, chain_id);
+        }
+    }
+}
+
+// Signs orders with keys held in-process instead of the node's unlocked account
+pub struct Signer {
+    master: SecretKey,
+    derive_user_accounts: bool,
+    pub nonces: NonceManager,
+}
+
+impl Signer {
+    pub fn new(master: SecretKey, derive_user_accounts: bool) -> Self {
+        let signer = Signer { master, derive_user_accounts, nonces: NonceManager::default() };
+        info!("Signing orders locally as {:?}{}", signer.address(), if derive_user_accounts { " with per-user accounts" } else { "" });
+        signer
+    }
+
+    pub fn address(&self) -> Address {
+        SecretKeyRef::new(&self.master).address()
+    }
+
+    // The key a user's orders are signed with. Derived keys are keccak256(master key ||
+    // username), so they can be recreated from the master key alone; each derived
+    // account must be funded with gas before it can trade.
+    pub fn key_for(&self, username: &str) -> SecretKey {
+        if !self.derive_user_accounts {
+            return self.master;
+        }
+        let mut seed = self.master.secret_bytes().to_vec();
+        seed.extend_from_slice(username.as_bytes());
+        SecretKey::from_slice(&keccak256(&seed)).unwrap()
+    }
+
+    pub fn account_for(&self, username: &str) -> Address {
+        SecretKeyRef::new(&self.key_for(username)).address()
+    }
+}
@@ -0,0 +1,16 @@
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::signer::Signer;
+use crate::venues::Venues;
+
+// Cloned by background tasks that must not hold the lock across RPCs
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Config,
+    pub redis_client: redis::Client,
+    // Where orders for each symbol are sent, with each venue's node and listener health
+    pub venues: Arc<Venues>,
+    // Set when orders are signed locally rather than by the node
+    pub signer: Option<Arc<Signer>>,
+}
@@ -0,0 +1,144 @@
+use actix_web::web;
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::events::listen_for_events;
+use crate::state::AppState;
+use crate::venues::Venue;
+
+// Liveness of the parts order entry depends on. Kept outside the AppState mutex so it
+// can be read without waiting on whoever holds the lock.
+#[derive(Default)]
+pub struct Health {
+    listener_up: AtomicBool,
+    shutting_down: AtomicBool,
+    in_flight: AtomicUsize,
+    listener_restarts: AtomicU64,
+    // Unix seconds of the last contract event applied, 0 before the first
+    last_event_at: AtomicI64,
+    // Latest block whose head the listener has seen, 0 before the first. An idle
+    // contract emits no logs, so this rather than the checkpoint shows it keeping up.
+    last_block: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthStatus {
+    pub listener_up: bool,
+    pub shutting_down: bool,
+    pub accepting_orders: bool,
+    pub in_flight_contract_calls: usize,
+    pub listener_restarts: u64,
+    pub last_event_at: Option<i64>,
+    pub last_block: Option<u64>,
+}
+
+impl Health {
+    pub fn listener_up(&self) -> bool {
+        self.listener_up.load(Ordering::SeqCst)
+    }
+
+    pub fn set_listener_up(&self, up: bool) {
+        self.listener_up.store(up, Ordering::SeqCst);
+    }
+
+    pub fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    // Orders are only taken while fills for them can be settled
+    pub fn accepting_orders(&self) -> bool {
+        self.listener_up() && !self.shutting_down()
+    }
+
+    pub fn record_event(&self) {
+        self.last_event_at.store(Utc::now().timestamp(), Ordering::SeqCst);
+    }
+
+    pub fn record_block(&self, block_number: u64) {
+        self.last_block.fetch_max(block_number, Ordering::SeqCst);
+    }
+
+    pub fn last_block(&self) -> Option<u64> {
+        Some(self.last_block.load(Ordering::SeqCst)).filter(|block_number| *block_number > 0)
+    }
+
+    // Counts a contract call as in flight until the returned guard is dropped
+    pub fn track(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        let last_event_at = self.last_event_at.load(Ordering::SeqCst);
+        HealthStatus {
+            listener_up: self.listener_up(),
+            shutting_down: self.shutting_down(),
+            accepting_orders: self.accepting_orders(),
+            in_flight_contract_calls: self.in_flight.load(Ordering::SeqCst),
+            listener_restarts: self.listener_restarts.load(Ordering::SeqCst),
+            last_event_at: if last_event_at > 0 { Some(last_event_at) } else { None },
+            last_block: self.last_block(),
+        }
+    }
+
+    // Waits for in-flight contract calls to finish, returning false on timeout
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        true
+    }
+}
+
+pub struct InFlight(Arc<Health>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Runs a venue's event listener and restarts it whenever it returns, errors or panics,
+// backing off exponentially up to 30s. The listener resumes from its checkpoint, so
+// fills emitted while it was down are applied on restart.
+pub async fn supervise_listener(data: web::Data<AsyncMutex<AppState>>, venue: Arc<Venue>) {
+    let health = venue.health.clone();
+    let mut backoff = 1;
+    while !health.shutting_down() {
+        let started = Instant::now();
+        let result = tokio::spawn(listen_for_events(data.clone(), venue.clone())).await;
+        health.set_listener_up(false);
+
+        match result {
+            Ok(Ok(())) => warn!("Event stream of {} ended", venue.name),
+            Ok(Err(e)) => error!("Event listener for {} failed: {:?}", venue.name, e),
+            Err(e) => error!("Event listener for {} panicked: {:?}", venue.name, e),
+        }
+        if health.shutting_down() {
+            break;
+        }
+
+        // A listener that stayed up for a while earns a fast restart again
+        if started.elapsed() > Duration::from_secs(60) {
+            backoff = 1;
+        }
+        health.listener_restarts.fetch_add(1, Ordering::SeqCst);
+        warn!("Restarting event listener for {} in {}s; its order entry is suspended until it is back", venue.name, backoff);
+        tokio::time::sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(30);
+    }
+    info!("Event listener supervisor for {} stopped", venue.name);
+}
@@ -0,0 +1,161 @@
+use chrono::Utc;
+use jsonwebtoken::{decode, decode_header, encode, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::errors::ApiError;
+use crate::models::{Claims, Role};
+
+// Signing keys by kid. Tokens are signed with the active key and verified with
+// whichever key their header names, so a key can be rotated out by first adding a
+// new active key and removing the old one once its tokens have expired. Built by
+// Config::resolve, which checks that the active kid has a secret.
+#[derive(Clone, Debug)]
+pub struct JwtKeys {
+    pub active_kid: String,
+    pub secrets: HashMap<String, String>,
+    // Seconds an access token is valid for
+    pub access_token_ttl: i64,
+}
+
+fn refresh_token_key(token: &str) -> String {
+    format!("refresh_token:{}", token)
+}
+
+fn revoked_key(jti: &str) -> String {
+    format!("revoked_token:{}", jti)
+}
+
+fn user_refresh_tokens_key(username: &str) -> String {
+    format!("refresh_tokens:{}", username)
+}
+
+fn tokens_revoked_at_key(username: &str) -> String {
+    format!("tokens_revoked_at:{}", username)
+}
+
+pub fn issue_access_token(keys: &JwtKeys, username: &str, role: Role) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: (now + keys.access_token_ttl) as usize,
+        role,
+        jti: Uuid::new_v4().to_string(),
+        iat: now as usize,
+    };
+    let header = Header {
+        kid: Some(keys.active_kid.clone()),
+        ..Default::default()
+    };
+    encode(&header, &claims, &EncodingKey::from_secret(keys.secrets[&keys.active_kid].as_ref()))
+}
+
+// Tokens without a kid predate key rotation and were signed with SECRET_KEY. Tokens
+// with no jti, or expiring later than a token issued now would, were not issued by
+// issue_access_token (the long-lived tokens from before it) and are refused.
+pub fn decode_access_token(keys: &JwtKeys, token: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
+    let kid = decode_header(token)?.kid.unwrap_or_else(|| "default".to_string());
+    let secret = keys.secrets.get(&kid).ok_or(jsonwebtoken::errors::ErrorKind::InvalidKeyFormat)?;
+    let validation = Validation::default();
+    let token_data = decode::<Claims>(token, &DecodingKey::from_secret(secret.as_ref()), &validation)?;
+
+    let latest_exp = Utc::now().timestamp() + keys.access_token_ttl + validation.leeway as i64;
+    if token_data.claims.jti.is_empty() || token_data.claims.exp as i64 > latest_exp {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+    Ok(token_data)
+}
+
+// Refresh tokens are opaque and single use; Redis maps each live one to its owner and
+// keeps a set of each owner's tokens so they can all be revoked together
+pub async fn issue_refresh_token(con: &mut MultiplexedConnection, username: &str, ttl_seconds: i64) -> Result<String, ApiError> {
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let _: () = con.set_ex(refresh_token_key(&token), username, ttl_seconds as u64).await?;
+    let _: () = con.sadd(user_refresh_tokens_key(username), &token).await?;
+    let _: () = con.expire(user_refresh_tokens_key(username), ttl_seconds).await?;
+    Ok(token)
+}
+
+// Consumes a refresh token, returning its owner if it was still live
+pub async fn consume_refresh_token(con: &mut MultiplexedConnection, token: &str) -> Result<Option<String>, ApiError> {
+    let username: Option<String> = redis::cmd("GETDEL").arg(refresh_token_key(token)).query_async(con).await?;
+    if let Some(username) = &username {
+        let _: () = con.srem(user_refresh_tokens_key(username), token).await?;
+    }
+    Ok(username)
+}
+
+// Signs the user out everywhere: drops every refresh token and refuses access tokens
+// issued before now, for as long as any of them could still be valid
+pub async fn revoke_user_tokens(con: &mut MultiplexedConnection, keys: &JwtKeys, username: &str) -> Result<(), ApiError> {
+    let tokens: Vec<String> = con.smembers(user_refresh_tokens_key(username)).await?;
+    for token in &tokens {
+        let _: () = con.del(refresh_token_key(token)).await?;
+    }
+    let _: () = con.del(user_refresh_tokens_key(username)).await?;
+
+    let remaining = keys.access_token_ttl + Validation::default().leeway as i64;
+    let _: () = con.set_ex(tokens_revoked_at_key(username), Utc::now().timestamp(), remaining as u64).await?;
+    Ok(())
+}
+
+// Keeps the jti on the revocation list until the token would have expired anyway
+pub async fn revoke_access_token(con: &mut MultiplexedConnection, claims: &Claims) -> Result<(), ApiError> {
+    let remaining = claims.exp as i64 - Utc::now().timestamp();
+    if remaining > 0 {
+        let _: () = con.set_ex(revoked_key(&claims.jti), 1, remaining as u64).await?;
+    }
+    Ok(())
+}
+
+// Revoked one at a time by signout, or along with the rest of the user's tokens
+pub async fn is_revoked(con: &mut MultiplexedConnection, claims: &Claims) -> Result<bool, ApiError> {
+    if con.exists(revoked_key(&claims.jti)).await? {
+        return Ok(true);
+    }
+    let revoked_at: Option<i64> = con.get(tokens_revoked_at_key(&claims.sub)).await?;
+    Ok(revoked_at.is_some_and(|revoked_at| (claims.iat as i64) < revoked_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> JwtKeys {
+        JwtKeys {
+            active_kid: "default".to_string(),
+            secrets: HashMap::from([("default".to_string(), "test-secret".to_string())]),
+            access_token_ttl: 15 * 60,
+        }
+    }
+
+    fn sign(claims: serde_json::Value) -> String {
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(b"test-secret")).unwrap()
+    }
+
+    #[test]
+    fn accepts_issued_tokens() {
+        let token = issue_access_token(&keys(), "alice", Role::Trader).unwrap();
+        let token_data = decode_access_token(&keys(), &token).unwrap();
+        assert_eq!(token_data.claims.sub, "alice");
+        assert!(!token_data.claims.jti.is_empty());
+        assert!(token_data.claims.iat as i64 <= Utc::now().timestamp());
+        assert!(token_data.claims.iat > 0);
+    }
+
+    #[test]
+    fn refuses_legacy_tokens() {
+        let exp = Utc::now().timestamp() + 60;
+        let cases = [
+            ("no jti", serde_json::json!({ "sub": "alice", "exp": exp, "role": "Trader" })),
+            ("empty jti", serde_json::json!({ "sub": "alice", "exp": exp, "role": "Trader", "jti": "" })),
+            ("far exp", serde_json::json!({ "sub": "alice", "exp": 10000000000u64, "role": "Trader", "jti": "abc" })),
+            ("no role", serde_json::json!({ "sub": "alice", "exp": exp, "jti": "abc" })),
+        ];
+        for (name, claims) in cases {
+            assert!(decode_access_token(&keys(), &sign(claims)).is_err(), "{} was accepted", name);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use redis::aio::MultiplexedConnection;
+use log::{info, warn};
+use redis::AsyncCommands;
+
+use crate::errors::ApiError;
+use crate::models::UserState;
+
+// Redis hashes mapping the generated ids handed out by /register and /login back to
+// the username that user states are keyed by
+pub const USER_IDS_KEY: &str = "user_ids";
+pub const PORTFOLIO_IDS_KEY: &str = "portfolio_ids";
+
+// User states live under their own prefix, so no username can collide with another key
+pub fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+// Moves user states stored under the bare username by earlier versions to their user key.
+// Those versions did not index every user in user_ids, so the keyspace is scanned for
+// string keys holding a user state of the same name, and the index is backfilled.
+pub async fn migrate_user_keys(redis_client: &redis::Client) -> Result<(), ApiError> {
+    let mut con = redis_client.get_multiplexed_async_connection().await?;
+    let mut keys: Vec<String> = vec![];
+    {
+        let mut iter = con.scan::<String>().await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+
+    // Every current key has a prefix ending in ':', and usernames cannot contain one
+    for key in keys.into_iter().filter(|key| !key.contains(':')) {
+        let key_type: String = con.key_type(&key).await?;
+        if key_type != "string" {
+            continue;
+        }
+        let value: Option<String> = con.get(&key).await?;
+        let user_state = match value.and_then(|value| serde_json::from_str::<UserState>(&value).ok()) {
+            Some(user_state) if user_state.username == key => user_state,
+            _ => continue,
+        };
+
+        index_user(&mut con, &user_state).await?;
+        let moved: bool = con.rename_nx(&key, user_key(&key)).await?;
+        if moved {
+            info!("Moved user state of {} to {}", key, user_key(&key));
+        } else {
+            warn!("User state of {} left in place, {} already exists", key, user_key(&key));
+        }
+    }
+    Ok(())
+}
+
+pub async fn load_user_state(con: &mut MultiplexedConnection, username: &str) -> Result<Option<UserState>, ApiError> {
+    let user_state_json: Option<String> = con.get(user_key(username)).await?;
+    Ok(user_state_json.map(|user_state_json| serde_json::from_str(&user_state_json)).transpose()?)
+}
+
+pub async fn save_user_state(con: &mut MultiplexedConnection, user_state: &UserState) -> Result<(), ApiError> {
+    let _: () = con.set(user_key(&user_state.username), serde_json::to_string(user_state)?).await?;
+    Ok(())
+}
+
+pub async fn index_user(con: &mut MultiplexedConnection, user_state: &UserState) -> Result<(), ApiError> {
+    let _: () = con.hset(USER_IDS_KEY, &user_state.user_id, &user_state.username).await?;
+    let _: () = con.hset(PORTFOLIO_IDS_KEY, &user_state.portfolio.portfolio_id, &user_state.username).await?;
+    Ok(())
+}
+
+// Accepts either a username or a generated user_id
+pub async fn find_by_user_id(con: &mut MultiplexedConnection, user_id: &str) -> Result<Option<UserState>, ApiError> {
+    if let Some(user_state) = load_user_state(con, user_id).await? {
+        return Ok(Some(user_state));
+    }
+    let username: Option<String> = con.hget(USER_IDS_KEY, user_id).await?;
+    match username {
+        Some(username) => load_user_state(con, &username).await,
+        None => Ok(None),
+    }
+}
+
+pub async fn find_by_portfolio_id(con: &mut MultiplexedConnection, portfolio_id: &str) -> Result<Option<UserState>, ApiError> {
+    let username: Option<String> = con.hget(PORTFOLIO_IDS_KEY, portfolio_id).await?;
+    match username {
+        Some(username) => load_user_state(con, &username).await,
+        None => Ok(None),
+    }
+}
+
+// Every registered user, in no particular order
+pub async fn all_user_states(con: &mut MultiplexedConnection) -> Result<Vec<UserState>, ApiError> {
+    let usernames: Vec<String> = con.hvals(USER_IDS_KEY).await?;
+    let mut user_states = Vec::with_capacity(usernames.len());
+    for username in usernames {
+        if let Some(user_state) = load_user_state(con, &username).await? {
+            user_states.push(user_state);
+        }
+    }
+    Ok(user_states)
+}
+
+pub async fn remove_user(con: &mut MultiplexedConnection, user_state: &UserState) -> Result<(), ApiError> {
+    let _: () = con.del(user_key(&user_state.username)).await?;
+    let _: () = con.hdel(USER_IDS_KEY, &user_state.user_id).await?;
+    let _: () = con.hdel(PORTFOLIO_IDS_KEY, &user_state.portfolio.portfolio_id).await?;
+    Ok(())
+}
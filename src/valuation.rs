@@ -0,0 +1,262 @@
+use serde::{Deserialize, Serialize};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+
+use crate::errors::ApiError;
+use crate::models::{Asset, Lot, Portfolio, PortfolioValuation};
+
+// Redis hash of symbol -> price of the most recent fill
+pub const LAST_PRICE_KEY: &str = "last_price";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum CostBasisMethod {
+    Fifo,
+    AverageCost,
+}
+
+impl CostBasisMethod {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "fifo" => Some(CostBasisMethod::Fifo),
+            "average" => Some(CostBasisMethod::AverageCost),
+            _ => None,
+        }
+    }
+}
+
+pub fn new_asset(symbol: &str) -> Asset {
+    Asset {
+        symbol: symbol.to_string(),
+        shares: 0,
+        market_value: 0.0,
+        average_cost: 0.0,
+        portfolio_diversity: 0.0,
+        unrealized_pnl: 0.0,
+        realized_pnl: 0.0,
+        lots: vec![],
+    }
+}
+
+// Assets created through initialize_user carry shares without lots, so treat
+// any shares not covered by a lot as one lot opened at the average cost.
+fn seed_lots(asset: &mut Asset) {
+    let lot_shares: u32 = asset.lots.iter().map(|lot| lot.quantity).sum();
+    if lot_shares < asset.shares {
+        asset.lots.insert(0, Lot {
+            quantity: asset.shares - lot_shares,
+            price: asset.average_cost,
+        });
+    }
+}
+
+pub fn apply_buy(asset: &mut Asset, quantity: u32, price: f64) {
+    seed_lots(asset);
+
+    let total_cost = asset.shares as f64 * asset.average_cost + quantity as f64 * price;
+    asset.shares += quantity;
+    asset.average_cost = total_cost / asset.shares as f64;
+    asset.lots.push(Lot { quantity, price });
+}
+
+// Removes `quantity` shares from the position and returns the realized P&L of the sale
+pub fn apply_sell(asset: &mut Asset, quantity: u32, price: f64, method: CostBasisMethod) -> f64 {
+    seed_lots(asset);
+
+    let mut remaining = quantity;
+    let mut fifo_cost = 0.0;
+    while remaining > 0 && !asset.lots.is_empty() {
+        let lot = &mut asset.lots[0];
+        let taken = remaining.min(lot.quantity);
+        fifo_cost += taken as f64 * lot.price;
+        lot.quantity -= taken;
+        remaining -= taken;
+        if lot.quantity == 0 {
+            asset.lots.remove(0);
+        }
+    }
+
+    let cost = match method {
+        CostBasisMethod::Fifo => fifo_cost,
+        CostBasisMethod::AverageCost => quantity as f64 * asset.average_cost,
+    };
+    let realized = quantity as f64 * price - cost;
+
+    asset.shares -= quantity;
+    asset.realized_pnl += realized;
+
+    if method == CostBasisMethod::Fifo {
+        let open_cost: f64 = asset.lots.iter().map(|lot| lot.quantity as f64 * lot.price).sum();
+        asset.average_cost = if asset.shares > 0 { open_cost / asset.shares as f64 } else { 0.0 };
+    } else if asset.shares == 0 {
+        asset.average_cost = 0.0;
+    }
+
+    realized
+}
+
+// Marks every position at its last traded price (falling back to cost when the
+// symbol has never traded) and recomputes diversity against total equity.
+// Returns the total market value of the positions.
+pub fn revalue(portfolio: &mut Portfolio, prices: &HashMap<String, f64>) -> f64 {
+    let mut market_value = 0.0;
+    for asset in portfolio.assets.values_mut() {
+        let price = prices.get(&asset.symbol).copied().unwrap_or(asset.average_cost);
+        asset.market_value = asset.shares as f64 * price;
+        asset.unrealized_pnl = asset.market_value - asset.shares as f64 * asset.average_cost;
+        market_value += asset.market_value;
+    }
+
+    let total_equity = portfolio.total_money + market_value;
+    for asset in portfolio.assets.values_mut() {
+        asset.portfolio_diversity = if total_equity != 0.0 { asset.market_value / total_equity } else { 0.0 };
+    }
+
+    market_value
+}
+
+pub fn value_portfolio(portfolio: &Portfolio, prices: &HashMap<String, f64>) -> PortfolioValuation {
+    let mut portfolio = portfolio.clone();
+    let market_value = revalue(&mut portfolio, prices);
+    let unrealized_pnl = portfolio.assets.values().map(|asset| asset.unrealized_pnl).sum();
+
+    PortfolioValuation {
+        portfolio_id: portfolio.portfolio_id,
+        total_money: portfolio.total_money,
+        market_value,
+        total_equity: portfolio.total_money + market_value,
+        realized_pnl: portfolio.realized_pnl,
+        unrealized_pnl,
+        assets: portfolio.assets,
+    }
+}
+
+pub async fn last_prices(con: &mut MultiplexedConnection) -> Result<HashMap<String, f64>, ApiError> {
+    Ok(con.hgetall(LAST_PRICE_KEY).await?)
+}
+
+pub async fn record_last_price(con: &mut MultiplexedConnection, symbol: &str, price: f64) -> Result<(), ApiError> {
+    let _: () = con.hset(LAST_PRICE_KEY, symbol, price).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 shares bought at 100, then 10 at 120
+    fn two_lots() -> Asset {
+        let mut asset = new_asset("ETH");
+        apply_buy(&mut asset, 10, 100.0);
+        apply_buy(&mut asset, 10, 120.0);
+        asset
+    }
+
+    fn lots(asset: &Asset) -> Vec<(u32, f64)> {
+        asset.lots.iter().map(|lot| (lot.quantity, lot.price)).collect()
+    }
+
+    #[test]
+    fn buys_open_lots_at_the_average_cost() {
+        let asset = two_lots();
+        assert_eq!(asset.shares, 20);
+        assert_eq!(asset.average_cost, 110.0);
+        assert_eq!(lots(&asset), vec![(10, 100.0), (10, 120.0)]);
+    }
+
+    #[test]
+    fn fifo_sells_the_oldest_lot_first() {
+        let mut asset = two_lots();
+        let realized = apply_sell(&mut asset, 15, 130.0, CostBasisMethod::Fifo);
+
+        // 10 @ 100 and 5 @ 120 sold at 130
+        assert_eq!(realized, 350.0);
+        assert_eq!(lots(&asset), vec![(5, 120.0)]);
+        assert_eq!(asset.average_cost, 120.0);
+    }
+
+    #[test]
+    fn fifo_partial_sale_reprices_the_remaining_position() {
+        let mut asset = two_lots();
+        apply_sell(&mut asset, 5, 130.0, CostBasisMethod::Fifo);
+
+        assert_eq!(asset.realized_pnl, 150.0);
+        assert_eq!(lots(&asset), vec![(5, 100.0), (10, 120.0)]);
+        assert_eq!(asset.average_cost, 1700.0 / 15.0);
+    }
+
+    #[test]
+    fn average_cost_sale_keeps_the_average() {
+        let mut asset = two_lots();
+        let realized = apply_sell(&mut asset, 15, 130.0, CostBasisMethod::AverageCost);
+
+        assert_eq!(realized, 15.0 * (130.0 - 110.0));
+        assert_eq!(asset.shares, 5);
+        assert_eq!(asset.average_cost, 110.0);
+    }
+
+    #[test]
+    fn liquidating_resets_the_cost_basis() {
+        for method in [CostBasisMethod::Fifo, CostBasisMethod::AverageCost] {
+            let mut asset = two_lots();
+            assert_eq!(apply_sell(&mut asset, 20, 130.0, method), 400.0);
+            assert_eq!(asset.shares, 0);
+            assert_eq!(asset.average_cost, 0.0);
+            assert!(asset.lots.is_empty());
+        }
+    }
+
+    #[test]
+    fn realized_pnl_accumulates_gains_and_losses() {
+        let mut asset = two_lots();
+        assert_eq!(apply_sell(&mut asset, 15, 130.0, CostBasisMethod::Fifo), 350.0);
+        assert_eq!(apply_sell(&mut asset, 5, 90.0, CostBasisMethod::Fifo), -150.0);
+        assert_eq!(asset.realized_pnl, 200.0);
+    }
+
+    #[test]
+    fn shares_without_lots_are_seeded_at_the_average_cost() {
+        // As initialize_user leaves it: 10 shares at an average cost of 50 and no lots
+        let mut asset = new_asset("ETH");
+        asset.shares = 10;
+        asset.average_cost = 50.0;
+        apply_buy(&mut asset, 10, 70.0);
+        assert_eq!(lots(&asset), vec![(10, 50.0), (10, 70.0)]);
+
+        let mut fifo = asset.clone();
+        assert_eq!(apply_sell(&mut fifo, 10, 80.0, CostBasisMethod::Fifo), 300.0);
+        assert_eq!(fifo.average_cost, 70.0);
+
+        let mut average = asset;
+        assert_eq!(apply_sell(&mut average, 10, 80.0, CostBasisMethod::AverageCost), 200.0);
+        assert_eq!(average.average_cost, 60.0);
+    }
+
+    #[test]
+    fn revalue_marks_at_the_last_price_or_at_cost() {
+        let mut eth = two_lots();
+        eth.symbol = "ETH".to_string();
+        let mut btc = new_asset("BTC");
+        apply_buy(&mut btc, 2, 50.0);
+        let mut portfolio = Portfolio {
+            portfolio_id: "p1".to_string(),
+            total_money: 700.0,
+            assets: HashMap::from([("ETH".to_string(), eth), ("BTC".to_string(), btc)]),
+            realized_pnl: 0.0,
+        };
+
+        // BTC has never traded, so it is carried at cost
+        let prices = HashMap::from([("ETH".to_string(), 100.0)]);
+        assert_eq!(revalue(&mut portfolio, &prices), 2100.0);
+
+        let eth = &portfolio.assets["ETH"];
+        assert_eq!(eth.market_value, 2000.0);
+        assert_eq!(eth.unrealized_pnl, -200.0);
+        assert_eq!(eth.portfolio_diversity, 2000.0 / 2800.0);
+        assert_eq!(portfolio.assets["BTC"].unrealized_pnl, 0.0);
+
+        let valuation = value_portfolio(&portfolio, &prices);
+        assert_eq!(valuation.total_equity, 2800.0);
+        assert_eq!(valuation.unrealized_pnl, -200.0);
+    }
+}
@@ -0,0 +1,189 @@
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use web3::transports::WebSocket;
+use web3::types::H160;
+use web3::Web3;
+
+use crate::batch::contract_supports_batching;
+use crate::config::{AbiVersion, VenueConfig, DEFAULT_VENUE};
+use crate::supervisor::Health;
+
+// A venue and its node connection. Each has its own listener, so its own Health.
+pub struct Venue {
+    pub name: String,
+    pub ws_url: String,
+    pub contract_address: H160,
+    pub abi_version: AbiVersion,
+    pub health: Arc<Health>,
+    expected_chain_id: Option<u64>,
+    // 0 until the node has been reached, unless the config names the chain
+    chain_id: AtomicU64,
+    // Replaced by each listener start, so order-path calls follow a reconnect
+    web3: RwLock<Option<Web3<WebSocket>>>,
+}
+
+impl Venue {
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id.load(Ordering::SeqCst)
+    }
+
+    // The handle order-path calls use, failing while the venue has never been reached
+    pub fn web3(&self) -> Result<Web3<WebSocket>, web3::Error> {
+        self.web3.read().unwrap_or_else(|e| e.into_inner()).clone().ok_or(web3::Error::Unreachable)
+    }
+
+    // Opens a fresh connection to the venue's node, checks it, and makes it the handle
+    // order-path calls use. The listener calls this on every start, so a venue that was
+    // unreachable or dropped its connection is reconnected by its supervisor.
+    pub async fn reconnect(&self) -> Result<Web3<WebSocket>, ConnectError> {
+        let (web3, chain_id) = open(&self.name, &self.ws_url, self.expected_chain_id, self.contract_address, self.abi_version).await?;
+        self.chain_id.store(chain_id, Ordering::SeqCst);
+        *self.web3.write().unwrap_or_else(|e| e.into_inner()) = Some(web3.clone());
+        Ok(web3)
+    }
+}
+
+// A node that cannot be reached is retried; one serving the wrong chain or contract is
+// a configuration error
+#[derive(Debug)]
+pub enum ConnectError {
+    Unreachable(String),
+    Misconfigured(String),
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::Unreachable(message) | ConnectError::Misconfigured(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// Connects to a venue's node and checks it serves the configured chain and, for v2
+// venues, that the contract has placeOrders
+async fn open(
+    name: &str,
+    ws_url: &str,
+    expected_chain_id: Option<u64>,
+    contract_address: H160,
+    abi_version: AbiVersion,
+) -> Result<(Web3<WebSocket>, u64), ConnectError> {
+    let transport = WebSocket::new(ws_url).await
+        .map_err(|e| ConnectError::Unreachable(format!("Venue {} could not connect to {}: {}", name, ws_url, e)))?;
+    let web3 = Web3::new(transport);
+    let chain_id = web3.eth().chain_id().await
+        .map_err(|e| ConnectError::Unreachable(format!("Venue {} could not read the chain id: {}", name, e)))?
+        .as_u64();
+    if let Some(expected) = expected_chain_id {
+        if expected != chain_id {
+            return Err(ConnectError::Misconfigured(format!(
+                "Venue {} expects chain {} but {} serves chain {}", name, expected, ws_url, chain_id
+            )));
+        }
+    }
+
+    if abi_version == AbiVersion::V2 && !contract_supports_batching(&web3, contract_address).await {
+        return Err(ConnectError::Misconfigured(format!(
+            "Venue {} has abi_version v2 but its contract {:?} has no placeOrders; deploy it from contracts/OrderBook.sol",
+            name, contract_address
+        )));
+    }
+    Ok((web3, chain_id))
+}
+
+// Every venue, default first, and the venue each listed symbol is routed to. Shared
+// outside the AppState mutex like Health, so probes can read it without the lock.
+pub struct Venues {
+    venues: Vec<Arc<Venue>>,
+    routes: HashMap<String, usize>,
+}
+
+impl Venues {
+    // Connects to each venue's node. A node that cannot be reached leaves its venue
+    // unhealthy until its listener's supervisor connects it; one that serves the wrong
+    // chain or contract is a configuration error and stops the server.
+    pub async fn connect(configs: &[VenueConfig]) -> Result<Venues, String> {
+        let mut venues = vec![];
+        let mut routes = HashMap::new();
+        for (index, config) in configs.iter().enumerate() {
+            let venue = Venue {
+                name: config.name.clone(),
+                ws_url: config.ws_url.clone(),
+                contract_address: config.contract_address,
+                abi_version: config.abi_version,
+                health: Arc::new(Health::default()),
+                expected_chain_id: config.chain_id,
+                chain_id: AtomicU64::new(config.chain_id.unwrap_or(0)),
+                web3: RwLock::new(None),
+            };
+            match venue.reconnect().await {
+                Ok(_) => info!("Venue {}: chain {}, contract {:?} ({:?})", config.name, venue.chain_id(), config.contract_address, config.abi_version),
+                Err(ConnectError::Unreachable(e)) => warn!("{}; starting it unhealthy until its listener connects", e),
+                Err(ConnectError::Misconfigured(e)) => return Err(e),
+            }
+            for symbol in &config.symbols {
+                routes.insert(symbol.clone(), index);
+            }
+            venues.push(Arc::new(venue));
+        }
+        Ok(Venues { venues, routes })
+    }
+
+    pub fn default_venue(&self) -> &Arc<Venue> {
+        &self.venues[0]
+    }
+
+    pub fn for_symbol(&self, symbol: &str) -> &Arc<Venue> {
+        self.routes.get(symbol).map_or(self.default_venue(), |index| &self.venues[*index])
+    }
+
+    // Records from before venues name none and belong to the default venue
+    pub fn get(&self, name: &str) -> Option<&Arc<Venue>> {
+        if name.is_empty() || name == DEFAULT_VENUE {
+            return Some(self.default_venue());
+        }
+        self.venues.iter().find(|venue| venue.name == name)
+    }
+
+    pub fn all(&self) -> &[Arc<Venue>] {
+        &self.venues
+    }
+
+    pub fn begin_shutdown(&self) {
+        for venue in &self.venues {
+            venue.health.begin_shutdown();
+        }
+    }
+
+    // Waits for in-flight contract calls on every venue, returning false on timeout
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        for venue in &self.venues {
+            if !venue.health.drain(deadline.saturating_duration_since(Instant::now())).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn status(&self) -> Value {
+        let venues: serde_json::Map<String, Value> = self.venues.iter().map(|venue| {
+            let symbols: Vec<&String> = self.routes.iter()
+                .filter(|(_, index)| self.venues[**index].name == venue.name)
+                .map(|(symbol, _)| symbol)
+                .collect();
+            (venue.name.clone(), json!({
+                "chain_id": venue.chain_id,
+                "contract_address": venue.contract_address,
+                "abi_version": venue.abi_version,
+                "symbols": symbols,
+                "health": venue.health.status()
+            }))
+        }).collect();
+        json!(venues)
+    }
+}
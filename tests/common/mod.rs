@@ -0,0 +1,164 @@
+// A local dev chain and Redis for the integration tests, each started on a free port
+// with the OrderBook contract deployed, and stopped when the harness is dropped.
+//
+// The chain is `anvil` or `ganache` and Redis is `redis-server`, found on the PATH or
+// named by TEST_CHAIN_BIN and TEST_REDIS_BIN. With TEST_REDIS_URL set, that Redis is
+// flushed and used instead, as CI does with a Redis service. The tests using it are
+// #[ignore]d, so they only run with `cargo test -- --ignored`, and then fail if either
+// is missing.
+use serde_json::Value;
+use std::env;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+use web3::contract::{Contract, Options};
+use web3::transports::WebSocket;
+use web3::types::{Address, U256};
+use web3::Web3;
+
+use hft_trading_server::app::Services;
+use hft_trading_server::config::Config;
+
+const ARTIFACT: &str = "build/contracts/OrderBook.json";
+pub const ADMIN_USERNAME: &str = "admin";
+pub const ADMIN_PASSWORD: &str = "admin-password-1";
+
+// The server reads its settings from the environment, so one harness runs at a time
+static ENVIRONMENT: Mutex<()> = Mutex::const_new(());
+
+// Child processes, killed when dropped so a failing test does not leave them running
+struct Processes(Vec<Child>);
+
+impl Drop for Processes {
+    fn drop(&mut self) {
+        for child in self.0.iter_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+pub struct Harness {
+    pub contract_address: Address,
+    pub services: Services,
+    _processes: Processes,
+    _environment: MutexGuard<'static, ()>,
+}
+
+fn on_path(name: &str) -> bool {
+    env::var_os("PATH").is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+}
+
+fn binary(env_name: &str, candidates: &[&str]) -> Option<String> {
+    if let Ok(binary) = env::var(env_name) {
+        return Some(binary);
+    }
+    candidates.iter().find(|candidate| on_path(candidate)).map(|candidate| candidate.to_string())
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn wait_for_port(name: &str, port: u16) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        if Instant::now() > deadline {
+            panic!("{} did not listen on port {}", name, port);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn spawn(binary: &str, args: &[String]) -> Child {
+    Command::new(binary)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("could not start {}: {}", binary, e))
+}
+
+fn start_chain(binary: &str, port: u16) -> Child {
+    let name = Path::new(binary).file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let args = if name.starts_with("ganache") {
+        vec!["--port".to_string(), port.to_string(), "--quiet".to_string()]
+    } else {
+        vec!["--port".to_string(), port.to_string(), "--silent".to_string()]
+    };
+    spawn(binary, &args)
+}
+
+// Deploys the artifact's bytecode from the chain's first unlocked account
+async fn deploy(web3: &Web3<WebSocket>, account: Address) -> Address {
+    let artifact: Value = serde_json::from_str(&std::fs::read_to_string(ARTIFACT).unwrap()).unwrap();
+    let bytecode = artifact["bytecode"].as_str().expect("artifact has no bytecode");
+    let contract = Contract::deploy(web3.eth(), artifact["abi"].to_string().as_bytes())
+        .unwrap()
+        .confirmations(0)
+        .options(Options::with(|options| options.gas = Some(U256::from(6_000_000))))
+        .execute(bytecode, (), account)
+        .await
+        .expect("could not deploy OrderBook");
+    contract.address()
+}
+
+// The URL of an empty Redis: TEST_REDIS_URL flushed, or a redis-server of our own
+async fn start_redis(processes: &mut Processes) -> String {
+    if let Ok(redis_url) = env::var("TEST_REDIS_URL") {
+        let client = redis::Client::open(redis_url.as_str()).unwrap();
+        let mut con = client.get_multiplexed_async_connection().await
+            .unwrap_or_else(|e| panic!("could not connect to TEST_REDIS_URL {}: {}", redis_url, e));
+        let _: () = redis::cmd("FLUSHDB").query_async(&mut con).await.unwrap();
+        return redis_url;
+    }
+
+    let redis_binary = binary("TEST_REDIS_BIN", &["redis-server"])
+        .expect("no redis-server on the PATH; install it, set TEST_REDIS_BIN or set TEST_REDIS_URL");
+    let redis_port = free_port();
+    processes.0.push(spawn(&redis_binary, &[
+        "--port".to_string(), redis_port.to_string(),
+        "--save".to_string(), String::new(),
+        "--appendonly".to_string(), "no".to_string(),
+    ]));
+    wait_for_port(&redis_binary, redis_port);
+    format!("redis://127.0.0.1:{}/", redis_port)
+}
+
+impl Harness {
+    // Starts the chain and Redis, deploys the contract and starts the server's
+    // listeners and background tasks against them
+    pub async fn start() -> Harness {
+        let chain_binary = binary("TEST_CHAIN_BIN", &["anvil", "ganache"])
+            .expect("no anvil or ganache on the PATH; install one or set TEST_CHAIN_BIN");
+        let environment = ENVIRONMENT.lock().await;
+
+        let chain_port = free_port();
+        let mut processes = Processes(vec![]);
+        processes.0.push(start_chain(&chain_binary, chain_port));
+        let redis_url = start_redis(&mut processes).await;
+        wait_for_port(&chain_binary, chain_port);
+
+        let ws_url = format!("ws://127.0.0.1:{}", chain_port);
+        let web3 = Web3::new(WebSocket::new(&ws_url).await.unwrap());
+        let account = web3.eth().accounts().await.unwrap()[0];
+        let contract_address = deploy(&web3, account).await;
+
+        env::set_var("APP_ENV", "test");
+        env::set_var("WS_URL", &ws_url);
+        env::set_var("CONTRACT_ADDRESS", format!("{:?}", contract_address));
+        env::set_var("ACCOUNT_ADDRESS", format!("{:?}", account));
+        env::set_var("REDIS_CLIENT_URL", &redis_url);
+        env::set_var("SECRET_KEY", "integration-test-secret-key-0123456789");
+        env::set_var("ADMIN_USERNAME", ADMIN_USERNAME);
+        env::set_var("ADMIN_PASSWORD", ADMIN_PASSWORD);
+        env::set_var("BCRYPT_COST", "10");
+
+        let config = Config::load().unwrap_or_else(|e| panic!("{}", e));
+        let services = Services::start(&config).await.unwrap_or_else(|e| panic!("{}", e));
+
+        Harness { contract_address, services, _processes: processes, _environment: environment }
+    }
+}
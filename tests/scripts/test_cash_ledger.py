@@ -0,0 +1,107 @@
+import requests
+import json
+
+BASE_URL = "http://127.0.0.1:8080"
+
+# Utility routes are admin-only; these must match ADMIN_USERNAME / ADMIN_PASSWORD in .env
+ADMIN_USERNAME = "admin"
+ADMIN_PASSWORD = "admin123"
+
+# Function to register a user
+def register_user(username, password):
+    url = f"{BASE_URL}/register"
+    payload = {
+        "username": username,
+        "password": password
+    }
+    response = requests.post(url, json=payload)
+    return response.json()
+
+# Function to login a user and get the token
+def login_user(username, password):
+    url = f"{BASE_URL}/login"
+    payload = {
+        "username": username,
+        "password": password
+    }
+    response = requests.post(url, json=payload)
+    return response.json()
+
+# Function to deposit or withdraw cash
+def move_cash(token, action, amount):
+    url = f"{BASE_URL}/account/{action}"
+    headers = {
+        "Authorization": f"Bearer {token}"
+    }
+    payload = {
+        "amount": amount
+    }
+    return requests.post(url, json=payload, headers=headers)
+
+# Function to credit a user's cash account as admin
+def deposit_cash(username, amount):
+    url = f"{BASE_URL}/utils/post/deposit"
+    payload = {
+        "username": username,
+        "amount": amount
+    }
+    return requests.post(url, json=payload, headers=ADMIN_HEADERS)
+
+# Function to get the user's cash ledger
+def get_ledger(token):
+    url = f"{BASE_URL}/account/ledger"
+    headers = {
+        "Authorization": f"Bearer {token}"
+    }
+    response = requests.get(url, headers=headers)
+    return response.json()
+
+# Login as admin for the utility routes; the token stays valid after the data wipe
+admin_token = login_user(ADMIN_USERNAME, ADMIN_PASSWORD).get("token")
+ADMIN_HEADERS = {
+    "Authorization": f"Bearer {admin_token}"
+}
+
+# Delete all data
+delete_all_data_url = f"{BASE_URL}/utils/delete/all_data"
+response = requests.delete(delete_all_data_url, headers=ADMIN_HEADERS)
+print("Deleted all data\n")
+
+# Register and login Dana
+register_user("dana", "password123")
+login_dana = login_user("dana", "password123")
+token_dana = login_dana.get("token")
+print("Dana Logged in\n")
+
+# Admin deposits $5000 for Dana, who withdraws $1200
+deposit = deposit_cash("dana", 5000.0)
+print("Deposit:\n", json.dumps(deposit.json(), indent=4), "\n")
+withdrawal = move_cash(token_dana, "withdraw", 1200.0)
+print("Withdrawal:\n", json.dumps(withdrawal.json(), indent=4), "\n")
+
+# Overdrawing and negative amounts must be rejected
+overdraw = move_cash(token_dana, "withdraw", 100000.0)
+negative = deposit_cash("dana", -10.0)
+# Users cannot credit their own account
+self_deposit = move_cash(token_dana, "deposit", 5000.0)
+
+ledger = get_ledger(token_dana)
+print("Dana's Ledger:\n", json.dumps(ledger, indent=4), "\n")
+
+# Check if the test performs as expected
+passed = True
+if self_deposit.status_code != 404:
+    print(f"Test Failed: expected 404 for a self-deposit, got {self_deposit.status_code}")
+    passed = False
+if overdraw.status_code != 400 or negative.status_code != 400:
+    print(f"Test Failed: expected 400 for invalid movements, got {overdraw.status_code} and {negative.status_code}")
+    passed = False
+if ledger.get("balance") != 3800.0 or len(ledger.get("entries", [])) != 2:
+    print(f"Test Failed: expected balance 3800.0 over 2 entries, got {ledger.get('balance')} over {len(ledger.get('entries', []))}")
+    passed = False
+for entry in ledger.get("entries", []):
+    if sum(posting["amount"] for posting in entry["postings"]) != 0:
+        print(f"Test Failed: unbalanced entry {entry}")
+        passed = False
+if passed:
+    print("Test Passed")
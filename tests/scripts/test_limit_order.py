@@ -3,6 +3,10 @@ import json
 
 BASE_URL = "http://127.0.0.1:8080"
 
+# Utility routes are admin-only; these must match ADMIN_USERNAME / ADMIN_PASSWORD in .env
+ADMIN_USERNAME = "admin"
+ADMIN_PASSWORD = "admin123"
+
 # Function to initialize a user
 def initialize_user(username, password, total_money, assets):
     url = f"{BASE_URL}/utils/post/initialize_user"
@@ -12,7 +16,7 @@ def initialize_user(username, password, total_money, assets):
         "total_money": total_money,
         "assets": assets
     }
-    response = requests.post(url, json=payload)
+    response = requests.post(url, json=payload, headers=ADMIN_HEADERS)
     return response.json()
 
 # Function to login a user and get the token
@@ -64,11 +68,38 @@ def get_user_portfolio(token, user_id):
     response = requests.get(url, headers=headers)
     return response.json()
 
+# Function to register a tradable instrument
+def register_instrument(symbol):
+    url = f"{BASE_URL}/utils/post/instruments"
+    payload = {
+        "symbol": symbol,
+        "description": f"{symbol} common stock",
+        "tick_size": 1,
+        "lot_size": 1,
+        "min_quantity": 1,
+        "max_quantity": 100000,
+        "min_price": 0,
+        "max_price": 100000,
+        "status": "Active"
+    }
+    response = requests.post(url, json=payload, headers=ADMIN_HEADERS)
+    return response.json()
+
+# Login as admin for the utility routes; the token stays valid after the data wipe
+admin_token = login_user(ADMIN_USERNAME, ADMIN_PASSWORD).get("token")
+ADMIN_HEADERS = {
+    "Authorization": f"Bearer {admin_token}"
+}
+
 # Delete all data
 delete_all_data_url = f"{BASE_URL}/utils/delete/all_data"
-response = requests.delete(delete_all_data_url)
+response = requests.delete(delete_all_data_url, headers=ADMIN_HEADERS)
 print("\nDeleted all data\n")
 
+# Register the traded instrument
+register_instrument("ABC")
+print("Registered ABC")
+
 # Initialize Alice with money and no assets
 alice = initialize_user("alice", "password123", 10000.0, {})
 print("Initialized Alice\n")
@@ -0,0 +1,163 @@
+// End-to-end trading against a local chain and Redis: register, log in, fund, place
+// crossing buy and sell orders, and check the fill settles into both portfolios. Signed
+// API key requests are checked here too, since replayed nonces are caught in Redis.
+mod common;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::{Harness, ADMIN_PASSWORD, ADMIN_USERNAME};
+
+fn request(request: TestRequest, uri: &str, token: Option<&str>, body: Option<Value>) -> TestRequest {
+    let mut request = request.uri(uri).peer_addr("127.0.0.1:40000".parse().unwrap());
+    if let Some(token) = token {
+        request = request.insert_header(("Authorization", format!("Bearer {}", token)));
+    }
+    if let Some(body) = body {
+        request = request.set_json(body);
+    }
+    request
+}
+
+async fn send<S, R, B>(app: &S, request: R) -> (StatusCode, Value)
+where
+    S: Service<R, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let response = test::call_service(app, request).await;
+    let status = response.status();
+    let body = test::read_body(response).await;
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+async fn login<S, B>(app: &S, username: &str, password: &str) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let body = json!({ "username": username, "password": password });
+    let (status, response) = send(app, request(TestRequest::post(), "/login", None, Some(body)).to_request()).await;
+    assert_eq!(status, StatusCode::OK, "login of {} failed: {}", username, response);
+    response["token"].as_str().unwrap().to_string()
+}
+
+// Signs a bodiless GET the way API key clients do: HMAC-SHA256 over timestamp, nonce, method and path
+fn signed_get(uri: &str, key_id: &str, secret: &str, nonce: &str) -> TestRequest {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().to_string();
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{}{}GET{}", timestamp, nonce, uri).as_bytes());
+    request(TestRequest::get(), uri, None, None)
+        .insert_header(("X-API-KEY", key_id))
+        .insert_header(("X-API-TIMESTAMP", timestamp))
+        .insert_header(("X-API-NONCE", nonce))
+        .insert_header(("X-API-SIGNATURE", hex::encode(mac.finalize().into_bytes())))
+}
+
+fn shares(portfolio: &Value, symbol: &str) -> u64 {
+    portfolio["assets"][symbol]["shares"].as_u64().unwrap_or(0)
+}
+
+#[actix_web::test]
+#[ignore = "needs anvil or ganache and redis-server; run with `cargo test -- --ignored`"]
+async fn matched_orders_settle_into_both_portfolios() {
+    let harness = Harness::start().await;
+    let app = test::init_service(harness.services.app()).await;
+
+    let admin = login(&app, ADMIN_USERNAME, ADMIN_PASSWORD).await;
+    let (status, health) = send(&app, request(TestRequest::get(), "/utils/get/health", Some(&admin), None).to_request()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(health["default"]["contract_address"], json!(harness.contract_address));
+
+    let instrument = json!({
+        "symbol": "AAPL",
+        "description": "AAPL common stock",
+        "tick_size": 1,
+        "lot_size": 1,
+        "min_quantity": 1,
+        "max_quantity": 100000,
+        "min_price": 0,
+        "max_price": 100000,
+        "status": "Active"
+    });
+    let (status, response) = send(&app, request(TestRequest::post(), "/utils/post/instruments", Some(&admin), Some(instrument)).to_request()).await;
+    assert_eq!(status, StatusCode::OK, "{}", response);
+
+    // The buyer registers and funds the account; the seller is set up holding shares
+    let buyer = json!({ "username": "buyer", "password": "buying-power-1" });
+    let (status, response) = send(&app, request(TestRequest::post(), "/register", None, Some(buyer)).to_request()).await;
+    assert_eq!(status, StatusCode::OK, "{}", response);
+    let buyer = login(&app, "buyer", "buying-power-1").await;
+    let deposit = json!({ "username": "buyer", "amount": 10000.0 });
+    let (status, response) = send(&app, request(TestRequest::post(), "/utils/post/deposit", Some(&admin), Some(deposit)).to_request()).await;
+    assert_eq!(status, StatusCode::OK, "{}", response);
+
+    let seller = json!({
+        "username": "seller",
+        "password": "selling-stock-1",
+        "total_money": 0.0,
+        "assets": {
+            "AAPL": { "symbol": "AAPL", "shares": 15, "market_value": 1500.0, "average_cost": 100.0, "portfolio_diversity": 1.0 }
+        }
+    });
+    let (status, response) = send(&app, request(TestRequest::post(), "/utils/post/initialize_user", Some(&admin), Some(seller)).to_request()).await;
+    assert_eq!(status, StatusCode::OK, "{}", response);
+    let seller = login(&app, "seller", "selling-stock-1").await;
+
+    let order = json!({ "symbol": "AAPL", "quantity": 10, "price": 150, "order_type": "Limit" });
+    let (status, response) = send(&app, request(TestRequest::post(), "/buy", Some(&buyer), Some(order.clone())).to_request()).await;
+    assert_eq!(status, StatusCode::OK, "buy failed: {}", response);
+    let (status, response) = send(&app, request(TestRequest::post(), "/sell", Some(&seller), Some(order)).to_request()).await;
+    assert_eq!(status, StatusCode::OK, "sell failed: {}", response);
+
+    // The match is settled once the listener sees the contract's OrderMatched event
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let buyer_portfolio = loop {
+        let (status, portfolio) = send(&app, request(TestRequest::get(), "/me/portfolio", Some(&buyer), None).to_request()).await;
+        assert_eq!(status, StatusCode::OK, "{}", portfolio);
+        if shares(&portfolio, "AAPL") == 10 {
+            break portfolio;
+        }
+        assert!(Instant::now() < deadline, "fill was not settled: {}", portfolio);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    };
+    assert!(buyer_portfolio["total_money"].as_f64().unwrap() <= 10000.0 - 1500.0);
+
+    let (status, seller_portfolio) = send(&app, request(TestRequest::get(), "/me/portfolio", Some(&seller), None).to_request()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(shares(&seller_portfolio, "AAPL"), 5);
+    assert!(seller_portfolio["total_money"].as_f64().unwrap() > 0.0);
+}
+
+#[actix_web::test]
+#[ignore = "needs anvil or ganache and redis-server; run with `cargo test -- --ignored`"]
+async fn replayed_api_key_nonces_are_rejected() {
+    let harness = Harness::start().await;
+    let app = test::init_service(harness.services.app()).await;
+
+    let trader = json!({ "username": "trader", "password": "signed-requests-1" });
+    let (status, response) = send(&app, request(TestRequest::post(), "/register", None, Some(trader)).to_request()).await;
+    assert_eq!(status, StatusCode::OK, "{}", response);
+    let trader = login(&app, "trader", "signed-requests-1").await;
+
+    let key = json!({ "label": "replay", "scopes": ["read"] });
+    let (status, api_key) = send(&app, request(TestRequest::post(), "/account/api_keys", Some(&trader), Some(key)).to_request()).await;
+    assert_eq!(status, StatusCode::OK, "{}", api_key);
+    let key_id = api_key["key_id"].as_str().unwrap();
+    let secret = api_key["secret"].as_str().unwrap();
+
+    let (status, response) = send(&app, signed_get("/me/orders", key_id, secret, "nonce-1").to_request()).await;
+    assert_eq!(status, StatusCode::OK, "signed request failed: {}", response);
+
+    // The same nonce under a fresh timestamp and signature is still a replay
+    let (status, response) = send(&app, signed_get("/me/orders", key_id, secret, "nonce-1").to_request()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "replay was accepted: {}", response);
+
+    let (status, response) = send(&app, signed_get("/me/orders", key_id, secret, "nonce-2").to_request()).await;
+    assert_eq!(status, StatusCode::OK, "fresh nonce failed: {}", response);
+}